@@ -0,0 +1,202 @@
+use std::path::Path;
+
+use webhook_runner_lib::command::CommandMode;
+
+use crate::cli::Args;
+use crate::{load_github_app, load_keyrings};
+
+/// The result of checking one part of the configuration.
+pub(crate) type Check = (String, Result<(), String>);
+
+/// Check the configuration the way the runner would at startup, and further check that the
+/// keyrings, keys, and commands it refers to can be used, so that a configuration can be checked
+/// before it is deployed. Bind addresses and other options are already checked while parsing.
+pub(crate) fn check_config(args: &Args) -> Vec<Check> {
+    let mut checks = vec![(
+        "options".to_string(),
+        args.assert().map_err(|e| e.to_string()),
+    )];
+
+    // Keyrings that fail to load are recorded rather than returned, to report all of them
+    match load_keyrings(args, false) {
+        Ok((keyrings, _watchers)) => {
+            checks.extend(keyrings.configured().map(|(name, keyring)| {
+                let result = match keyring {
+                    Ok(keyring) => keyring
+                        .read()
+                        .expect("poisoned keyring")
+                        .check_usable(args.crypto_policy())
+                        .map_err(|e| e.to_string()),
+                    Err(reason) => Err(reason.clone()),
+                };
+                (name, result)
+            }));
+        }
+        Err(e) => checks.push(("keyrings".to_string(), Err(e.to_string()))),
+    }
+
+    let commands = [
+        ("commit command", &args.commit_command),
+        ("tag command", &args.tag_command),
+        ("deployment command", &args.deployment_command),
+        ("pull request command", &args.pr_command),
+        ("before command", &args.before_command),
+        ("after command", &args.after_command),
+    ]
+    .into_iter()
+    .map(|(name, command)| (name.to_string(), command.as_ref()))
+    .chain(args.repositories.iter().flat_map(|(repository, config)| {
+        [
+            (
+                format!("commit command of {repository}"),
+                config.commit_command.as_ref(),
+            ),
+            (
+                format!("tag command of {repository}"),
+                config.tag_command.as_ref(),
+            ),
+        ]
+    }))
+    .chain(args.path_commands.iter().map(|path_command| {
+        (
+            format!("command for {}", path_command.pattern),
+            Some(&path_command.command),
+        )
+    }));
+    let mode = args.command_mode();
+    for (name, command) in commands {
+        if let Some(command) = command {
+            checks.push((name, check_command(&mode, &command.run)));
+        }
+    }
+
+    if let Some(ssh_key) = &args.ssh_key {
+        checks.push(("ssh key".to_string(), check_readable(Path::new(ssh_key))));
+    }
+    if let Err(e) = args.https_token() {
+        checks.push(("https token file".to_string(), Err(e.to_string())));
+    }
+    if let Err(e) = args.ssh_credentials() {
+        checks.push(("ssh key passphrase file".to_string(), Err(e.to_string())));
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        checks.push(("tls certificate".to_string(), check_readable(cert)));
+        checks.push(("tls key".to_string(), check_readable(key)));
+    }
+    if args.github_app_id.is_some() {
+        checks.push((
+            "github app".to_string(),
+            load_github_app(args).map(drop).map_err(|e| e.to_string()),
+        ));
+    }
+    checks
+}
+
+/// Check that the program that runs the command exists: the shell given with `--shell`, or with
+/// `--no-shell`, the first word of the command. Commands run by a shell are further checked if
+/// their first word is an absolute path. Relative paths are resolved in the cloned repository, and
+/// variables are only set once a webhook is received, so programs given with either can't be
+/// checked ahead of time.
+fn check_command(mode: &CommandMode, command: &str) -> Result<(), String> {
+    check_program(&mode.program(command).map_err(|e| e.to_string())?)?;
+    match (mode, CommandMode::Direct.program(command)) {
+        (CommandMode::Shell(_), Ok(program)) if program.starts_with('/') => check_program(&program),
+        _ => Ok(()),
+    }
+}
+
+fn check_program(program: &str) -> Result<(), String> {
+    if program.contains('$') {
+        Ok(())
+    } else if program.starts_with('/') {
+        if Path::new(program).is_file() {
+            Ok(())
+        } else {
+            Err(format!("{program} does not exist"))
+        }
+    } else if !program.contains('/') && !in_path(program) {
+        Err(format!("unable to find {program} in PATH"))
+    } else {
+        Ok(())
+    }
+}
+
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+fn check_readable(path: &Path) -> Result<(), String> {
+    std::fs::File::open(path)
+        .map(drop)
+        .map_err(|e| format!("unable to read {path:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use tempdir::TempDir;
+
+    #[test]
+    fn can_check_config() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let keyring = dir.path().join("keyring");
+        std::fs::create_dir(&keyring).unwrap();
+        let args = Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "/bin/true",
+            "--tag-command",
+            "/nonexistent/release.sh",
+            "--before-command",
+            "/nonexistent/check.sh",
+            "--after-command",
+            "/bin/true",
+            "--commit-keyring",
+            keyring.to_str().unwrap(),
+            "--ssh-key",
+            "/nonexistent/id_ed25519",
+        ]);
+
+        let checks = check_config(&args);
+        let failed = checks
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            ["commit keyring", "tag command", "before command", "ssh key"]
+        );
+        assert!(checks.contains(&("commit command".to_string(), Ok(()))));
+        assert!(checks.contains(&("after command".to_string(), Ok(()))));
+    }
+
+    #[test]
+    fn can_check_program_of_command() {
+        let failed = |extra_args: &[&str], command: &str| {
+            let args = Args::parse_from(
+                ["webhook-runner", "--commit-command", command]
+                    .iter()
+                    .chain(extra_args),
+            );
+            check_config(&args)
+                .into_iter()
+                .any(|(name, result)| name == "commit command" && result.is_err())
+        };
+
+        assert!(!failed(&[], "deploy.sh | tee deploy.log"));
+        assert!(failed(&["--shell", "/nonexistent/bash -c"], "true"));
+        assert!(!failed(&["--no-shell"], "'/bin/true' --now"));
+        assert!(!failed(&["--no-shell"], "true --now"));
+        assert!(!failed(&["--no-shell"], "./deploy.sh"));
+        assert!(failed(&["--no-shell"], "'/nonexistent/deploy tool' --now"));
+        assert!(failed(
+            &["--no-shell"],
+            "nonexistent-webhook-runner-program"
+        ));
+        assert!(failed(&["--no-shell"], "'unbalanced"));
+    }
+}
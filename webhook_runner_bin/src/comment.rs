@@ -0,0 +1,88 @@
+use std::str::FromStr;
+
+/// Fields that may be included in a commit comment. None of them are secret, and the template
+/// can't refer to anything else, so a comment never includes secrets.
+const FIELDS: [&str; 6] = [
+    "repository",
+    "ref",
+    "commit",
+    "signer",
+    "fingerprint",
+    "status",
+];
+
+/// Template of the comment posted on commits once they were verified and processed, with fields
+/// written in braces, such as `Verified by {signer} ({fingerprint}): {status}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CommentTemplate(String);
+
+impl CommentTemplate {
+    /// Fill in the template, taking the value of each field from `field`.
+    pub(crate) fn render<'a>(&self, field: impl Fn(&str) -> &'a str) -> String {
+        FIELDS.iter().fold(self.0.clone(), |body, name| {
+            body.replace(&format!("{{{name}}}"), field(name))
+        })
+    }
+}
+
+impl Default for CommentTemplate {
+    fn default() -> Self {
+        CommentTemplate(
+            "webhook-runner verified {commit} as signed by {signer} with key {fingerprint}: \
+            {status}"
+                .to_string(),
+        )
+    }
+}
+
+impl FromStr for CommentTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let (name, after) = rest[start + 1..]
+                .split_once('}')
+                .ok_or_else(|| format!("invalid comment template: unclosed field in {s}"))?;
+            if !FIELDS.contains(&name) {
+                return Err(format!(
+                    "invalid comment template: unknown field {{{name}}}, expected one of {}",
+                    FIELDS.map(|field| format!("{{{field}}}")).join(", ")
+                ));
+            }
+            rest = after;
+        }
+        Ok(CommentTemplate(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_render_comment_template() {
+        let template: CommentTemplate = "{signer} ({fingerprint}) verified {commit}: {status}"
+            .parse()
+            .unwrap();
+        let body = template.render(|field| match field {
+            "signer" => "Jane Doe <jane@example.com>",
+            "fingerprint" => "0123ABCD",
+            "commit" => "7fd1a60b",
+            "status" => "completed",
+            _ => "",
+        });
+        assert_eq!(
+            body,
+            "Jane Doe <jane@example.com> (0123ABCD) verified 7fd1a60b: completed"
+        );
+    }
+
+    #[test]
+    fn will_error_on_unknown_template_field() {
+        assert!("{signer} {github_token}"
+            .parse::<CommentTemplate>()
+            .is_err());
+        assert!("{signer".parse::<CommentTemplate>().is_err());
+    }
+}
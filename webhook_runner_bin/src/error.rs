@@ -8,27 +8,71 @@ pub(crate) enum HubSignatureValidationError {
         source: http::header::ToStrError,
     },
 
-    #[error("the http header was malformed: {header}")]
-    Content { header: String },
-
-    #[error("header value for signature was incorrect size: {length} != {intended}")]
-    Length { length: usize, intended: u32 },
-
-    #[error("hex value was malformed: {source}")]
-    HexDecode {
+    #[error(transparent)]
+    Signature {
         #[from]
-        source: hex::FromHexError,
+        source: webhook_runner_lib::ProcessingError,
     },
 
-    #[error("invalid length of hmac key: {source}")]
-    HmacKeyLength {
-        #[from]
-        source: crypto_common::InvalidLength,
-    },
+    #[error("webhook token did not match expected")]
+    TokenMismatch,
+}
 
-    #[error("hmac did not match expected: {source}")]
-    HmacVerification {
-        #[from]
-        source: digest::MacError,
-    },
+#[derive(Error, Debug, PartialEq, Eq)]
+pub(crate) enum ConfigError {
+    #[error("tag keyring defined without defining tag command")]
+    TagKeyringWithoutCommand,
+
+    #[error("commit keyring defined without defining commit or deployment command")]
+    CommitKeyringWithoutCommand,
+
+    #[error("pull request keyring defined without defining pull request command")]
+    PrKeyringWithoutCommand,
+
+    #[error("protected branches defined without defining commit keyring")]
+    ProtectedBranchesWithoutKeyring,
+
+    #[error("repository with ssh authentication defined without defining ssh key or ssh agent")]
+    SshRepositoryWithoutKey,
+
+    #[error("tag keyring defined for {repository} without defining tag command")]
+    RepositoryTagKeyringWithoutCommand { repository: String },
+
+    #[error("commit keyring defined for {repository} without defining commit command")]
+    RepositoryCommitKeyringWithoutCommand { repository: String },
+
+    #[error("https token and https token file must not both be defined")]
+    ConflictingHttpsToken,
+
+    #[error("ssh key passphrase and ssh key passphrase file must not both be defined")]
+    ConflictingSshKeyPassphrase,
+
+    #[error("ssh key passphrase was defined without an ssh key")]
+    SshKeyPassphraseWithoutKey,
+
+    #[error("known hosts file and skipping host verification must not both be defined")]
+    ConflictingHostVerification,
+
+    #[error(
+        "command user or group defined, but only root is able to run commands as another user"
+    )]
+    CommandUserWithoutRoot,
+
+    #[error("unable to run commands as the configured user: {reason}")]
+    InvalidCommandUser { reason: String },
+
+    #[error("history file can't be written to: {reason}")]
+    HistoryFile { reason: String },
+
+    #[error("tls certificate and tls key must be defined together")]
+    IncompleteTls,
+
+    #[error("github app id, private key, and installation id must be defined together")]
+    IncompleteGitHubApp,
+
+    #[error("async processing requires at least one worker and a queue capacity of at least one")]
+    EmptyWorkerPool,
+
+    #[error("github token can only comment on commits of github webhooks")]
+    GitHubTokenWithoutGitHub,
 }
@@ -0,0 +1,207 @@
+use serde::Deserialize;
+
+use webhook_runner_lib::payload::{
+    is_zero_id, CommitStats, GitUser, Payload, PushRepository, UserRef,
+};
+
+/// Name of the header that Gitea and Forgejo send the kind of event in.
+pub(crate) const GITEA_EVENT: &str = "x-gitea-event";
+
+/// A push of commits to a branch, or of a tag, sent by Gitea or Forgejo. The payload is close to
+/// the payload GitHub sends, but leaves out some fields and represents users differently.
+///
+/// Reference: <https://docs.gitea.com/usage/webhooks>
+#[derive(Debug, Deserialize)]
+pub(crate) struct Push {
+    #[serde(rename = "ref")]
+    _ref: String,
+    before: String,
+    after: String,
+    compare_url: String,
+    commits: Vec<Commit>,
+    total_commits: usize,
+    repository: Repository,
+    pusher: User,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    id: String,
+    message: String,
+    url: String,
+    author: GitUser,
+    committer: GitUser,
+    timestamp: String,
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    removed: Vec<String>,
+    #[serde(default)]
+    modified: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    name: String,
+    full_name: String,
+    clone_url: String,
+    ssh_url: String,
+    default_branch: String,
+    size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    login: String,
+    email: Option<String>,
+}
+
+impl From<Commit> for CommitStats {
+    fn from(commit: Commit) -> Self {
+        CommitStats {
+            added: commit.added,
+            author: commit.author,
+            committer: commit.committer,
+            distinct: true,
+            id: commit.id,
+            message: commit.message,
+            modified: commit.modified,
+            removed: commit.removed,
+            timestamp: commit.timestamp,
+            url: commit.url,
+            ..CommitStats::default()
+        }
+    }
+}
+
+impl From<Push> for Payload {
+    /// Convert a Gitea push to the push payload that GitHub would have sent. Gitea only lists the
+    /// most recent commits of a push, so the commits are marked as truncated if any were left out.
+    fn from(push: Push) -> Self {
+        let commits_truncated = push.total_commits > push.commits.len();
+        let mut commits = push
+            .commits
+            .into_iter()
+            .map(CommitStats::from)
+            .collect::<Vec<_>>();
+        // Tag pushes don't list any commits, only the commit that the tag points to
+        if commits.is_empty() {
+            commits.push(CommitStats {
+                id: push.after.clone(),
+                ..CommitStats::default()
+            });
+        }
+        let head_commit = commits.last().cloned().unwrap_or_default();
+
+        // Pushes that create or delete a ref are only marked by an id of all zeros
+        let created = is_zero_id(&push.before);
+        let deleted = is_zero_id(&push.after);
+
+        Payload::Push {
+            after: push.after,
+            base_ref: None,
+            before: push.before,
+            commits,
+            commits_truncated,
+            compare: push.compare_url,
+            created,
+            deleted,
+            forced: false,
+            head_commit,
+            pusher: UserRef {
+                name: push.pusher.login,
+                email: push.pusher.email,
+                ..UserRef::default()
+            },
+            _ref: push._ref,
+            repository: PushRepository {
+                clone_url: push.repository.clone_url,
+                default_branch: push.repository.default_branch,
+                full_name: push.repository.full_name,
+                name: push.repository.name,
+                size: push.repository.size,
+                ssh_url: push.repository.ssh_url,
+                ..PushRepository::default()
+            },
+            sender: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH: &str = include_str!("testdata/gitea_push.json");
+
+    #[test]
+    fn can_decode_push_payload() {
+        let push: Push = serde_json::from_str(PUSH).expect("unable to decode push");
+        match Payload::from(push) {
+            Payload::Push {
+                _ref,
+                before,
+                after,
+                commits,
+                commits_truncated,
+                compare,
+                head_commit,
+                pusher,
+                repository,
+                ..
+            } => {
+                assert_eq!(_ref, "refs/heads/main");
+                assert_eq!(before, "28e1879d029cb852e4844d9c718537df08844e03");
+                assert_eq!(after, "bffeb74224043ba2feb48d137756c8a9331c449a");
+                // `compare_url` is mapped to `compare`
+                assert!(compare.ends_with("/compare/28e1879d029cb852e4844d9c718537df08844e03...bffeb74224043ba2feb48d137756c8a9331c449a"));
+                assert_eq!(commits.len(), 1);
+                assert!(!commits_truncated);
+                assert_eq!(commits[0].added, ["deploy/production.yaml"]);
+                assert_eq!(commits[0].modified, ["README.md"]);
+                assert_eq!(commits[0].author.username.as_deref(), Some("gitea"));
+                assert_eq!(head_commit.id, after);
+                // The `login` of the pusher is mapped to the `name` of the pusher
+                assert_eq!(pusher.name, "gitea");
+                assert_eq!(pusher.email.as_deref(), Some("someone@gitea.io"));
+                assert_eq!(repository.full_name, "gitea/webhooks");
+                assert_eq!(
+                    repository.clone_url,
+                    "https://gitea.example.com/gitea/webhooks.git"
+                );
+                assert_eq!(repository.default_branch, "main");
+                assert_eq!(repository.size, Some(1024));
+            }
+            e => panic!("incorrect payload converted: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn can_mark_truncated_commits() {
+        let mut json: serde_json::Value = serde_json::from_str(PUSH).unwrap();
+        json["total_commits"] = 6.into();
+        let push: Push = serde_json::from_value(json).unwrap();
+        match Payload::from(push) {
+            Payload::Push {
+                commits_truncated, ..
+            } => assert!(commits_truncated),
+            e => panic!("incorrect payload converted: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn can_decode_tag_push_payload() {
+        let mut json: serde_json::Value = serde_json::from_str(PUSH).unwrap();
+        json["ref"] = "refs/tags/v1.0.0".into();
+        json["commits"] = serde_json::json!([]);
+        json["total_commits"] = 0.into();
+        let push: Push = serde_json::from_value(json).unwrap();
+        match Payload::from(push) {
+            Payload::Push { commits, .. } => {
+                assert_eq!(commits.len(), 1);
+                assert_eq!(commits[0].id, "bffeb74224043ba2feb48d137756c8a9331c449a");
+            }
+            e => panic!("incorrect payload converted: {e:?}"),
+        }
+    }
+}
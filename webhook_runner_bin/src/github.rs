@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use webhook_runner_lib::payload::{
+    default_if_null, is_zero_id, CommitStats, Payload, PushRepository, User, UserRef,
+};
+
+use crate::status::DeathReason;
+
+/// Name of the header that GitHub sends the kind of event in.
+pub(crate) const GITHUB_EVENT: &str = "x-github-event";
+
+/// Fields that GitHub only sends for some pushes, such as pushes to repositories of an
+/// organization, which are accepted in strict mode even though they are not used.
+const OPTIONAL_PUSH_FIELDS: [&str; 3] = ["enterprise", "installation", "organization"];
+
+/// A push of commits to a branch, or of a tag, decoded on its own rather than as one of the
+/// untagged variants of [`Payload`], so that errors name the field that could not be decoded
+/// instead of only reporting that no variant matched. Fields that are not part of a push are
+/// collected rather than ignored, so that they can be rejected in strict mode.
+///
+/// Reference: <https://docs.github.com/en/webhooks/webhook-events-and-payloads#push>
+#[derive(Debug, Deserialize)]
+pub(crate) struct Push {
+    after: String,
+    base_ref: Option<String>,
+    before: String,
+    commits: Vec<CommitStats>,
+    compare: String,
+    created: bool,
+    deleted: bool,
+    forced: bool,
+    #[serde(deserialize_with = "default_if_null")]
+    head_commit: CommitStats,
+    pusher: UserRef,
+    #[serde(rename = "ref")]
+    _ref: String,
+    repository: PushRepository,
+    sender: User,
+    #[serde(flatten)]
+    unknown_fields: BTreeMap<String, serde_json::Value>,
+}
+
+impl From<Push> for Payload {
+    fn from(push: Push) -> Self {
+        let created = push.created || is_zero_id(&push.before);
+        let deleted = push.deleted || is_zero_id(&push.after);
+        Payload::Push {
+            after: push.after,
+            base_ref: push.base_ref,
+            before: push.before,
+            commits: push.commits,
+            commits_truncated: false,
+            compare: push.compare,
+            created,
+            deleted,
+            forced: push.forced,
+            head_commit: push.head_commit,
+            pusher: push.pusher,
+            _ref: push._ref,
+            repository: push.repository,
+            sender: push.sender,
+        }
+    }
+}
+
+/// Decode the body of a webhook sent by GitHub for `event`, naming the path of the field that
+/// could not be decoded, such as `repository.owner.id`. Pushes are decoded as a [`Push`], and if
+/// `strict` is set, are rejected if they have any field that is not known, including fields of
+/// the repository, commits, and users within them. Other events are decoded as any variant of
+/// [`Payload`], whatever their fields.
+pub(crate) fn decode_payload(
+    event: Option<&[u8]>,
+    body: &[u8],
+    strict: bool,
+) -> Result<Payload, DeathReason> {
+    let invalid = |e: serde_path_to_error::Error<serde_json::Error>| DeathReason::InvalidWebhook {
+        field_path: e.path().to_string(),
+        value: Some(e.into_inner().to_string()),
+    };
+    let trailing = |e: serde_json::Error| DeathReason::InvalidWebhook {
+        field_path: ".".to_string(),
+        value: Some(e.to_string()),
+    };
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    if event != Some(b"push") {
+        let payload = serde_path_to_error::deserialize(&mut deserializer).map_err(invalid)?;
+        deserializer.end().map_err(trailing)?;
+        return Ok(payload);
+    }
+
+    // Fields of nested objects that are not known are ignored while decoding, and only recorded
+    let mut ignored = vec![];
+    let mut record = |path: serde_ignored::Path<'_>| ignored.push(path.to_string());
+    let push: Push = serde_path_to_error::deserialize(serde_ignored::Deserializer::new(
+        &mut deserializer,
+        &mut record,
+    ))
+    .map_err(invalid)?;
+    deserializer.end().map_err(trailing)?;
+    if !strict {
+        return Ok(Payload::from(push));
+    }
+    let unknown = push
+        .unknown_fields
+        .iter()
+        .find(|(field, _)| !OPTIONAL_PUSH_FIELDS.contains(&field.as_str()));
+    if let Some((field, value)) = unknown {
+        return Err(DeathReason::InvalidWebhook {
+            field_path: field.clone(),
+            value: Some(value.to_string()),
+        });
+    }
+    match ignored.into_iter().next() {
+        Some(field_path) => Err(DeathReason::InvalidWebhook {
+            field_path,
+            value: None,
+        }),
+        None => Ok(Payload::from(push)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH: &str = include_str!("testdata/github_tag_push.json");
+
+    fn push_with(field: &str, value: serde_json::Value) -> Vec<u8> {
+        let mut json: serde_json::Value = serde_json::from_str(PUSH).unwrap();
+        json[field] = value;
+        serde_json::to_vec(&json).unwrap()
+    }
+
+    #[test]
+    fn can_reject_unknown_fields_in_strict_mode() {
+        let body = push_with("unexpected", "value".into());
+        match decode_payload(Some(b"push"), &body, false) {
+            Ok(Payload::Push { _ref, .. }) => assert_eq!(_ref, "refs/tags/v1.0.0"),
+            e => panic!("push with unknown field was not decoded: {e:?}"),
+        }
+        match decode_payload(Some(b"push"), &body, true) {
+            Err(DeathReason::InvalidWebhook { field_path, value }) => {
+                assert_eq!(field_path, "unexpected");
+                assert_eq!(value.as_deref(), Some("\"value\""));
+            }
+            e => panic!("push with unknown field was not rejected: {e:?}"),
+        }
+
+        // Pushes to repositories of an organization are still accepted
+        let body = push_with("organization", serde_json::json!({"login": "octo-org"}));
+        assert!(decode_payload(Some(b"push"), &body, true).is_ok());
+
+        // Fields of the repository, commits, and users are checked as well
+        let mut json: serde_json::Value = serde_json::from_str(PUSH).unwrap();
+        json["repository"]["owner"]["unexpected"] = "value".into();
+        let body = serde_json::to_vec(&json).unwrap();
+        assert!(decode_payload(Some(b"push"), &body, false).is_ok());
+        match decode_payload(Some(b"push"), &body, true) {
+            Err(DeathReason::InvalidWebhook { field_path, .. }) => {
+                assert_eq!(field_path, "repository.owner.unexpected");
+            }
+            e => panic!("push with unknown nested field was not rejected: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn will_name_field_of_invalid_push() {
+        let body = push_with("after", serde_json::Value::Null);
+        match decode_payload(Some(b"push"), &body, false) {
+            Err(DeathReason::InvalidWebhook { field_path, value }) => {
+                assert_eq!(field_path, "after");
+                let value = value.unwrap();
+                assert!(value.contains("invalid type: null"), "{value}");
+            }
+            e => panic!("invalid push was not rejected: {e:?}"),
+        }
+
+        let mut json: serde_json::Value = serde_json::from_str(PUSH).unwrap();
+        json["head_commit"]["author"]["name"] = 1.into();
+        let body = serde_json::to_vec(&json).unwrap();
+        match decode_payload(Some(b"push"), &body, false) {
+            Err(DeathReason::InvalidWebhook { field_path, .. }) => {
+                assert_eq!(field_path, "head_commit.author.name");
+            }
+            e => panic!("invalid push was not rejected: {e:?}"),
+        }
+
+        let mut json: serde_json::Value = serde_json::from_str(PUSH).unwrap();
+        json.as_object_mut().unwrap().remove("before");
+        let body = serde_json::to_vec(&json).unwrap();
+        match decode_payload(Some(b"push"), &body, false) {
+            Err(DeathReason::InvalidWebhook { value, .. }) => {
+                let value = value.unwrap();
+                assert!(value.contains("missing field `before`"), "{value}");
+            }
+            e => panic!("push without before was not rejected: {e:?}"),
+        }
+    }
+}
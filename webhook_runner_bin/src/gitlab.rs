@@ -0,0 +1,204 @@
+use serde::Deserialize;
+
+use webhook_runner_lib::payload::{
+    is_zero_id, CommitStats, GitUser, Payload, PushRepository, UserRef,
+};
+
+/// Events sent by GitLab, tagged by the kind of object that the event is about.
+///
+/// Reference: <https://docs.gitlab.com/ee/user/project/integrations/webhook_events.html>
+#[derive(Debug, Deserialize)]
+#[serde(tag = "object_kind", rename_all = "snake_case")]
+pub(crate) enum GitLabPayload {
+    Push(Push),
+    TagPush(Push),
+    #[serde(other)]
+    Other,
+}
+
+/// A push of commits to a branch, or of a tag.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Push {
+    before: String,
+    after: String,
+    #[serde(rename = "ref")]
+    _ref: String,
+    checkout_sha: Option<String>,
+    user_username: String,
+    user_email: Option<String>,
+    project: Project,
+    commits: Vec<Commit>,
+    total_commits_count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct Project {
+    name: String,
+    path_with_namespace: String,
+    git_http_url: String,
+    git_ssh_url: String,
+    default_branch: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    id: String,
+    message: String,
+    timestamp: String,
+    url: String,
+    author: Author,
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    modified: Vec<String>,
+    #[serde(default)]
+    removed: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Author {
+    name: String,
+    email: String,
+}
+
+impl From<Commit> for CommitStats {
+    fn from(commit: Commit) -> Self {
+        let author = GitUser {
+            name: commit.author.name,
+            email: commit.author.email,
+            ..GitUser::default()
+        };
+        CommitStats {
+            added: commit.added,
+            committer: author.clone(),
+            author,
+            distinct: true,
+            id: commit.id,
+            message: commit.message,
+            modified: commit.modified,
+            removed: commit.removed,
+            timestamp: commit.timestamp,
+            url: commit.url,
+            ..CommitStats::default()
+        }
+    }
+}
+
+impl From<Push> for Payload {
+    /// Convert a GitLab push to the push payload that GitHub would have sent. GitLab lists at
+    /// most 20 commits, so the commits are marked as truncated if any were left out.
+    fn from(push: Push) -> Self {
+        let commits_truncated = push.total_commits_count > push.commits.len();
+        let mut commits = push
+            .commits
+            .into_iter()
+            .map(CommitStats::from)
+            .collect::<Vec<_>>();
+        // Tag pushes don't list any commits, only the commit that the tag points to
+        if commits.is_empty() {
+            if let Some(checkout_sha) = push.checkout_sha {
+                commits.push(CommitStats {
+                    id: checkout_sha,
+                    ..CommitStats::default()
+                });
+            }
+        }
+        let head_commit = commits.last().cloned().unwrap_or_default();
+
+        // Pushes that create or delete a ref are only marked by an id of all zeros
+        let created = is_zero_id(&push.before);
+        let deleted = is_zero_id(&push.after);
+
+        Payload::Push {
+            after: push.after,
+            base_ref: None,
+            before: push.before,
+            commits,
+            commits_truncated,
+            compare: String::new(),
+            created,
+            deleted,
+            forced: false,
+            head_commit,
+            pusher: UserRef {
+                name: push.user_username,
+                email: push.user_email,
+                ..UserRef::default()
+            },
+            _ref: push._ref,
+            repository: PushRepository {
+                clone_url: push.project.git_http_url,
+                default_branch: push.project.default_branch,
+                full_name: push.project.path_with_namespace,
+                name: push.project.name,
+                ssh_url: push.project.git_ssh_url,
+                ..PushRepository::default()
+            },
+            sender: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH: &str = include_str!("testdata/gitlab_push.json");
+
+    #[test]
+    fn can_decode_push_payload() {
+        let push = match serde_json::from_str(PUSH).expect("unable to decode push") {
+            GitLabPayload::Push(push) => push,
+            e => panic!("incorrect payload decoded: {e:?}"),
+        };
+        match Payload::from(push) {
+            Payload::Push {
+                _ref,
+                commits,
+                commits_truncated,
+                pusher,
+                repository,
+                ..
+            } => {
+                assert_eq!(_ref, "refs/heads/main");
+                assert_eq!(commits.len(), 2);
+                assert_eq!(commits[1].id, "da1560886d4f094c3e6c9ef40349f7d38b5d27d7");
+                assert_eq!(commits[1].added, ["deploy/production.yaml"]);
+                assert!(!commits_truncated);
+                assert_eq!(pusher.name, "jsmith");
+                assert_eq!(repository.full_name, "mike/diaspora");
+                assert_eq!(repository.clone_url, "http://example.com/mike/diaspora.git");
+                assert_eq!(repository.default_branch, "main");
+            }
+            e => panic!("incorrect payload converted: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn can_decode_tag_push_payload() {
+        let json = PUSH
+            .replace(r#""object_kind": "push""#, r#""object_kind": "tag_push""#)
+            .replace("refs/heads/main", "refs/tags/v1.0.0");
+        let mut json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        json["commits"] = serde_json::json!([]);
+        json["total_commits_count"] = 0.into();
+
+        let push = match serde_json::from_value(json).expect("unable to decode tag push") {
+            GitLabPayload::TagPush(push) => push,
+            e => panic!("incorrect payload decoded: {e:?}"),
+        };
+        match Payload::from(push) {
+            Payload::Push { commits, .. } => {
+                assert_eq!(commits.len(), 1);
+                assert_eq!(commits[0].id, "da1560886d4f094c3e6c9ef40349f7d38b5d27d7");
+            }
+            e => panic!("incorrect payload converted: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn can_decode_other_payload() {
+        let payload = serde_json::from_str(r#"{"object_kind": "issue", "user": {}}"#);
+        assert!(matches!(payload, Ok(GitLabPayload::Other)));
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::Serialize;
+
+use crate::KeyringFiles;
+
+/// Body of a response to a health check.
+#[derive(Serialize, Debug)]
+pub(crate) struct Health {
+    status: &'static str,
+    version: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+
+/// Report whether the runner is ready to process webhooks. The runner is not ready if a
+/// configured keyring failed to load, since every push that requires the keyring would fail.
+///
+/// Repositories are cloned with libgit2, so there is no `git` binary to check for.
+pub(crate) async fn healthz(
+    Extension(keyring_files): Extension<Arc<KeyringFiles>>,
+) -> (StatusCode, Json<Health>) {
+    let errors = keyring_files
+        .configured()
+        .filter_map(|(name, keyring)| match keyring {
+            Err(reason) => Some(format!("{name} could not be loaded: {reason}")),
+            Ok(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let (code, status) = if errors.is_empty() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    (
+        code,
+        Json(Health {
+            status,
+            version: env!("CARGO_PKG_VERSION"),
+            errors,
+        }),
+    )
+}
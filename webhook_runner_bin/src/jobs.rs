@@ -0,0 +1,719 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use tokio::sync::{mpsc, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::cli::Args;
+use crate::status::{DeathReason, JobStatus};
+
+/// Limits how many webhooks are processed at once, so simultaneous pushes don't each clone a
+/// repository and run a command at the same time. Webhooks past the limit wait for a slot. Pushes
+/// may also be limited per repository, to coalesce bursts of pushes into the latest push instead
+/// of running each one, and jobs that recently completed may be skipped, so that a push delivered
+/// twice is only run once, even if the second delivery arrives while the first is running.
+#[derive(Debug)]
+pub(crate) struct JobQueue {
+    slots: Semaphore,
+    timeout: Duration,
+    push_limit: Option<PushLimit>,
+    completed: Option<CompletedJobs>,
+    workers: Option<Workers>,
+}
+
+/// The processing of a webhook that was accepted to be run in the background.
+type Work = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Webhooks accepted with `--async-processing`, waiting in a bounded queue to be run in the order
+/// they were accepted by a fixed number of workers.
+#[derive(Debug)]
+struct Workers {
+    capacity: usize,
+    /// Taken once the runner shuts down, so that workers stop once the queue is empty
+    sender: Mutex<Option<mpsc::Sender<Work>>>,
+    handles: tokio::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// A job that ran for a commit of a repository, and the target it ran for, such as the ref the
+/// commit was pushed to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Job {
+    pub(crate) repository: String,
+    pub(crate) commit: String,
+    pub(crate) target: String,
+}
+
+/// Storage for the jobs that completed successfully and when they did. Jobs are only stored in
+/// memory, but implementing this for a persistent store would remember jobs across restarts.
+pub(crate) trait CompletedJobStore: std::fmt::Debug + Send + Sync {
+    /// When `job` last completed, if it was recorded.
+    fn completed_at(&self, job: &Job) -> Option<Instant>;
+
+    /// Record that `job` completed at `time`, forgetting jobs that completed before `expired`.
+    fn record(&self, job: Job, time: Instant, expired: Instant);
+}
+
+/// Stores completed jobs in memory, so they are forgotten when the runner restarts.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryJobStore {
+    jobs: Mutex<HashMap<Job, Instant>>,
+}
+
+impl CompletedJobStore for MemoryJobStore {
+    fn completed_at(&self, job: &Job) -> Option<Instant> {
+        let jobs = self.jobs.lock().expect("job store lock poisoned");
+        jobs.get(job).copied()
+    }
+
+    fn record(&self, job: Job, time: Instant, expired: Instant) {
+        let mut jobs = self.jobs.lock().expect("job store lock poisoned");
+        // Forget expired jobs, so the map doesn't grow without bound
+        jobs.retain(|_, completed| *completed > expired);
+        jobs.insert(job, time);
+    }
+}
+
+/// Jobs that completed within `ttl`, which are not run again, and the jobs that are running, which
+/// other deliveries of the same job wait for.
+#[derive(Debug)]
+struct CompletedJobs {
+    ttl: Duration,
+    store: Box<dyn CompletedJobStore>,
+    running: Mutex<HashSet<Job>>,
+    /// Notified whenever a running job finishes, whether or not it completed successfully
+    finished: tokio::sync::Notify,
+}
+
+/// A job started with [`JobQueue::start_job`], which other deliveries of the same job wait for
+/// until it is dropped.
+#[must_use]
+pub(crate) struct RunningJob<'a> {
+    queue: &'a JobQueue,
+    job: Job,
+}
+
+impl RunningJob<'_> {
+    /// Record that the job completed successfully, so that other deliveries of it are skipped.
+    pub(crate) fn completed(self) {
+        self.queue.record_completed(self.job.clone());
+    }
+}
+
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        if let Some(completed) = &self.queue.completed {
+            let mut running = completed.running.lock().expect("running job lock poisoned");
+            running.remove(&self.job);
+            completed.finished.notify_waiters();
+        }
+    }
+}
+
+/// Limits how many pushes to each repository are processed within a window of time, tracking the
+/// pushes of each repository by its full name.
+#[derive(Debug)]
+struct PushLimit {
+    max_pushes: usize,
+    window: Duration,
+    pushes: Mutex<PushLimitState>,
+    /// Notified whenever a push that was allowed is verified, or dropped without being verified
+    released: tokio::sync::Notify,
+}
+
+#[derive(Debug, Default)]
+struct PushLimitState {
+    repositories: HashMap<String, RepositoryPushes>,
+    next_ticket: u64,
+}
+
+/// The pushes to a repository that count against its limit: those verified within the window, and
+/// those allowed that are not verified yet. Pushes past the limit wait for the window to allow
+/// another push, unless a later push arrived since.
+#[derive(Debug, Default)]
+struct RepositoryPushes {
+    verified: VecDeque<Instant>,
+    in_flight: usize,
+    waiting: usize,
+    latest: u64,
+}
+
+/// A push waiting for the limit of its repository, which stops waiting once dropped.
+struct Waiting<'a> {
+    limit: &'a PushLimit,
+    repository: &'a str,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limit.pushes.lock().expect("push limit lock poisoned");
+        if let Some(pushes) = state.repositories.get_mut(self.repository) {
+            pushes.waiting -= 1;
+        }
+    }
+}
+
+/// A push allowed by the push limit of its repository, which only counts against the limit once
+/// [`PushPermit::verified`] is called. Dropping the permit without verifying the push frees its
+/// place for another push.
+#[must_use]
+pub(crate) struct PushPermit<'a> {
+    limit: Option<(&'a PushLimit, String)>,
+}
+
+impl PushPermit<'_> {
+    /// Count the push against the limit of its repository, now that its commit was verified.
+    pub(crate) fn verified(mut self) {
+        if let Some((limit, repository)) = self.limit.take() {
+            let mut state = limit.pushes.lock().expect("push limit lock poisoned");
+            let pushes = state.repositories.entry(repository).or_default();
+            pushes.in_flight -= 1;
+            pushes.verified.push_back(Instant::now());
+            // Pushes waiting on this one can now wait for the window instead
+            limit.released.notify_waiters();
+        }
+    }
+}
+
+impl Drop for PushPermit<'_> {
+    fn drop(&mut self) {
+        if let Some((limit, repository)) = self.limit.take() {
+            let mut state = limit.pushes.lock().expect("push limit lock poisoned");
+            if let Some(pushes) = state.repositories.get_mut(&repository) {
+                pushes.in_flight -= 1;
+            }
+            limit.released.notify_waiters();
+        }
+    }
+}
+
+impl JobQueue {
+    pub(crate) fn new(max_concurrent_jobs: usize, timeout: Duration) -> Self {
+        JobQueue {
+            slots: Semaphore::new(max_concurrent_jobs),
+            timeout,
+            push_limit: None,
+            completed: None,
+            workers: None,
+        }
+    }
+
+    /// Build the queue configured by the options of the runner.
+    pub(crate) fn from_args(args: &Args) -> Self {
+        let mut job_queue = JobQueue::new(
+            args.max_concurrent_jobs,
+            Duration::from_secs(args.job_queue_timeout),
+        );
+        if let Some(max_pushes) = args.max_pushes_per_repo_per_minute {
+            job_queue = job_queue.with_push_limit(max_pushes, Duration::from_secs(60));
+        }
+        if let Some(ttl) = args.completed_job_ttl {
+            job_queue = job_queue
+                .with_completed_jobs(Box::<MemoryJobStore>::default(), Duration::from_secs(ttl));
+        }
+        if args.async_processing {
+            job_queue = job_queue.with_workers(args.worker_count, args.queue_capacity);
+        }
+        job_queue
+    }
+
+    /// Run work queued with [`JobQueue::enqueue`] on `count` workers, queueing up to `capacity`
+    /// webhooks that no worker has taken yet. Workers are spawned right away, so this must be
+    /// called within a runtime.
+    #[must_use]
+    pub(crate) fn with_workers(mut self, count: usize, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Work>(capacity);
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let handles = (0..count)
+            .map(|worker| tokio::spawn(run_worker(worker, receiver.clone())))
+            .collect();
+        self.workers = Some(Workers {
+            capacity,
+            sender: Mutex::new(Some(sender)),
+            handles: tokio::sync::Mutex::new(handles),
+        });
+        self
+    }
+
+    /// Queue `work` to be run by the next free worker, returning an error without waiting if the
+    /// queue is full, or if there are no workers to run it.
+    pub(crate) fn enqueue(
+        &self,
+        work: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(), DeathReason> {
+        let Some(workers) = &self.workers else {
+            return Err(DeathReason::QueueFull { capacity: 0 });
+        };
+        let full = || DeathReason::QueueFull {
+            capacity: workers.capacity,
+        };
+        let sender = workers.sender.lock().expect("worker queue lock poisoned");
+        sender
+            .as_ref()
+            .ok_or_else(full)?
+            .try_send(Box::pin(work))
+            .map_err(|_| full())
+    }
+
+    /// Stop accepting work and wait until `deadline` for the workers to finish the work that was
+    /// already queued, cancelling whatever is left once it has passed.
+    pub(crate) async fn shutdown(&self, deadline: Instant) {
+        let Some(workers) = &self.workers else {
+            return;
+        };
+        drop(
+            workers
+                .sender
+                .lock()
+                .expect("worker queue lock poisoned")
+                .take(),
+        );
+        let mut handles = workers.handles.lock().await;
+        let grace = deadline.saturating_duration_since(Instant::now());
+        info!(?grace, "waiting for queued webhooks to finish");
+        let finished = futures::future::join_all(handles.iter_mut());
+        if tokio::time::timeout(grace, finished).await.is_err() {
+            warn!("queued webhooks did not finish within shutdown grace period, cancelling them");
+            for handle in handles.iter() {
+                handle.abort();
+            }
+        }
+    }
+
+    /// Skip jobs that completed successfully within `ttl`, as recorded in `store`.
+    #[must_use]
+    pub(crate) fn with_completed_jobs(
+        mut self,
+        store: Box<dyn CompletedJobStore>,
+        ttl: Duration,
+    ) -> Self {
+        self.completed = Some(CompletedJobs {
+            ttl,
+            store,
+            running: Mutex::default(),
+            finished: tokio::sync::Notify::new(),
+        });
+        self
+    }
+
+    /// Start running `job`, returning `None` if it completed successfully within the configured
+    /// time, so that it should not be run again. If another delivery of the job is running, this
+    /// waits for it to finish first, so that the job is only run again if that delivery failed.
+    pub(crate) async fn start_job(&self, job: Job) -> Option<RunningJob<'_>> {
+        let Some(completed) = &self.completed else {
+            return Some(RunningJob { queue: self, job });
+        };
+        loop {
+            let finished = completed.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+
+            {
+                let mut running = completed.running.lock().expect("running job lock poisoned");
+                if self.completed_recently(&job) {
+                    return None;
+                }
+                if running.insert(job.clone()) {
+                    return Some(RunningJob { queue: self, job });
+                }
+            }
+            debug!(?job, "waiting for another delivery of job to finish");
+            finished.await;
+        }
+    }
+
+    /// Determine whether `job` completed successfully within the configured time, so that it
+    /// should not be run again.
+    pub(crate) fn completed_recently(&self, job: &Job) -> bool {
+        self.completed.as_ref().is_some_and(|completed| {
+            completed
+                .store
+                .completed_at(job)
+                .is_some_and(|time| time.elapsed() < completed.ttl)
+        })
+    }
+
+    /// Record that `job` completed successfully, if completed jobs are skipped.
+    pub(crate) fn record_completed(&self, job: Job) {
+        if let Some(completed) = &self.completed {
+            let now = Instant::now();
+            let expired = now.checked_sub(completed.ttl).unwrap_or(now);
+            debug!(?job, "recording completed job");
+            completed.store.record(job, now, expired);
+        }
+    }
+
+    /// Only process up to `max_pushes` verified pushes to each repository within `window`.
+    #[must_use]
+    pub(crate) fn with_push_limit(mut self, max_pushes: usize, window: Duration) -> Self {
+        self.push_limit = Some(PushLimit {
+            max_pushes,
+            window,
+            pushes: Mutex::default(),
+            released: tokio::sync::Notify::new(),
+        });
+        self
+    }
+
+    /// Wait until a push to `repository` may be processed, returning `None` if a later push
+    /// arrived while waiting. Pushes past the limit wait for the window to allow another push, and
+    /// each push replaces the pushes waiting before it, so that a burst of pushes ends with a run
+    /// of the latest push.
+    pub(crate) async fn allow_push(&self, repository: &str) -> Option<PushPermit<'_>> {
+        let Some(limit) = &self.push_limit else {
+            return Some(PushPermit { limit: None });
+        };
+        let mut ticket = None;
+        let mut waiting = None;
+        loop {
+            let released = limit.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let now = Instant::now();
+            let wake = {
+                let mut state = limit.pushes.lock().expect("push limit lock poisoned");
+                // Forget repositories without recent pushes, so the map doesn't grow without bound
+                state.repositories.retain(|_, pushes| {
+                    while pushes
+                        .verified
+                        .front()
+                        .is_some_and(|time| now.duration_since(*time) >= limit.window)
+                    {
+                        pushes.verified.pop_front();
+                    }
+                    !pushes.verified.is_empty() || pushes.in_flight > 0 || pushes.waiting > 0
+                });
+                let next_ticket = &mut state.next_ticket;
+                let ticket = *ticket.get_or_insert_with(|| {
+                    *next_ticket += 1;
+                    *next_ticket
+                });
+                let pushes = state
+                    .repositories
+                    .entry(repository.to_string())
+                    .or_default();
+                pushes.latest = pushes.latest.max(ticket);
+                if pushes.latest > ticket {
+                    debug!(repository, "push was superseded by a later push");
+                    return None;
+                }
+                if pushes.verified.len() + pushes.in_flight < limit.max_pushes {
+                    pushes.in_flight += 1;
+                    return Some(PushPermit {
+                        limit: Some((limit, repository.to_string())),
+                    });
+                }
+                if waiting.is_none() {
+                    debug!(
+                        repository,
+                        "push limit reached, waiting to run the latest push"
+                    );
+                    pushes.waiting += 1;
+                    waiting = Some(Waiting { limit, repository });
+                }
+                pushes.verified.front().map(|time| *time + limit.window)
+            };
+            match wake {
+                Some(wake) => {
+                    tokio::select! {
+                        () = tokio::time::sleep_until(wake.into()) => (),
+                        () = released => (),
+                    }
+                }
+                None => released.await,
+            }
+        }
+    }
+
+    /// Wait for a slot to run a job in, returning an error if no slot was available before the
+    /// queue timeout. The slot is released once the permit is dropped.
+    pub(crate) async fn acquire(&self) -> Result<SemaphorePermit<'_>, DeathReason> {
+        debug!(
+            available = self.slots.available_permits(),
+            "waiting for job slot"
+        );
+        tokio::time::timeout(self.timeout, self.slots.acquire())
+            .await
+            .map_err(|_| DeathReason::QueueTimeout {
+                timeout: self.timeout.as_secs(),
+            })
+            .map(|permit| permit.expect("job queue semaphore is never closed"))
+    }
+}
+
+/// Process a webhook, reporting a panic as the reason it died, since nothing waits on the response
+/// of webhooks processed in the background to report it otherwise.
+pub(crate) async fn catch_panic(
+    processing: impl Future<Output = Result<JobStatus, DeathReason>>,
+) -> Result<JobStatus, DeathReason> {
+    AssertUnwindSafe(processing)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|panic| {
+            Err(DeathReason::Panicked {
+                reason: panic_message(panic.as_ref()).to_string(),
+            })
+        })
+}
+
+/// The message a panic was started with, if it was started with one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Take work from the queue and run it until the queue is closed and empty. Panics only end the
+/// work that panicked, and are logged here in case the work did not report them itself.
+async fn run_worker(worker: usize, receiver: Arc<tokio::sync::Mutex<mpsc::Receiver<Work>>>) {
+    loop {
+        // The receiver is only locked while waiting, so that each free worker takes work in turn
+        let Some(work) = receiver.lock().await.recv().await else {
+            break;
+        };
+        if let Err(panic) = AssertUnwindSafe(work).catch_unwind().await {
+            let message = panic_message(panic.as_ref());
+            error!(worker, message, "background processing of webhook panicked");
+        }
+    }
+    debug!(worker, "worker stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn can_queue_job_past_limit() {
+        let queue = Arc::new(JobQueue::new(2, Duration::from_secs(10)));
+        let first = queue.acquire().await.unwrap();
+        let _second = queue.acquire().await.unwrap();
+
+        let waiting = queue.clone();
+        let mut third = tokio::spawn(async move { waiting.acquire().await.map(drop) });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut third)
+                .await
+                .is_err(),
+            "job past the limit did not wait"
+        );
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), third)
+            .await
+            .expect("queued job did not run")
+            .unwrap()
+            .expect("queued job timed out");
+    }
+
+    #[tokio::test]
+    async fn can_limit_pushes_per_repository() {
+        let queue =
+            JobQueue::new(1, Duration::from_secs(1)).with_push_limit(2, Duration::from_millis(200));
+        let repository = "octocat/hello-world";
+        queue.allow_push(repository).await.unwrap().verified();
+        // Pushes that are never verified don't count against the limit
+        drop(queue.allow_push(repository).await.unwrap());
+        queue.allow_push(repository).await.unwrap().verified();
+        queue
+            .allow_push("octocat/other")
+            .await
+            .expect("push limit was shared between repositories")
+            .verified();
+
+        // Of a burst of pushes past the limit, only the latest is run once the window allows
+        let started = Instant::now();
+        let (first, second, third) = tokio::join!(
+            queue.allow_push(repository),
+            async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                queue.allow_push(repository).await
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                queue.allow_push(repository).await
+            },
+        );
+        assert!(first.is_none(), "superseded push was run");
+        assert!(second.is_none(), "superseded push was run");
+        let third = third.expect("latest push of the burst was not run");
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        third.verified();
+    }
+
+    #[tokio::test]
+    async fn can_run_push_waiting_on_unverified_push() {
+        let queue =
+            JobQueue::new(1, Duration::from_secs(1)).with_push_limit(1, Duration::from_millis(200));
+        let repository = "octocat/hello-world";
+        let first = queue.allow_push(repository).await.unwrap();
+
+        // The second push waits on the first, which is neither verified nor dropped yet
+        let started = Instant::now();
+        let (second, ()) = tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::join(queue.allow_push(repository), async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                first.verified();
+            }),
+        )
+        .await
+        .expect("push waiting on a verified push was never run");
+        let second = second.expect("latest push was not run");
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        second.verified();
+    }
+
+    #[test]
+    fn can_skip_recently_completed_jobs() {
+        let queue = JobQueue::new(1, Duration::from_secs(1))
+            .with_completed_jobs(Box::<MemoryJobStore>::default(), Duration::from_millis(200));
+        let job = |commit: &str| Job {
+            repository: "octocat/hello-world".to_string(),
+            commit: commit.to_string(),
+            target: "refs/heads/main".to_string(),
+        };
+        assert!(!queue.completed_recently(&job("7fd1a60b")));
+        queue.record_completed(job("7fd1a60b"));
+        assert!(queue.completed_recently(&job("7fd1a60b")));
+        assert!(
+            !queue.completed_recently(&job("553c2077")),
+            "job for another commit was skipped"
+        );
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(!queue.completed_recently(&job("7fd1a60b")));
+
+        // Completed jobs are never skipped unless asked for
+        let queue = JobQueue::new(1, Duration::from_secs(1));
+        queue.record_completed(job("7fd1a60b"));
+        assert!(!queue.completed_recently(&job("7fd1a60b")));
+    }
+
+    #[tokio::test]
+    async fn can_wait_for_running_job() {
+        let queue = JobQueue::new(2, Duration::from_secs(1))
+            .with_completed_jobs(Box::<MemoryJobStore>::default(), Duration::from_secs(60));
+        let job = |commit: &str| Job {
+            repository: "octocat/hello-world".to_string(),
+            commit: commit.to_string(),
+            target: "refs/heads/main".to_string(),
+        };
+
+        // Another delivery of a job that failed is run once the first delivery is done
+        let first = queue.start_job(job("7fd1a60b")).await.unwrap();
+        let (second, ()) = futures::future::join(queue.start_job(job("7fd1a60b")), async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(first);
+        })
+        .await;
+        let second = second.expect("job was skipped after the first delivery failed");
+        let other = queue
+            .start_job(job("553c2077"))
+            .await
+            .expect("job for another commit waited");
+        drop(other);
+
+        // Another delivery of a job that completed is skipped
+        let (third, ()) = futures::future::join(queue.start_job(job("7fd1a60b")), async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            second.completed();
+        })
+        .await;
+        assert!(third.is_none(), "job was run again after it completed");
+    }
+
+    #[tokio::test]
+    async fn will_error_on_queue_timeout() {
+        let queue = JobQueue::new(1, Duration::from_millis(100));
+        let _first = queue.acquire().await.unwrap();
+        let result = queue.acquire().await.map(drop);
+        match result {
+            Err(DeathReason::QueueTimeout { .. }) => (),
+            e => panic!("incorrect result from JobQueue::acquire: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_run_queued_work_in_order() {
+        let queue = JobQueue::new(1, Duration::from_secs(1)).with_workers(1, 10);
+        let ran = Arc::new(Mutex::new(vec![]));
+        for n in 0..5 {
+            let ran = ran.clone();
+            queue
+                .enqueue(async move {
+                    // A panic only ends the work that panicked, not the worker
+                    assert_ne!(n, 2, "work panicked");
+                    ran.lock().unwrap().push(n);
+                })
+                .expect("work was not queued");
+        }
+
+        queue
+            .shutdown(Instant::now() + Duration::from_secs(10))
+            .await;
+        assert_eq!(*ran.lock().unwrap(), [0, 1, 3, 4]);
+        match queue.enqueue(async {}) {
+            Err(DeathReason::QueueFull { .. }) => (),
+            e => panic!("work was queued after shutdown: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_report_panic_of_webhook() {
+        let processing = async {
+            let reason = "webhook could not be processed";
+            panic!("{reason}");
+        };
+        match catch_panic(processing).await {
+            Err(DeathReason::Panicked { reason }) => {
+                assert_eq!(reason, "webhook could not be processed");
+            }
+            e => panic!("panic was not reported: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn will_error_on_full_work_queue() {
+        let queue = JobQueue::new(1, Duration::from_secs(1)).with_workers(1, 1);
+        let started = Arc::new(tokio::sync::Notify::new());
+        {
+            let started = started.clone();
+            queue
+                .enqueue(async move {
+                    started.notify_one();
+                    std::future::pending::<()>().await;
+                })
+                .unwrap();
+        }
+        // The first webhook is taken by the worker, and the second fills the queue
+        started.notified().await;
+        queue.enqueue(async {}).expect("queue was full too early");
+        match queue.enqueue(async {}) {
+            Err(reason @ DeathReason::QueueFull { capacity: 1 }) => {
+                let response = axum::response::IntoResponse::into_response(reason);
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            }
+            e => panic!("incorrect result from JobQueue::enqueue: {e:?}"),
+        }
+
+        // Work that never finishes is cancelled once the grace period has passed
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            queue.shutdown(Instant::now() + Duration::from_millis(100)),
+        )
+        .await
+        .expect("shutdown waited past the grace period");
+    }
+}
@@ -1,76 +1,1206 @@
 //! Documentation of the command options of the crate can be found by running `webhook-runner -h`,
 //! including flags, options, and environment variables.
 
-use std::sync::Arc;
+use std::cell::Cell;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use axum::{body, routing::post, Extension, Router};
-use clap::Parser;
+use axum::{
+    body,
+    routing::{get, post},
+    Extension, Router,
+};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::try_join_all;
+use hyper::server::conn::AddrIncoming;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tower::ServiceBuilder;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
 use tower_http::ServiceBuilderExt;
-use tracing::info;
+use tracing::{error, info, warn};
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
 
-use webhook_runner_lib::cert_builder;
+use metrics_exporter_prometheus::PrometheusHandle;
+
+use webhook_runner_lib::cert_builder::KeyringFile;
+use webhook_runner_lib::github_app::GitHubApp;
 use webhook_runner_lib::repository;
+use webhook_runner_lib::watcher::{watch_keyring, KeyringWatcher, SharedKeyringFile};
 use webhook_runner_lib::KeyringFiles;
 
+mod check;
 mod cli;
+mod comment;
 mod error;
-mod payload;
+mod events;
+mod gitea;
+mod github;
+mod gitlab;
+mod health;
+mod jobs;
+mod metrics;
 mod signature;
+mod source;
 mod status;
+mod trigger;
 mod webhook;
 
-fn setup_registry() {
+#[cfg(test)]
+mod test_util;
+
+/// Build the layer that writes log lines in the given format.
+fn log_layer<S, W>(log_format: cli::LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match log_format {
+        cli::LogFormat::Pretty => layer.boxed(),
+        cli::LogFormat::Compact => layer.compact().boxed(),
+        cli::LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// Set up logging once options are parsed, so that `--log-format` applies to every log line.
+/// Levels are filtered with `RUST_LOG`, logging everything at `level` and above by default.
+fn setup_registry(log_format: cli::LogFormat, level: LevelFilter) {
     let envfilter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::DEBUG.into())
+        .with_default_directive(level.into())
         .from_env_lossy();
     tracing_subscriber::registry()
         .with(envfilter)
-        .with(tracing_subscriber::fmt::layer())
+        .with(log_layer(log_format, std::io::stdout))
         .init();
 }
 
+/// Load a keyring from the given paths, optionally watching the paths to reload the keyring when
+/// they change.
+fn load_keyring(
+    name: &str,
+    paths: &[String],
+    args: &cli::Args,
+) -> Result<(SharedKeyringFile, Option<KeyringWatcher>), Box<dyn std::error::Error>> {
+    let warning_window = Duration::from_secs(args.cert_expiry_warning_days * 24 * 60 * 60);
+    let keyring = KeyringFile::from_paths(paths)?;
+    keyring.report_expiration(name, warning_window);
+    let keyring = Arc::new(RwLock::new(keyring));
+    let watcher = if args.watch_keyrings {
+        let paths = paths.iter().map(PathBuf::from).collect();
+        let name = name.to_string();
+        Some(watch_keyring(
+            paths,
+            keyring.clone(),
+            Duration::from_millis(500),
+            move |keyring| keyring.report_expiration(&name, warning_window),
+        )?)
+    } else {
+        None
+    };
+    Ok((keyring, watcher))
+}
+
+/// Load every configured keyring, including the keyrings of individual repositories. A keyring
+/// that fails to load is an error if `fail_closed` is set, and is otherwise recorded so that
+/// pushes requiring it are rejected.
+fn load_keyrings(
+    args: &cli::Args,
+    fail_closed: bool,
+) -> Result<(KeyringFiles, Vec<KeyringWatcher>), Box<dyn std::error::Error>> {
+    let mut keyrings = KeyringFiles {
+        repositories: args
+            .repositories
+            .keys()
+            .map(|repository| (repository.clone(), KeyringFiles::default()))
+            .collect(),
+        ..KeyringFiles::default()
+    };
+    let mut watchers = vec![];
+    let mut configured = vec![
+        (
+            "commit".to_string(),
+            &args.commit_keyring[..],
+            &mut keyrings.commit,
+        ),
+        ("tag".to_string(), &args.tag_keyring[..], &mut keyrings.tag),
+        (
+            "pull-request".to_string(),
+            &args.pr_keyring[..],
+            &mut keyrings.pull_request,
+        ),
+    ];
+    // Both maps are ordered by the name of the repository
+    for ((repository, config), repository_keyrings) in args
+        .repositories
+        .iter()
+        .zip(keyrings.repositories.values_mut())
+    {
+        configured.extend([
+            (
+                format!("commit:{repository}"),
+                &config.commit_keyring[..],
+                &mut repository_keyrings.commit,
+            ),
+            (
+                format!("tag:{repository}"),
+                &config.tag_keyring[..],
+                &mut repository_keyrings.tag,
+            ),
+        ]);
+    }
+    for (name, paths, keyring) in configured {
+        if paths.is_empty() {
+            continue;
+        }
+        match load_keyring(&name, paths, args) {
+            Ok((loaded, watcher)) => {
+                keyring.replace(Ok(loaded));
+                watchers.extend(watcher);
+            }
+            // Pushes that require the keyring are rejected, rather than skipping verification
+            Err(e) if !fail_closed => {
+                error!(keyring = %name, %e, "unable to load keyring, pushes requiring it will fail");
+                keyring.replace(Err(e.to_string()));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((keyrings, watchers))
+}
+
+/// Check that every loaded keyring is able to verify signatures, so that a keyring that would
+/// reject every push is noticed at startup rather than on the first webhook. Unusable keyrings
+/// are an error with `--strict-keyring`, and are otherwise only warned about.
+fn check_keyrings(keyrings: &KeyringFiles, args: &cli::Args) -> Result<(), String> {
+    for (name, keyring) in keyrings.configured() {
+        // Keyrings that failed to load have already been reported
+        let Ok(keyring) = keyring else {
+            continue;
+        };
+        let usable = keyring
+            .read()
+            .expect("poisoned keyring")
+            .check_usable(args.crypto_policy());
+        match usable {
+            Ok(()) => (),
+            Err(e) if args.strict_keyring => return Err(format!("{name} is unusable: {e}")),
+            Err(e) => warn!(keyring = %name, %e, "keyring is unable to verify any signature"),
+        }
+    }
+    Ok(())
+}
+
+/// Load the GitHub App used to clone repositories, if one is configured.
+fn load_github_app(args: &cli::Args) -> webhook_runner_lib::Result<Option<GitHubApp>> {
+    match (
+        args.github_app_id,
+        args.github_app_installation_id,
+        &args.github_app_private_key,
+    ) {
+        (Some(app_id), Some(installation_id), Some(private_key)) => Ok(Some(
+            GitHubApp::from_key_file(app_id, installation_id, private_key)?,
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// Build the router of the webhook endpoints, with the middleware that checks their sources and
+/// signatures and the extensions that their handlers take, so that it can be nested under a path
+/// prefix.
+pub(crate) fn build_router(
+    args: Arc<cli::Args>,
+    keyrings: Arc<KeyringFiles>,
+    github_app: Option<Arc<GitHubApp>>,
+    job_queue: Arc<jobs::JobQueue>,
+) -> Router {
+    let event_sink = Arc::new(events::EventSink::from_args(&args));
+    let webhook = match args.webhook_provider {
+        cli::WebhookProvider::GitHub => post(webhook::webhook),
+        cli::WebhookProvider::GitLab => post(webhook::gitlab_webhook),
+        cli::WebhookProvider::Gitea => post(webhook::gitea_webhook),
+    };
+    // Signed bodies are limited by the signature middleware, which reads them first; other bodies
+    // are limited right before the handler, so the handler can tell a body that is too large apart
+    let max_body_bytes = usize::try_from(args.max_body_bytes).unwrap_or(usize::MAX);
+    let router = Router::new().route("/", webhook);
+    // Manual triggers must never be accepted without verifying them, and clone the configured
+    // repository since there is no webhook to read it from
+    let router = if args.webhook_secret_key.is_some() && args.git_repository.is_some() {
+        router.route("/trigger", post(trigger::trigger))
+    } else {
+        router
+    };
+    let router = router.layer(RequestBodyLimitLayer::new(max_body_bytes));
+    let router = router.layer(
+        ServiceBuilder::new()
+            .map_request_body(body::boxed)
+            .layer(axum::middleware::from_fn(signature::verify_middleware)),
+    );
+    // Drop webhooks from unexpected addresses before reading the body to verify its signature
+    let router = router.layer(
+        ServiceBuilder::new()
+            .map_request_body(body::boxed)
+            .layer(axum::middleware::from_fn(source::verify_middleware)),
+    );
+    // Log lines of the middleware above carry the delivery ID as well
+    let router = router.layer(
+        ServiceBuilder::new()
+            .map_request_body(body::boxed)
+            .layer(axum::middleware::from_fn(events::delivery_span_middleware)),
+    );
+    router
+        .layer(Extension(args))
+        .layer(Extension(keyrings))
+        .layer(Extension(github_app))
+        .layer(Extension(job_queue))
+        .layer(Extension(event_sink))
+}
+
+/// Build the application, serving the webhook endpoints of [`build_router`] along with the health
+/// and metrics endpoints, which are not signed.
+fn app(
+    args: Arc<cli::Args>,
+    keyrings: KeyringFiles,
+    github_app: Option<Arc<GitHubApp>>,
+    job_queue: Arc<jobs::JobQueue>,
+    metrics_handle: PrometheusHandle,
+) -> Router {
+    let keyrings = Arc::new(keyrings);
+    let router = build_router(args.clone(), keyrings.clone(), github_app, job_queue);
+    // Routes added after the signature middleware are not wrapped by it
+    let router = router.route("/healthz", get(health::healthz));
+    let router = if args.metrics_bind.is_some() {
+        router
+    } else {
+        router.route("/metrics", get(metrics::metrics))
+    };
+    let router = match args.request_timeout {
+        Some(timeout) => router.layer(TimeoutLayer::new(Duration::from_secs(timeout))),
+        None => router,
+    };
+    router
+        .layer(Extension(metrics_handle))
+        .layer(Extension(keyrings))
+        .layer(TraceLayer::new_for_http())
+}
+
+/// Wait for SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("unable to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+    }
+}
+
+/// Serve the application until `shutdown` completes, then stop accepting connections and wait up
+/// to `grace` for webhooks that are being processed, such as running commands, to finish.
+async fn serve(
+    server: hyper::server::Builder<AddrIncoming>,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+    grace: Duration,
+) -> hyper::Result<()> {
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let server = server
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            info!(?grace, "shutting down, waiting for webhooks to finish");
+            let _ = shutdown_tx.send(());
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result,
+        Ok(()) = shutdown_rx => (),
+    }
+    if let Ok(result) = tokio::time::timeout(grace, server).await {
+        result
+    } else {
+        warn!("webhooks did not finish within shutdown grace period, exiting anyway");
+        Ok(())
+    }
+}
+
+/// Serve the application over HTTPS, shutting down the same way as [`serve`].
+async fn serve_tls(
+    server: axum_server::Server<RustlsAcceptor>,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+    grace: Duration,
+) -> std::io::Result<()> {
+    let handle = axum_server::Handle::new();
+    let server = server
+        .handle(handle.clone())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result,
+        () = shutdown => (),
+    }
+    info!(?grace, "shutting down, waiting for webhooks to finish");
+    handle.graceful_shutdown(Some(grace));
+    server.await
+}
+
+/// Wait until the shutdown signal is sent to every server by [`serve_all`].
+async fn until_shutdown(mut shutdown: watch::Receiver<()>) {
+    // The sender is only dropped once every server has stopped
+    let _ = shutdown.changed().await;
+}
+
+/// Run every server until `shutdown` completes, passing each server a receiver to wait for the
+/// shutdown with through [`until_shutdown`]. Returns once every server has shut down, or as soon as
+/// any server fails.
+async fn serve_all<S, F, E>(
+    servers: impl IntoIterator<Item = S>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), E>
+where
+    S: FnOnce(watch::Receiver<()>) -> F,
+    F: Future<Output = Result<(), E>>,
+{
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let servers = try_join_all(servers.into_iter().map(|serve| serve(shutdown_rx.clone())));
+    tokio::pin!(servers);
+
+    tokio::select! {
+        result = &mut servers => return result.map(drop),
+        () = shutdown => (),
+    }
+    let _ = shutdown_tx.send(());
+    servers.await.map(drop)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    setup_registry();
+    let args = Arc::new(cli::Args::parse_with_config(std::env::args_os())?);
+    setup_registry(args.log_format, args.log_level());
+    let metrics_handle = metrics::setup_recorder()?;
 
-    let args = Arc::new(cli::Args::parse());
-    args.assert();
+    if args.command == Some(cli::Command::CheckConfig) {
+        let checks = check::check_config(&args);
+        for (name, result) in &checks {
+            match result {
+                Ok(()) => println!("ok: {name}"),
+                Err(reason) => println!("error: {name}: {reason}"),
+            }
+        }
+        std::process::exit(i32::from(checks.iter().any(|(_, result)| result.is_err())));
+    }
+    if let Err(e) = args.assert() {
+        eprintln!("error: invalid configuration: {e}");
+        std::process::exit(2);
+    }
     info!("Running with the following options: {:?}", &args);
+    if args.insecure_skip_host_verify {
+        warn!("ssh host keys are not verified, so clones of ssh repositories may be intercepted");
+    }
+
+    let (keyrings, watchers) = load_keyrings(&args, args.fail_closed_on_keyring_error)?;
+    check_keyrings(&keyrings, &args)?;
+    let github_app = load_github_app(&args)?.map(Arc::new);
+
+    if let Some(metrics_bind) = &args.metrics_bind {
+        metrics::spawn_server(metrics_bind, metrics_handle.clone());
+    }
+
+    // Webhooks processed in the background are drained once the servers have shut down
+    let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+    let app = app(
+        args.clone(),
+        keyrings,
+        github_app,
+        job_queue.clone(),
+        metrics_handle,
+    );
+    let grace = Duration::from_secs(args.shutdown_grace);
+    // Servers and queued webhooks share one grace period, counted from the shutdown signal
+    let shutdown_at = Cell::new(None);
+    let shutdown = async {
+        shutdown_signal().await;
+        shutdown_at.set(Some(Instant::now()));
+    };
+
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        let config = RustlsConfig::from_pem_file(cert, key)
+            .await
+            .map_err(|e| format!("unable to load tls certificate {cert:?} and key {key:?}: {e}"))?;
+
+        let servers = args.bind_address.iter().map(|addr| {
+            info!("Listening on https://{}", addr);
+            let server = axum_server::bind_rustls(*addr, config.clone());
+            let app = app.clone();
+            move |shutdown| serve_tls(server, app, until_shutdown(shutdown), grace)
+        });
+        serve_all(servers, shutdown).await?;
+    } else {
+        let servers = args
+            .bind_address
+            .iter()
+            .map(|addr| {
+                info!("Listening on http://{}", addr);
+                let server = axum::Server::try_bind(addr)?;
+                let app = app.clone();
+                Ok(move |shutdown| serve(server, app, until_shutdown(shutdown), grace))
+            })
+            .collect::<hyper::Result<Vec<_>>>()?;
+        serve_all(servers, shutdown).await?;
+    }
+
+    let deadline = shutdown_at.get().unwrap_or_else(Instant::now) + grace;
+    job_queue.shutdown(deadline).await;
+    drop(watchers);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
 
-    let mut keyrings = KeyringFiles::default();
-    if let Some(keyring) = args.commit_keyring() {
-        keyrings
-            .commit
-            .replace(cert_builder::KeyringFile::from_path(
-                keyring.clone().as_str(),
-            )?);
-    }
-    if let Some(keyring) = args.tag_keyring() {
-        keyrings.tag.replace(cert_builder::KeyringFile::from_path(
-            keyring.clone().as_str(),
-        )?);
-    }
-
-    let app = Router::new()
-        .route("/", post(webhook::webhook))
-        .layer(ServiceBuilder::new().map_request_body(body::boxed).layer(
-            axum::middleware::from_fn(signature::HubSignature256::verify_middleware),
-        ))
-        .layer(Extension(args.clone()))
-        .layer(Extension(Arc::new(keyrings)))
-        .layer(TraceLayer::new_for_http());
-    let addr = &args.bind_address;
-
-    info!("Listening on http://{}", addr);
-
-    axum::Server::bind(addr)
-        .serve(app.into_make_service())
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::{header, Request, StatusCode};
+    use clap::Parser;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tower::ServiceExt;
+
+    const MAX_BODY_BYTES: usize = 64;
+
+    async fn post_body(body: String) -> StatusCode {
+        let args = cli::Args::parse_from([
+            "webhook-runner",
+            "--max-body-bytes",
+            &MAX_BODY_BYTES.to_string(),
+        ]);
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let request = Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+        app(
+            Arc::new(args),
+            KeyringFiles::default(),
+            None,
+            job_queue,
+            handle,
+        )
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+    }
+
+    async fn get_health(keyrings: KeyringFiles) -> (StatusCode, serde_json::Value) {
+        let args = cli::Args::parse_from(["webhook-runner"]);
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let request = Request::get("/healthz").body(Body::empty()).unwrap();
+        let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+        let response = app(Arc::new(args), keyrings, None, job_queue, handle)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn can_report_health_without_signature() {
+        let (status, body) = get_health(KeyringFiles::default()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({"status": "ok", "version": env!("CARGO_PKG_VERSION")})
+        );
+    }
+
+    #[tokio::test]
+    async fn will_report_unavailable_on_keyring_that_failed_to_load() {
+        let keyrings = KeyringFiles {
+            tag: Some(Err("no such file or directory".to_string())),
+            ..KeyringFiles::default()
+        };
+        let (status, body) = get_health(keyrings).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn can_finish_job_before_shutdown() {
+        let started = Arc::new(tokio::sync::Notify::new());
+        let finished = Arc::new(AtomicBool::new(false));
+        let job = {
+            let (started, finished) = (started.clone(), finished.clone());
+            move || async move {
+                started.notify_one();
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                finished.store(true, Ordering::SeqCst);
+            }
+        };
+        let app = Router::new().route("/", get(job));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            axum::Server::from_tcp(listener).unwrap(),
+            app,
+            async move { shutdown_rx.await.unwrap() },
+            Duration::from_secs(10),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        started.notified().await;
+        shutdown_tx.send(()).unwrap();
+
+        server.await.unwrap().unwrap();
+        assert!(
+            finished.load(Ordering::SeqCst),
+            "server exited before job finished"
+        );
+    }
+
+    #[tokio::test]
+    async fn can_serve_on_multiple_addresses() {
+        let listeners = [
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+        ];
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        let servers = listeners.map(|listener| {
+            let app = app.clone();
+            move |shutdown| {
+                serve(
+                    axum::Server::from_tcp(listener).unwrap(),
+                    app,
+                    until_shutdown(shutdown),
+                    Duration::from_secs(10),
+                )
+            }
+        });
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_all(servers, async move {
+            shutdown_rx.await.unwrap();
+        }));
+
+        for addr in addrs {
+            let response = reqwest::get(format!("http://{addr}/"))
+                .await
+                .expect("unable to make request");
+            assert_eq!(response.text().await.unwrap(), "hello");
+        }
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn can_serve_tls() {
+        let config = RustlsConfig::from_pem(
+            include_bytes!("testdata/tls_cert.pem").to_vec(),
+            include_bytes!("testdata/tls_key.pem").to_vec(),
+        )
         .await
         .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_tls(
+            axum_server::from_tcp_rustls(listener, config),
+            Router::new().route("/", get(|| async { "hello" })),
+            async move { shutdown_rx.await.unwrap() },
+            Duration::from_secs(10),
+        ));
 
-    Ok(())
+        let cert = reqwest::Certificate::from_pem(include_bytes!("testdata/tls_cert.pem")).unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(cert)
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://localhost:{port}/"))
+            .send()
+            .await
+            .expect("unable to make verified request");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn can_serve_metrics_on_separate_address() {
+        let get_metrics = |args: &[&str]| {
+            let args = cli::Args::parse_from(args);
+            let handle = PrometheusBuilder::new().build_recorder().handle();
+            let request = Request::get("/metrics").body(Body::empty()).unwrap();
+            let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+            app(
+                Arc::new(args),
+                KeyringFiles::default(),
+                None,
+                job_queue,
+                handle,
+            )
+            .oneshot(request)
+        };
+        let response = get_metrics(&["webhook-runner"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get_metrics(&["webhook-runner", "--metrics-bind", "127.0.0.1:9090"])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn post_from(args: &[&str], peer: &str, forwarded: Option<&str>) -> StatusCode {
+        let args = cli::Args::parse_from(args);
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let mut request = Request::post("/");
+        if let Some(forwarded) = forwarded {
+            request = request.header("x-forwarded-for", forwarded);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        let peer: SocketAddr = peer.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+        app(
+            Arc::new(args),
+            KeyringFiles::default(),
+            None,
+            job_queue,
+            handle,
+        )
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn can_reject_webhooks_from_unexpected_sources() {
+        let args = [
+            "webhook-runner",
+            "--allow-source-cidr",
+            "192.30.252.0/22,2a0a:a440::/29",
+        ];
+        // Allowed requests continue on to the handler, which rejects the empty body
+        let status = post_from(&args, "192.30.252.10:4000", None).await;
+        assert_ne!(status, StatusCode::FORBIDDEN);
+        let status = post_from(&args, "[2a0a:a440::10]:4000", None).await;
+        assert_ne!(status, StatusCode::FORBIDDEN);
+
+        let status = post_from(&args, "203.0.113.7:4000", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // Forwarded headers are ignored unless they are trusted
+        let status = post_from(&args, "203.0.113.7:4000", Some("192.30.252.10")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn can_check_forwarded_source() {
+        let args = [
+            "webhook-runner",
+            "--allow-source-cidr",
+            "192.30.252.0/22",
+            "--trust-forwarded",
+        ];
+        let status = post_from(&args, "127.0.0.1:4000", Some("192.30.252.10")).await;
+        assert_ne!(status, StatusCode::FORBIDDEN);
+        let status = post_from(&args, "192.30.252.10:4000", Some("203.0.113.7")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn will_reject_oversized_body() {
+        // The body is valid JSON, but not a valid payload; if it reached the handler, it would be
+        // rejected with a different status
+        let body = format!("{{\"zen\": \"{}\"}}", "a".repeat(MAX_BODY_BYTES));
+        assert_eq!(post_body(body).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn can_accept_body_at_limit() {
+        let body = format!("{{\"zen\": \"{}\"}}", "a".repeat(MAX_BODY_BYTES - 11));
+        assert_eq!(body.len(), MAX_BODY_BYTES);
+        assert_ne!(post_body(body).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    /// Post a body without sending its length ahead, signed with a mismatched signature if a secret
+    /// key is given.
+    async fn post_chunked_body(args: cli::Args, body: Body) -> StatusCode {
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let request = Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-hub-signature-256", format!("sha256={}", "0".repeat(64)))
+            .body(body)
+            .unwrap();
+        let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+        app(
+            Arc::new(args),
+            KeyringFiles::default(),
+            None,
+            job_queue,
+            handle,
+        )
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn will_reject_oversized_chunked_body() {
+        let max_body_bytes = MAX_BODY_BYTES.to_string();
+        let unsigned = ["webhook-runner", "--max-body-bytes", &max_body_bytes];
+        let signed = [&unsigned[..], &["--webhook-secret-key", "secret"]].concat();
+        // The body is either read by the signature middleware or by the handler
+        for argv in [&unsigned[..], &signed[..]] {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                let chunk = format!("{{\"zen\": \"{}\"}}", "a".repeat(MAX_BODY_BYTES));
+                sender.send_data(chunk.into()).await
+            });
+            let args = cli::Args::parse_from(argv);
+            assert_eq!(
+                post_chunked_body(args, body).await,
+                StatusCode::PAYLOAD_TOO_LARGE
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn will_time_out_slow_request() {
+        let args = cli::Args::parse_from([
+            "webhook-runner",
+            "--webhook-secret-key",
+            "secret",
+            "--request-timeout",
+            "1",
+        ]);
+        // The body never finishes, so verifying the signature waits until the request times out
+        let (_sender, body) = Body::channel();
+        assert_eq!(
+            post_chunked_body(args, body).await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+
+    #[tokio::test]
+    async fn can_trigger_push_manually() {
+        let repository = test_util::TestRepository::new(None);
+        let oid = repository.commit;
+        let clone_url = repository.clone_url();
+
+        let argv = [
+            "webhook-runner",
+            "--git-repository",
+            &clone_url,
+            "--commit-command",
+            "echo \"{\\\"branch\\\": \\\"$WEBHOOK_BRANCH\\\"}\"",
+            "--command-env",
+            "branch",
+            "--command-output-format",
+            "json-last-line",
+        ];
+        let trigger = |args: &[&str], body: String, signed: bool| {
+            let args = cli::Args::parse_from(argv.iter().chain(args));
+            let mut request =
+                Request::post("/trigger").header(header::CONTENT_TYPE, "application/json");
+            if signed {
+                let signature = test_util::hub_signature("testingkey", &body);
+                request = request.header("x-hub-signature-256", signature);
+            }
+            let handle = PrometheusBuilder::new().build_recorder().handle();
+            let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+            let router = app(
+                Arc::new(args),
+                KeyringFiles::default(),
+                None,
+                job_queue,
+                handle,
+            );
+            async move {
+                let response = router
+                    .oneshot(request.body(Body::from(body)).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default(),
+                )
+            }
+        };
+        let body = |sha: &str| format!(r#"{{"ref": "main", "sha": "{sha}", "target": "commit"}}"#);
+        let key = ["--webhook-secret-key", "testingkey"];
+
+        let (status, result) = trigger(&key, body(&oid.to_string()), true).await;
+        assert_eq!(status, StatusCode::OK);
+        // Unlike webhooks, triggers always respond with the output of the command
+        assert_eq!(
+            result,
+            serde_json::json!({
+                "status": "life",
+                "kind": "completed",
+                "result": {"branch": "main"},
+                "output": {"stdout": "{\"branch\": \"main\"}\n", "stderr": "", "truncated": false},
+            })
+        );
+
+        // The commit must exist in the repository, the same as for a pushed commit
+        let (status, result) = trigger(&key, body(&"0".repeat(40)), true).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(result["kind"], "failed_clone", "{result}");
+
+        // Triggers are verified the same way as webhooks, and never served unverified
+        let (status, _) = trigger(&key, body(&oid.to_string()), false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = trigger(&[], body(&oid.to_string()), false).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    // {{{ end to end
+
+    #[tokio::test]
+    async fn can_run_command_for_signed_push() {
+        let cert = test_util::generate_cert();
+        let test = test_util::EndToEnd::new(Some(&cert), &cert);
+
+        let body = test.repository.push_body("refs/heads/main");
+        let signature = test_util::hub_signature("testingkey", &body);
+        let (status, result) =
+            test_util::post_webhook(&test.argv(&[]), body, Some(signature)).await;
+        assert_eq!(status, StatusCode::OK, "{result}");
+        assert_eq!(
+            result,
+            serde_json::json!({"status": "life", "kind": "completed", "result": {"branch": "main"}})
+        );
+        assert!(test.marker.exists(), "command did not run");
+    }
+
+    #[tokio::test]
+    async fn will_reject_push_with_bad_signature() {
+        let cert = test_util::generate_cert();
+        let test = test_util::EndToEnd::new(Some(&cert), &cert);
+        let args = test.argv(&[]);
+
+        let body = test.repository.push_body("refs/heads/main");
+        let signature = test_util::hub_signature("otherkey", &body);
+        let (status, _) = test_util::post_webhook(&args, body.clone(), Some(signature)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = test_util::post_webhook(&args, body, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!test.marker.exists(), "command ran for unverified webhook");
+    }
+
+    #[tokio::test]
+    async fn can_nest_router_under_prefix() {
+        let cert = test_util::generate_cert();
+        let test = test_util::EndToEnd::new(Some(&cert), &cert);
+        let args = cli::Args::parse_with_config(test.argv(&[])).unwrap();
+        let (keyrings, _) = load_keyrings(&args, true).unwrap();
+        let job_queue = Arc::new(jobs::JobQueue::from_args(&args));
+        let router = Router::new().nest(
+            "/hooks",
+            build_router(Arc::new(args), Arc::new(keyrings), None, job_queue),
+        );
+
+        let body = test.repository.push_body("refs/heads/main");
+        let signature = test_util::hub_signature("testingkey", &body);
+        let request = |path: &str| {
+            Request::post(path)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-github-event", "push")
+                .header("x-hub-signature-256", &signature)
+                .body(Body::from(body.clone()))
+                .unwrap()
+        };
+        let response = router.clone().oneshot(request("/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!test.marker.exists(), "command ran outside of prefix");
+        let response = router.oneshot(request("/hooks")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(test.marker.exists(), "command did not run");
+    }
+
+    #[tokio::test]
+    async fn will_reject_push_of_commit_signed_by_untrusted_key() {
+        let trusted = test_util::generate_cert();
+        let untrusted = test_util::generate_cert();
+        let test = test_util::EndToEnd::new(Some(&untrusted), &trusted);
+
+        let body = test.repository.push_body("refs/heads/main");
+        let signature = test_util::hub_signature("testingkey", &body);
+        let (status, result) =
+            test_util::post_webhook(&test.argv(&[]), body, Some(signature)).await;
+        assert!(!status.is_success(), "unverified commit was accepted");
+        assert_eq!(result["kind"], "keyring_verification", "{result}");
+        assert!(!test.marker.exists(), "command ran for unverified commit");
+    }
+
+    #[tokio::test]
+    async fn can_verify_every_commit_of_push() {
+        let cert = test_util::generate_cert();
+        let mut test = test_util::EndToEnd::new(Some(&cert), &cert);
+        let before = test.repository.commit;
+        let unsigned = test.repository.commit(None);
+        let signed = test.repository.commit(Some(&cert));
+        let body = || {
+            let mut push: serde_json::Value =
+                serde_json::from_str(&test.repository.push_body("refs/heads/main")).unwrap();
+            push["before"] = before.to_string().into();
+            let head_commit = push["head_commit"].clone();
+            let mut commits = vec![head_commit.clone(), head_commit];
+            commits[0]["id"] = unsigned.to_string().into();
+            commits[1]["id"] = signed.to_string().into();
+            push["commits"] = commits.into();
+            push.to_string()
+        };
+
+        for (extra_args, verified) in [
+            (&[][..], true),
+            (&["--verify-all-commits"][..], false),
+            (&["--max-commits", "1"][..], false),
+        ] {
+            let body = body();
+            let signature = test_util::hub_signature("testingkey", &body);
+            let (status, result) =
+                test_util::post_webhook(&test.argv(extra_args), body, Some(signature)).await;
+            assert_eq!(status.is_success(), verified, "{extra_args:?}: {result}");
+            assert_eq!(test.marker.exists(), verified, "{extra_args:?}");
+            let _ = std::fs::remove_file(&test.marker);
+        }
+    }
+
+    /// Verify pushed tags with the keyring and run the command for them, rather than for commits.
+    fn verify_tags(test: &mut test_util::EndToEnd) {
+        for arg in &mut test.args {
+            if let Some(option) = arg.strip_prefix("--commit-") {
+                *arg = format!("--tag-{option}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn can_verify_signature_of_pushed_tag() {
+        let cert = test_util::generate_cert();
+        // Only the tag is signed, not the commit it points to
+        let mut test = test_util::EndToEnd::new(None, &cert);
+        verify_tags(&mut test);
+        test.repository.tag("v1.0.0", Some(&cert));
+        test.repository.tag("v1.0.1", None);
+        let push = |git_ref: &str, tag_verification: &'static str| {
+            let args = test.argv(&["--tag-verification", tag_verification]);
+            let body = test.repository.push_body(git_ref);
+            let signature = test_util::hub_signature("testingkey", &body);
+            async move { test_util::post_webhook(&args, body, Some(signature)).await }
+        };
+
+        let (status, result) = push("refs/tags/v1.0.0", "commit").await;
+        assert_eq!(result["kind"], "keyring_verification", "{result}");
+        assert!(!status.is_success(), "unsigned commit of tag was accepted");
+
+        let (status, result) = push("refs/tags/v1.0.0", "tag").await;
+        assert_eq!(status, StatusCode::OK, "{result}");
+        assert!(test.marker.exists(), "command did not run for signed tag");
+        std::fs::remove_file(&test.marker).unwrap();
+
+        // Lightweight tags fall back to their unsigned commit
+        let (status, result) = push("refs/tags/v1.0.1", "tag").await;
+        assert_eq!(result["kind"], "keyring_verification", "{result}");
+        assert!(
+            !status.is_success(),
+            "unsigned commit of lightweight tag was accepted"
+        );
+        assert!(!test.marker.exists(), "command ran for unverified tag");
+    }
+
+    #[tokio::test]
+    async fn can_require_annotated_tag() {
+        let cert = test_util::generate_cert();
+        let mut test = test_util::EndToEnd::new(Some(&cert), &cert);
+        verify_tags(&mut test);
+        test.repository.tag("v1.0.0", None);
+        for (tag_verification, verified) in [("tag", true), ("annotated", false)] {
+            let args = test.argv(&["--tag-verification", tag_verification]);
+            let body = test.repository.push_body("refs/tags/v1.0.0");
+            let signature = test_util::hub_signature("testingkey", &body);
+            let (status, result) = test_util::post_webhook(&args, body, Some(signature)).await;
+            assert_eq!(
+                status.is_success(),
+                verified,
+                "{tag_verification}: {result}"
+            );
+            assert_eq!(test.marker.exists(), verified, "{tag_verification}");
+            let _ = std::fs::remove_file(&test.marker);
+        }
+    }
+
+    #[tokio::test]
+    async fn can_log_delivery_id_of_each_step() {
+        let output = test_util::LogOutput::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(log_layer(cli::LogFormat::Json, move || writer.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let cert = test_util::generate_cert();
+        let test = test_util::EndToEnd::new(Some(&cert), &cert);
+        let body = test.repository.push_body("refs/heads/main");
+        let signature = test_util::hub_signature("testingkey", &body);
+        let (status, result) =
+            test_util::post_webhook(&test.argv(&[]), body, Some(signature)).await;
+        assert_eq!(status, StatusCode::OK, "{result}");
+
+        let lines = output.json_lines();
+        for message in [
+            "determined clone timeout",
+            "commit verified",
+            "processed webhook",
+        ] {
+            let line = lines
+                .iter()
+                .find(|line| line["fields"]["message"] == message)
+                .unwrap_or_else(|| panic!("no log line for {message}"));
+            let spans = line["spans"].as_array().expect("log line has no spans");
+            assert!(
+                spans.iter().any(|span| span["delivery_id"] == "72d3162e"),
+                "log line for {message} has no delivery id: {line}"
+            );
+        }
+    }
+
+    // }}}
+
+    #[test]
+    fn can_load_commit_and_tag_keyrings_separately() {
+        use sequoia_openpgp::cert::CertBuilder;
+        use sequoia_openpgp::serialize::Serialize;
+
+        let dir = tempdir::TempDir::new("webhook-runner-test").unwrap();
+        // The certificates expire at different times, to tell the keyrings apart
+        let now = std::time::SystemTime::now();
+        let write_keyring = |name: &str, days: u64| {
+            let validity = Duration::from_secs(days * 24 * 60 * 60);
+            let (cert, _) = CertBuilder::new()
+                .add_userid(format!("{name} <{name}@example.com>"))
+                .add_signing_subkey()
+                .set_creation_time(now)
+                .set_validity_period(validity)
+                .generate()
+                .unwrap();
+            let path = dir.path().join(format!("{name}.pgp"));
+            cert.serialize(&mut std::fs::File::create(&path).unwrap())
+                .unwrap();
+            (path.to_str().unwrap().to_string(), now + validity)
+        };
+        let (commit_keyring, commit_expiration) = write_keyring("commit", 10);
+        let (tag_keyring, tag_expiration) = write_keyring("tag", 20);
+        let args = cli::Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "true",
+            "--tag-command",
+            "true",
+            "--commit-keyring",
+            &commit_keyring,
+            "--tag-keyring",
+            &tag_keyring,
+        ]);
+
+        let (keyrings, _watchers) = load_keyrings(&args, true).unwrap();
+        // Creation times are stored with a precision of seconds
+        let secs = |time: std::time::SystemTime| {
+            time.duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        let expiration = |keyring: &Option<webhook_runner_lib::ConfiguredKeyring>| {
+            let keyring = keyring.as_ref().unwrap().as_ref().unwrap();
+            let expiration = keyring.read().unwrap().nearest_expiration().unwrap();
+            secs(expiration)
+        };
+        assert_eq!(expiration(&keyrings.commit), secs(commit_expiration));
+        assert_eq!(expiration(&keyrings.tag), secs(tag_expiration));
+    }
+
+    #[test]
+    fn will_error_on_unusable_keyring_with_strict_keyring() {
+        let dir = tempdir::TempDir::new("webhook-runner-test").unwrap();
+        let empty = dir.path().join("empty.pgp");
+        std::fs::File::create(&empty).unwrap();
+        let keyring = test_util::write_keyring(dir.path(), &test_util::generate_cert());
+        let args = |keyring: &std::path::Path, strict: &[&str]| {
+            let argv = [
+                "webhook-runner",
+                "--commit-keyring",
+                keyring.to_str().unwrap(),
+            ];
+            cli::Args::parse_from(argv.iter().chain(strict))
+        };
+
+        // Without --strict-keyring, the runner only warns about an unusable keyring
+        let (keyrings, _) = load_keyrings(&args(&empty, &[]), true).unwrap();
+        check_keyrings(&keyrings, &args(&empty, &[])).unwrap();
+        let strict = args(&empty, &["--strict-keyring"]);
+        let (keyrings, _) = load_keyrings(&strict, true).unwrap();
+        let e = check_keyrings(&keyrings, &strict).unwrap_err();
+        assert!(e.starts_with("commit keyring is unusable"), "{e}");
+
+        let strict = args(&keyring, &["--strict-keyring"]);
+        let (keyrings, _) = load_keyrings(&strict, true).unwrap();
+        check_keyrings(&keyrings, &strict).unwrap();
+    }
+
+    #[test]
+    fn can_log_structured_fields_as_json() {
+        let output = test_util::LogOutput::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(log_layer(cli::LogFormat::Json, move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            info!(
+                repository = "octocat/hello-world",
+                attempt = 2,
+                "cloning repository"
+            );
+        });
+
+        let line = output.json_line();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "cloning repository");
+        assert_eq!(line["fields"]["repository"], "octocat/hello-world");
+        assert_eq!(line["fields"]["attempt"], 2);
+    }
 }
@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+
+use axum::{routing::get, Extension, Router};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use tracing::{info, warn};
+
+/// Install a global metrics recorder, returning a handle that can render the recorded metrics.
+pub(crate) fn setup_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new().install_recorder()
+}
+
+/// Render all recorded metrics in the Prometheus text exposition format.
+pub(crate) async fn metrics(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
+
+/// Serve metrics on a separate address in the background. If the address can't be bound, a
+/// warning is logged and `false` is returned rather than an error, so that broken metrics never
+/// stop webhooks from being processed.
+pub(crate) fn spawn_server(addr: &SocketAddr, handle: PrometheusHandle) -> bool {
+    let server = match axum::Server::try_bind(addr) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!(%e, %addr, "unable to bind metrics address, continuing without metrics");
+            return false;
+        }
+    };
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .layer(Extension(handle));
+    info!("Serving metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(app.into_make_service()).await {
+            warn!(%e, "metrics server stopped");
+        }
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_continue_when_metrics_address_is_taken() {
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(!spawn_server(&addr, handle.clone()));
+
+        drop(listener);
+        assert!(spawn_server(&addr, handle));
+    }
+}
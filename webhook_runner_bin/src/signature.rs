@@ -4,20 +4,27 @@ use axum::{
     middleware::Next,
     response::Response,
 };
-use headers::{Header, HeaderName, HeaderValue};
-use hmac::Mac;
-use sha2::Sha256;
+use headers::{HeaderName, HeaderValue};
+use http_body::{LengthLimitError, Limited};
 use std::sync::Arc;
 use tracing::{debug, error, instrument};
 
-use crate::cli::Args;
+use webhook_runner_lib::{verify_signature, HubSignature, ProcessingError, SignatureAlgorithm};
+
+use crate::cli::{Args, WebhookProvider};
 use crate::error::HubSignatureValidationError;
 
 type Result<T> = std::result::Result<T, HubSignatureValidationError>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub(crate) struct Key(Vec<u8>);
 
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
 /// A key to be used for hmac-sha256 signature verification.
 impl Key {
     /// Convert the given input to bytes to use as a key.
@@ -57,242 +64,434 @@ impl clap::builder::TypedValueParser for KeyValueParser {
     }
 }
 
-/// A header from GitHub containing the signed body's content.
-#[derive(Clone, Debug)]
-pub(crate) struct HubSignature256(Vec<u8>);
+/// Reject requests with a body larger than the configured maximum size, before anything tries to
+/// read the body. Bodies sent without a length are limited while they are read instead.
+fn check_body_size(req: &Request<BoxBody>, args: &Args) -> std::result::Result<(), StatusCode> {
+    let content_length = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > args.max_body_bytes) {
+        error!(?content_length, "body is larger than the maximum size");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    Ok(())
+}
 
 static HUB_SIGNATURE_256: HeaderName = HeaderName::from_static("x-hub-signature-256");
 
-impl HubSignature256 {
-    /// Verify that a hmac-sha256 signature from the given key will produce the same result as the
-    /// stored mac.
-    pub(crate) fn verify(&self, key: &Key, content: &Bytes) -> Result<()> {
-        let tested_hmac = {
-            let mut mac = hmac::Hmac::<Sha256>::new_from_slice(key.into())?;
-            mac.update(content);
-            mac
-        };
+/// The same signature as [`HUB_SIGNATURE_256`], sent by Gitea and Forgejo without a prefix.
+static GITEA_SIGNATURE: HeaderName = HeaderName::from_static("x-gitea-signature");
 
-        debug!(?tested_hmac, received_hmac = ?self.0, "ensuring equality");
-        tested_hmac.verify_slice(&self.0[..])?;
+/// A header from GitLab containing the secret token of the webhook. GitLab sends the token as is,
+/// rather than signing the body with it.
+#[derive(Clone, Debug)]
+pub(crate) struct GitLabToken(Vec<u8>);
+
+static GITLAB_TOKEN: HeaderName = HeaderName::from_static("x-gitlab-token");
+
+impl GitLabToken {
+    /// Verify that the token matches the given key. Every byte is compared, so that the time taken
+    /// does not reveal how much of the token matched.
+    pub(crate) fn verify(&self, key: &Key) -> Result<()> {
+        let key: &[u8] = key.into();
+        let difference = self
+            .0
+            .iter()
+            .zip(key)
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if self.0.len() != key.len() || difference != 0 {
+            return Err(HubSignatureValidationError::TokenMismatch);
+        }
         Ok(())
     }
+}
 
-    /// Provide a middleware for the Axum application to parse a GitHub X-Hub-Signature-256 value
-    /// and verify the signature from the body.
-    ///
-    /// Reference: <https://docs.github.com/en/developers/webhooks-and-events/webhooks/securing-your-webhooks>
-    #[instrument(skip_all)]
-    pub(crate) async fn verify_middleware(
-        mut req: Request<BoxBody>,
-        next: Next<BoxBody>,
-    ) -> std::result::Result<Response, StatusCode> {
-        let args = req
-            .extensions_mut()
-            .get::<Arc<Args>>()
-            .expect("uninitialized args")
-            .clone();
-        let secret_key = match &args.webhook_secret_key {
-            Some(k) => k,
-            None => return Ok(next.run(req).await),
-        };
+/// A way for a webhook provider to prove that a webhook was sent by someone with the secret key.
+/// Each provider sends its proof in a header of its own, and may or may not sign the body with it.
+pub(crate) trait SignatureVerifier: Send + Sync {
+    /// The header that the provider sends the signature or token in.
+    fn header(&self) -> &'static HeaderName;
 
-        let received_hmac = match req.headers().get(&HUB_SIGNATURE_256) {
-            Some(header) => HubSignature256::try_from(header).map_err(|e| {
-                error!("error when parsing header: {e}");
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?,
-            None => return Err(StatusCode::UNAUTHORIZED),
-        };
+    /// Whether the signature covers the body, which then has to be read before verifying it.
+    fn signs_body(&self) -> bool {
+        true
+    }
 
-        debug!("breaking body into parts");
-        let (parts, body) = req.into_parts();
-        let body_bytes = hyper::body::to_bytes(body).await.map_err(|e| {
-            error!("error when converting body to bytes: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    /// Verify the value of the header against the secret key and the body, rejecting signatures
+    /// made with an algorithm weaker than `minimum`. The body is empty if the signature does not
+    /// cover it.
+    fn verify(
+        &self,
+        value: &HeaderValue,
+        key: &Key,
+        body: &Bytes,
+        minimum: SignatureAlgorithm,
+    ) -> Result<()>;
+}
 
-        debug!("verifying hmac");
-        received_hmac.verify(secret_key, &body_bytes).map_err(|e| {
-            error!("error when authenticating hmac: {e}");
-            StatusCode::UNAUTHORIZED
-        })?;
+/// GitHub signs the body with hmac-sha256, sending the signature with a `sha256=` prefix. Other
+/// prefixes select the algorithm of the same name.
+///
+/// Reference: <https://docs.github.com/en/developers/webhooks-and-events/webhooks/securing-your-webhooks>
+pub(crate) struct GitHubVerifier;
 
-        debug!("rebuilding request from parts");
-        let req = Request::from_parts(parts, body::boxed(Full::from(body_bytes)));
+impl SignatureVerifier for GitHubVerifier {
+    fn header(&self) -> &'static HeaderName {
+        &HUB_SIGNATURE_256
+    }
 
-        // All guards have successfully matched, time to move on
-        Ok(next.run(req).await)
+    fn verify(
+        &self,
+        value: &HeaderValue,
+        key: &Key,
+        body: &Bytes,
+        minimum: SignatureAlgorithm,
+    ) -> Result<()> {
+        Ok(verify_signature(
+            key.into(),
+            body,
+            value.to_str()?,
+            minimum,
+        )?)
     }
 }
 
-impl TryFrom<&HeaderValue> for HubSignature256 {
-    type Error = HubSignatureValidationError;
+/// Gitea and Forgejo sign the body with hmac-sha256, sending the signature without a prefix.
+///
+/// Reference: <https://docs.gitea.com/usage/webhooks>
+pub(crate) struct GiteaVerifier;
+
+impl SignatureVerifier for GiteaVerifier {
+    fn header(&self) -> &'static HeaderName {
+        &GITEA_SIGNATURE
+    }
 
-    fn try_from(value: &HeaderValue) -> Result<HubSignature256> {
-        value.to_str()?.try_into()
+    fn verify(
+        &self,
+        value: &HeaderValue,
+        key: &Key,
+        body: &Bytes,
+        minimum: SignatureAlgorithm,
+    ) -> Result<()> {
+        let signature = HubSignature::from_hex(SignatureAlgorithm::Sha256, value.to_str()?)?;
+        signature.require(minimum)?;
+        Ok(signature.verify(key.into(), body)?)
     }
 }
 
-impl TryFrom<&str> for HubSignature256 {
-    type Error = HubSignatureValidationError;
+/// GitLab sends the secret key as is, without signing the body.
+///
+/// Reference: <https://docs.gitlab.com/ee/user/project/integrations/webhooks.html#validate-payloads-by-using-a-secret-token>
+pub(crate) struct GitLabVerifier;
 
-    fn try_from(value: &str) -> Result<HubSignature256> {
-        let len = value.len();
-        if len != (64 + 7) {
-            return Err(HubSignatureValidationError::Length {
-                length: len,
-                intended: (64 + 7),
-            });
-        }
-        if &value[0..7] != "sha256=" {
-            return Err(HubSignatureValidationError::Content {
-                header: value.to_string(),
-            });
-        }
-        let hex_decode = hex::decode(&value[7..]);
-        match hex_decode {
-            Ok(hex) => Ok(HubSignature256(hex)),
-            Err(e) => Err(HubSignatureValidationError::from(e)),
-        }
+impl SignatureVerifier for GitLabVerifier {
+    fn header(&self) -> &'static HeaderName {
+        &GITLAB_TOKEN
     }
-}
 
-impl Header for HubSignature256 {
-    fn name() -> &'static HeaderName {
-        &HUB_SIGNATURE_256
+    fn signs_body(&self) -> bool {
+        false
     }
 
-    fn decode<'i, I>(values: &mut I) -> std::result::Result<Self, headers::Error>
-    where
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        let value = values.next().ok_or_else(headers::Error::invalid)?;
-        if let Ok(value) = HubSignature256::try_from(value) {
-            return Ok(value);
-        }
-        Err(headers::Error::invalid())
+    fn verify(
+        &self,
+        value: &HeaderValue,
+        key: &Key,
+        _body: &Bytes,
+        _minimum: SignatureAlgorithm,
+    ) -> Result<()> {
+        GitLabToken(value.as_bytes().to_vec()).verify(key)
     }
+}
 
-    fn encode<E>(&self, values: &mut E)
-    where
-        E: Extend<HeaderValue>,
-    {
-        if let Ok(value) =
-            HeaderValue::from_str(format!("sha256={}", hex::encode(&self.0)).as_str())
-        {
-            values.extend(std::iter::once(value));
+impl WebhookProvider {
+    /// The verifier for the signatures that the provider sends.
+    pub(crate) fn verifier(&self) -> &'static dyn SignatureVerifier {
+        match self {
+            WebhookProvider::GitHub => &GitHubVerifier,
+            WebhookProvider::GitLab => &GitLabVerifier,
+            WebhookProvider::Gitea => &GiteaVerifier,
         }
     }
 }
 
+/// Provide a middleware for the Axum application to verify the signature of a webhook, using the
+/// verifier of the configured `--webhook-provider`. Webhooks are not verified if no secret key is
+/// configured.
+#[instrument(skip_all)]
+pub(crate) async fn verify_middleware(
+    req: Request<BoxBody>,
+    next: Next<BoxBody>,
+) -> std::result::Result<Response, StatusCode> {
+    let args = req
+        .extensions()
+        .get::<Arc<Args>>()
+        .expect("uninitialized args")
+        .clone();
+
+    let secret_key = match &args.webhook_secret_key {
+        Some(k) => k,
+        None => return Ok(next.run(req).await),
+    };
+
+    let verifier = args.webhook_provider.verifier();
+    let received = match req.headers().get(verifier.header()) {
+        Some(header) => header.clone(),
+        None => return Err(StatusCode::UNAUTHORIZED),
+    };
+
+    // The body is only read here if it is signed, otherwise the handler reads and limits it
+    let (req, body_bytes) = if verifier.signs_body() {
+        check_body_size(&req, &args)?;
+
+        debug!("breaking body into parts");
+        let (parts, body) = req.into_parts();
+        let max_body_bytes = usize::try_from(args.max_body_bytes).unwrap_or(usize::MAX);
+        let body_bytes = hyper::body::to_bytes(Limited::new(body, max_body_bytes))
+            .await
+            .map_err(|e| {
+                error!("error when converting body to bytes: {e}");
+                if e.is::<LengthLimitError>() {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+
+        debug!("rebuilding request from parts");
+        let req = Request::from_parts(parts, body::boxed(Full::from(body_bytes.clone())));
+        (req, body_bytes)
+    } else {
+        (req, Bytes::new())
+    };
+
+    debug!("verifying signature");
+    verifier
+        .verify(
+            &received,
+            secret_key,
+            &body_bytes,
+            args.min_signature_algorithm,
+        )
+        .map_err(|e| match e {
+            HubSignatureValidationError::Signature {
+                source:
+                    ProcessingError::HmacVerification { .. }
+                    | ProcessingError::WeakWebhookSignature { .. },
+            }
+            | HubSignatureValidationError::TokenMismatch => {
+                error!("error when authenticating signature: {e}");
+                StatusCode::UNAUTHORIZED
+            }
+            e => {
+                error!("error when parsing header: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // All guards have successfully matched, time to move on
+    Ok(next.run(req).await)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    // {{{ HubSignature256 decoding
+    use axum::body::Body;
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use clap::Parser;
+    use tower::ServiceExt;
 
-    #[test]
-    fn can_decode_signature_header_from_str() {
-        HubSignature256::try_from(
-            "sha256=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
-        )
-        .expect("signature was not correctly parsed");
+    // {{{ Body size limit
+    const MAX_BODY_BYTES: u64 = 64;
+
+    /// Post a body to a router that only verifies the signature, so that nothing but the
+    /// middleware limits the size of the body.
+    async fn post_signed(request: Request<Body>) -> StatusCode {
+        post_to_provider("github", request).await
     }
 
-    #[test]
-    fn will_error_on_invalid_length() {
-        assert!(
-            HubSignature256::try_from(
-                "sha256=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fa5ec56f9",
-            )
-            .is_err(),
-            "length should be too short"
-        );
-        assert!(
-            HubSignature256::try_from(
-                "sha256=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9ca",
-            )
-            .is_err(),
-            "length should be too long"
-        );
-        let err = HubSignature256::try_from("");
-        match err {
-            Err(HubSignatureValidationError::Length { .. }) => (),
-            e => {
-                assert!(e.is_err(), "length should be too short");
-                e.expect("incorrect error variant from HubSignature256::<&str>::try_from");
-            }
-        }
+    async fn post_to_provider(provider: &str, request: Request<Body>) -> StatusCode {
+        post_with_args(&["--webhook-provider", provider], request).await
     }
 
-    #[test]
-    fn will_error_on_malformed_header() {
-        let err = HubSignature256::try_from(
-            "sha255=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
+    async fn post_with_args(extra_args: &[&str], request: Request<Body>) -> StatusCode {
+        let max_body_bytes = MAX_BODY_BYTES.to_string();
+        let args = Args::parse_from(
+            [
+                "webhook-runner",
+                "--webhook-secret-key",
+                "testingkey",
+                "--max-body-bytes",
+                &max_body_bytes,
+            ]
+            .iter()
+            .chain(extra_args),
         );
-        match err {
-            Err(HubSignatureValidationError::Content { .. }) => (),
-            e => {
-                assert!(e.is_err(), "content should be invalid");
-                e.expect("incorrect error variant from HubSignature256::<&str>::try_from");
+        Router::new()
+            .route("/", post(|| async {}))
+            .layer(axum::middleware::from_fn(verify_middleware))
+            .layer(Extension(Arc::new(args)))
+            .oneshot(request.map(body::boxed))
+            .await
+            .unwrap()
+            .status()
+    }
+
+    /// Start a request signed for the body `hello`.
+    fn signed_request() -> http::request::Builder {
+        Request::post("/").header(
+            &HUB_SIGNATURE_256,
+            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+        )
+    }
+
+    #[tokio::test]
+    async fn will_reject_oversized_content_length() {
+        // The length alone is enough to reject the body, which is never sent
+        let (_sender, body) = Body::channel();
+        let request = signed_request()
+            .header(http::header::CONTENT_LENGTH, MAX_BODY_BYTES + 1)
+            .body(body)
+            .unwrap();
+        assert_eq!(post_signed(request).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn will_reject_oversized_chunked_body() {
+        // Each chunk is below the limit, but the body goes over it partway through
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                let chunk = vec![b'a'; MAX_BODY_BYTES as usize / 2];
+                if sender.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
             }
-        }
+        });
+        let request = signed_request().body(body).unwrap();
+        assert_eq!(post_signed(request).await, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // The signature of a body within the limit is verified as usual
+        let request = signed_request().body(Body::from("hello")).unwrap();
+        assert_eq!(post_signed(request).await, StatusCode::OK);
     }
+    // }}}
 
-    #[test]
-    fn will_error_on_invalid_hex() {
-        let err = HubSignature256::try_from(
-            "sha256=2gd61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
-        );
-        match err {
-            Err(HubSignatureValidationError::HexDecode { .. }) => (),
-            e => {
-                assert!(e.is_err(), "content should be invalid");
-                e.expect("incorrect error variant from HubSignature256::<&str>::try_from");
+    // {{{ SignatureVerifier selection
+    #[tokio::test]
+    async fn can_verify_signature_of_each_provider() {
+        let signature = "aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72";
+        let requests = [
+            ("github", &HUB_SIGNATURE_256, format!("sha256={signature}")),
+            ("gitea", &GITEA_SIGNATURE, signature.to_string()),
+            ("gitlab", &GITLAB_TOKEN, "testingkey".to_string()),
+        ];
+        for (provider, header, value) in &requests {
+            let request = Request::post("/")
+                .header(*header, value)
+                .body(Body::from("hello"))
+                .unwrap();
+            assert_eq!(
+                post_to_provider(provider, request).await,
+                StatusCode::OK,
+                "{provider} signature was not verified"
+            );
+
+            // Only the header of the configured provider is accepted
+            for (other, _, _) in &requests {
+                if other != provider {
+                    let request = Request::post("/")
+                        .header(*header, value)
+                        .body(Body::from("hello"))
+                        .unwrap();
+                    assert_eq!(
+                        post_to_provider(other, request).await,
+                        StatusCode::UNAUTHORIZED,
+                        "{other} accepted a {provider} signature"
+                    );
+                }
             }
         }
+
+        // The body is signed by GitHub and Gitea, but not by GitLab
+        let request = Request::post("/")
+            .header(&GITEA_SIGNATURE, signature)
+            .body(Body::from("heloo"))
+            .unwrap();
+        assert_eq!(
+            post_to_provider("gitea", request).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let request = Request::post("/")
+            .header(&GITLAB_TOKEN, "testingkey")
+            .body(Body::from("heloo"))
+            .unwrap();
+        assert_eq!(post_to_provider("gitlab", request).await, StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn will_reject_signature_weaker_than_minimum() {
+        let request = || {
+            Request::post("/")
+                .header(
+                    &HUB_SIGNATURE_256,
+                    "sha1=235a794f6d0e9786b17f2c88cbb3a550da3f7d7c",
+                )
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+        assert_eq!(
+            post_with_args(&[], request()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_with_args(&["--min-signature-algorithm", "sha1"], request()).await,
+            StatusCode::OK
+        );
+
+        // Gitea only signs with hmac-sha256
+        let request = Request::post("/")
+            .header(
+                &GITEA_SIGNATURE,
+                "aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+            )
+            .body(Body::from("hello"))
+            .unwrap();
+        let args = [
+            "--webhook-provider",
+            "gitea",
+            "--min-signature-algorithm",
+            "sha512",
+        ];
+        assert_eq!(
+            post_with_args(&args, request).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
     // }}}
 
-    // {{{ HubSignature256 verifying
+    // {{{ GitLabToken verifying
     #[test]
-    fn can_verify_valid_signature() {
-        let signature = HubSignature256::try_from(
-            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
-        )
-        .expect("unable to parse signature header");
+    fn can_verify_valid_token() {
         let key = Key::new("testingkey");
-        let test_body = axum::body::Bytes::from_static(b"hello");
-        signature
-            .verify(&key, &test_body)
-            .expect("invalid signature verification");
+        GitLabToken(b"testingkey".to_vec())
+            .verify(&key)
+            .expect("invalid token verification");
     }
 
     #[test]
-    fn will_error_on_incorrect_signature() {
-        let signature = HubSignature256::try_from(
-            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d73",
-        )
-        .expect("unable to parse signature header");
+    fn will_error_on_incorrect_token() {
         let key = Key::new("testingkey");
-        let test_body = axum::body::Bytes::from_static(b"hello");
-        assert!(
-            signature.verify(&key, &test_body).is_err(),
-            "didn't error on modified signature"
-        );
-
-        let signature = HubSignature256::try_from(
-            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
-        )
-        .expect("unable to parse signature header");
-        let key = Key::new("testingkey");
-        let test_body = axum::body::Bytes::from_static(b"heloo");
-        assert!(
-            signature.verify(&key, &test_body).is_err(),
-            "didn't error on modified body"
-        );
+        for token in [&b"testingkez"[..], b"testingke", b"testingkeyy", b""] {
+            match GitLabToken(token.to_vec()).verify(&key) {
+                Err(HubSignatureValidationError::TokenMismatch) => (),
+                e => panic!("incorrect result from GitLabToken::verify: {e:?}"),
+            }
+        }
     }
     // }}}
 }
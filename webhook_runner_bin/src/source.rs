@@ -0,0 +1,184 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::{
+    body::BoxBody,
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use headers::HeaderName;
+use tracing::{debug, error, instrument};
+
+use crate::cli::Args;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// A range of IPv4 or IPv6 addresses, written as an address and a prefix length, such as
+/// `140.82.112.0/20`. An address without a prefix length only contains that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SourceCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl SourceCidr {
+    /// Check whether the range contains the given address. IPv4 addresses mapped to IPv6, such as
+    /// peers of a server bound to `[::]`, are compared as IPv4 addresses.
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for SourceCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("invalid cidr: {s}: {e}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| {
+                    format!("invalid cidr: {s}, expected a prefix length up to {max_prefix}")
+                })?,
+            None => max_prefix,
+        };
+        Ok(SourceCidr { addr, prefix })
+    }
+}
+
+/// Find the address that sent the request: the peer of the connection or, when forwarded headers
+/// are trusted, the last address in `X-Forwarded-For`, which was added by the closest proxy.
+fn source_address<B>(req: &Request<B>, trust_forwarded: bool) -> Option<IpAddr> {
+    let forwarded = req
+        .headers()
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last();
+    match forwarded {
+        Some(forwarded) if trust_forwarded => forwarded.trim().parse().ok(),
+        _ => req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| peer.ip()),
+    }
+}
+
+/// Provide a middleware for the Axum application to reject requests from addresses outside of
+/// the configured source ranges, such as the `hooks` ranges from GitHub's meta API. Requests are
+/// accepted from any address if no ranges are configured.
+///
+/// Reference: <https://docs.github.com/en/rest/meta/meta#get-github-meta-information>
+#[instrument(skip_all)]
+pub(crate) async fn verify_middleware(
+    req: Request<BoxBody>,
+    next: Next<BoxBody>,
+) -> Result<Response, StatusCode> {
+    let args = req
+        .extensions()
+        .get::<Arc<Args>>()
+        .expect("uninitialized args")
+        .clone();
+
+    if args.allow_source_cidr.is_empty() {
+        return Ok(next.run(req).await);
+    }
+
+    let source = source_address(&req, args.trust_forwarded);
+    debug!(?source, "checking source address");
+    match source {
+        Some(source)
+            if args
+                .allow_source_cidr
+                .iter()
+                .any(|cidr| cidr.contains(source)) =>
+        {
+            Ok(next.run(req).await)
+        }
+        _ => {
+            error!(?source, "source address is not in any allowed range");
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Body;
+
+    #[test]
+    fn can_match_addresses_in_range() {
+        let v4: SourceCidr = "192.30.252.0/22".parse().unwrap();
+        assert!(v4.contains("192.30.252.1".parse().unwrap()));
+        assert!(v4.contains("192.30.255.255".parse().unwrap()));
+        assert!(!v4.contains("192.30.0.1".parse().unwrap()));
+        assert!(v4.contains("::ffff:192.30.253.10".parse().unwrap()));
+
+        let v6: SourceCidr = "2a0a:a440::/29".parse().unwrap();
+        assert!(v6.contains("2a0a:a440::1".parse().unwrap()));
+        assert!(!v6.contains("2a0a:a450::1".parse().unwrap()));
+        assert!(!v6.contains("192.30.252.1".parse().unwrap()));
+
+        let single: SourceCidr = "10.0.0.1".parse().unwrap();
+        assert!(single.contains("10.0.0.1".parse().unwrap()));
+        assert!(!single.contains("10.0.0.2".parse().unwrap()));
+
+        let any: SourceCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn will_error_on_invalid_cidr() {
+        assert!("192.30.252.0/33".parse::<SourceCidr>().is_err());
+        assert!("2a0a:a440::/129".parse::<SourceCidr>().is_err());
+        assert!("github.com/22".parse::<SourceCidr>().is_err());
+        assert!("192.30.252.0/".parse::<SourceCidr>().is_err());
+    }
+
+    #[test]
+    fn can_find_forwarded_source_address() {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let mut req = Request::post("/")
+            .header(&X_FORWARDED_FOR, "198.51.100.1, 192.30.252.1")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+
+        assert_eq!(source_address(&req, false), Some(peer.ip()));
+        assert_eq!(
+            source_address(&req, true),
+            Some("192.30.252.1".parse().unwrap())
+        );
+    }
+}
@@ -1,8 +1,80 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use serde::Serialize;
 use thiserror::Error;
 
+use webhook_runner_lib::command::CapturedOutput;
+
+/// The end of the output of a command, decoded lossily since commands are not guaranteed to write
+/// UTF-8, for debugging a command from the response.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OutputTail {
+    stdout: String,
+    stderr: String,
+    /// Whether the start of the output was left out
+    truncated: bool,
+}
+
+impl OutputTail {
+    /// Keep the last `max_bytes` of each stream of the captured output.
+    pub(crate) fn new(output: &CapturedOutput, max_bytes: usize) -> Self {
+        let tail = |bytes: &[u8]| {
+            String::from_utf8_lossy(&bytes[bytes.len().saturating_sub(max_bytes)..]).into_owned()
+        };
+        OutputTail {
+            stdout: tail(&output.stdout),
+            stderr: tail(&output.stderr),
+            truncated: output.truncated
+                || output.stdout.len() > max_bytes
+                || output.stderr.len() > max_bytes,
+        }
+    }
+}
+
+/// The response to a webhook or trigger, as a single JSON object. `status` is `life` if the
+/// webhook was processed without error and `death` if it was not, and `kind` names the result or
+/// the reason, alongside its fields, such as
+/// `{"status": "death", "kind": "failed_clone", "reason": "..."}`.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum Status {
+    Life(JobStatus),
+    Death(DeathReason),
+}
+
+impl From<&Result<JobStatus, DeathReason>> for Status {
+    fn from(result: &Result<JobStatus, DeathReason>) -> Self {
+        match result {
+            Ok(status) => Status::Life(status.clone()),
+            Err(reason) => Status::Death(reason.clone()),
+        }
+    }
+}
+
+/// The result of a webhook that was processed without error.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    /// The command was run and exited successfully, optionally reporting a structured result
+    Completed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<OutputTail>,
+    },
+
+    /// No command was run for the webhook
+    Skipped { reason: String },
+
+    /// The webhook is processed in the background, and its result is only reported to the event
+    /// sink, history file, and result callback URL under the ID of the delivery
+    Accepted { delivery: String },
+}
+
 /// The reasons a program may have died or not started to begin with.
 #[derive(Serialize, Error, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub(crate) enum DeathReason {
     /// The information we received from the webhook did not match something we expected
     #[error("Received invalid data in webhook at path: {field_path}, value?: {value:?}")]
@@ -15,12 +87,384 @@ pub(crate) enum DeathReason {
     #[error("Cloning the repository failed: {reason}")]
     FailedClone { reason: String },
 
+    /// The keyring required to verify a push could not be loaded
+    #[error("Keyring could not be loaded: {reason}")]
+    KeyringError { reason: String },
+
     /// The keyring was unable to successfully verify a commit based on an invalid or missing
     /// signature on the keyring
     #[error("Error verifying commit from keyring: {reason}")]
-    KeyringVerification { reason: String },
+    KeyringVerification {
+        reason: String,
+        /// Where the cloned repository was kept for debugging, if it was
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kept_clone: Option<String>,
+    },
+
+    /// A pushed tag did not satisfy the configured tag message policy
+    #[error("Tag did not satisfy message policy: {reason}")]
+    TagPolicy { reason: String },
+
+    /// The command for the push failed to run or exited unsuccessfully
+    #[error("Running command failed: {reason}")]
+    CommandFailed {
+        reason: String,
+        /// The exit code of the command, if it ran and exited unsuccessfully
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<OutputTail>,
+        /// Where the cloned repository was kept for debugging, if it was
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kept_clone: Option<String>,
+        /// The results of the commands for changed paths that completed before this one failed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Box<serde_json::Value>>,
+    },
+
+    /// The before command exited unsuccessfully, so the command was not run
+    #[error("Before command vetoed running the command: {reason}")]
+    PreCommandVeto {
+        reason: String,
+        exit_code: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<OutputTail>,
+    },
 
     /// There was some error when verifying something within the repository
     #[error("Repository error: {reason}")]
     RepositoryError { reason: String },
+
+    /// Repositories are configured individually, but not the repository the webhook is for
+    #[error("Repository is not configured: {repository}")]
+    UnconfiguredRepository { repository: String },
+
+    /// The push introduced more commits than are allowed to be run for
+    #[error("Push of {commits} commits exceeds the maximum of {max}")]
+    TooManyCommits { commits: usize, max: usize },
+
+    /// Too many webhooks were being processed to start processing this one in time
+    #[error("No job slot became available within {timeout} seconds")]
+    QueueTimeout { timeout: u64 },
+
+    /// Too many webhooks were already waiting to be processed in the background to accept this one
+    #[error("Queue of webhooks to process in the background is full at {capacity} webhooks")]
+    QueueFull { capacity: usize },
+
+    /// Processing the webhook in the background panicked, which is a bug of the runner
+    #[error("Processing of the webhook panicked: {reason}")]
+    Panicked { reason: String },
+}
+
+impl JobStatus {
+    /// Leave out the output of the command, for responses that only report the status.
+    pub(crate) fn without_output(self) -> Self {
+        match self {
+            JobStatus::Completed { result, .. } => JobStatus::Completed {
+                result,
+                output: None,
+            },
+            status => status,
+        }
+    }
+}
+
+impl DeathReason {
+    /// Leave out the output of the command, for responses that only report the reason.
+    pub(crate) fn without_output(self) -> Self {
+        match self {
+            DeathReason::CommandFailed {
+                reason,
+                exit_code,
+                kept_clone,
+                result,
+                ..
+            } => DeathReason::CommandFailed {
+                reason,
+                exit_code,
+                output: None,
+                kept_clone,
+                result,
+            },
+            DeathReason::PreCommandVeto {
+                reason, exit_code, ..
+            } => DeathReason::PreCommandVeto {
+                reason,
+                exit_code,
+                output: None,
+            },
+            reason => reason,
+        }
+    }
+
+    /// The HTTP status code to respond with, so that failed deliveries are noticed by monitoring.
+    /// Webhooks that are rejected for their content or by policy are unprocessable, failures to
+    /// reach the repository are reported as a bad gateway, and a full queue is reported as
+    /// unavailable so that the delivery can be retried.
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            DeathReason::InvalidWebhook { .. }
+            | DeathReason::KeyringVerification { .. }
+            | DeathReason::TagPolicy { .. }
+            | DeathReason::PreCommandVeto { .. }
+            | DeathReason::UnconfiguredRepository { .. }
+            | DeathReason::TooManyCommits { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            DeathReason::FailedClone { .. } | DeathReason::RepositoryError { .. } => {
+                StatusCode::BAD_GATEWAY
+            }
+            DeathReason::KeyringError { .. }
+            | DeathReason::CommandFailed { .. }
+            | DeathReason::Panicked { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            DeathReason::QueueTimeout { .. } | DeathReason::QueueFull { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+    }
+}
+
+impl IntoResponse for JobStatus {
+    fn into_response(self) -> Response {
+        let status = match self {
+            JobStatus::Accepted { .. } => StatusCode::ACCEPTED,
+            _ => StatusCode::OK,
+        };
+        (status, Json(Status::Life(self))).into_response()
+    }
+}
+
+impl IntoResponse for DeathReason {
+    fn into_response(self) -> Response {
+        (self.status_code(), Json(Status::Death(self))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_respond_with_status_code_of_reason() {
+        let reason = || "reason".to_string();
+        let reasons = [
+            (
+                DeathReason::InvalidWebhook {
+                    field_path: "_ref".to_string(),
+                    value: None,
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                DeathReason::FailedClone { reason: reason() },
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                DeathReason::KeyringError { reason: reason() },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                DeathReason::KeyringVerification {
+                    reason: reason(),
+                    kept_clone: None,
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                DeathReason::TagPolicy { reason: reason() },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                DeathReason::CommandFailed {
+                    reason: reason(),
+                    exit_code: Some(1),
+                    output: None,
+                    kept_clone: None,
+                    result: None,
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                DeathReason::PreCommandVeto {
+                    reason: reason(),
+                    exit_code: 1,
+                    output: None,
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                DeathReason::RepositoryError { reason: reason() },
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                DeathReason::UnconfiguredRepository {
+                    repository: "octocat/Hello-World".to_string(),
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                DeathReason::TooManyCommits {
+                    commits: 5000,
+                    max: 100,
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                DeathReason::QueueTimeout { timeout: 30 },
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                DeathReason::QueueFull { capacity: 100 },
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                DeathReason::Panicked { reason: reason() },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (reason, status) in reasons {
+            let expected = serde_json::to_value(Status::Death(reason.clone())).unwrap();
+            let response = reason.into_response();
+            assert_eq!(response.status(), status, "incorrect status for {expected}");
+
+            // The reason is still reported in the body
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[test]
+    fn can_serialize_status() {
+        let serialized = |status: Status| serde_json::to_value(status).unwrap();
+        assert_eq!(
+            serialized(Status::Life(JobStatus::Completed {
+                result: Some(serde_json::json!({"deployed": true})),
+                output: None,
+            })),
+            serde_json::json!({"status": "life", "kind": "completed", "result": {"deployed": true}})
+        );
+        assert_eq!(
+            serialized(Status::Life(JobStatus::Skipped {
+                reason: "no command configured".to_string(),
+            })),
+            serde_json::json!({"status": "life", "kind": "skipped", "reason": "no command configured"})
+        );
+        assert_eq!(
+            serialized(Status::Life(JobStatus::Accepted {
+                delivery: "72d3162e".to_string(),
+            })),
+            serde_json::json!({"status": "life", "kind": "accepted", "delivery": "72d3162e"})
+        );
+
+        let reason = || "reason".to_string();
+        let reasons = [
+            (
+                DeathReason::InvalidWebhook {
+                    field_path: "_ref".to_string(),
+                    value: None,
+                },
+                serde_json::json!({"kind": "invalid_webhook", "field_path": "_ref", "value": null}),
+            ),
+            (
+                DeathReason::FailedClone { reason: reason() },
+                serde_json::json!({"kind": "failed_clone", "reason": "reason"}),
+            ),
+            (
+                DeathReason::KeyringError { reason: reason() },
+                serde_json::json!({"kind": "keyring_error", "reason": "reason"}),
+            ),
+            (
+                DeathReason::KeyringVerification {
+                    reason: reason(),
+                    kept_clone: None,
+                },
+                serde_json::json!({"kind": "keyring_verification", "reason": "reason"}),
+            ),
+            (
+                DeathReason::TagPolicy { reason: reason() },
+                serde_json::json!({"kind": "tag_policy", "reason": "reason"}),
+            ),
+            (
+                DeathReason::CommandFailed {
+                    reason: reason(),
+                    exit_code: Some(1),
+                    output: None,
+                    kept_clone: None,
+                    result: None,
+                },
+                serde_json::json!({"kind": "command_failed", "reason": "reason", "exit_code": 1}),
+            ),
+            (
+                DeathReason::RepositoryError { reason: reason() },
+                serde_json::json!({"kind": "repository_error", "reason": "reason"}),
+            ),
+            (
+                DeathReason::UnconfiguredRepository {
+                    repository: "octocat/Hello-World".to_string(),
+                },
+                serde_json::json!({
+                    "kind": "unconfigured_repository",
+                    "repository": "octocat/Hello-World",
+                }),
+            ),
+            (
+                DeathReason::TooManyCommits {
+                    commits: 5000,
+                    max: 100,
+                },
+                serde_json::json!({"kind": "too_many_commits", "commits": 5000, "max": 100}),
+            ),
+            (
+                DeathReason::QueueTimeout { timeout: 30 },
+                serde_json::json!({"kind": "queue_timeout", "timeout": 30}),
+            ),
+            (
+                DeathReason::QueueFull { capacity: 100 },
+                serde_json::json!({"kind": "queue_full", "capacity": 100}),
+            ),
+            (
+                DeathReason::Panicked { reason: reason() },
+                serde_json::json!({"kind": "panicked", "reason": "reason"}),
+            ),
+        ];
+        for (reason, mut expected) in reasons {
+            expected["status"] = "death".into();
+            assert_eq!(serialized(Status::Death(reason)), expected);
+        }
+    }
+
+    #[test]
+    fn can_keep_end_of_output() {
+        let captured = CapturedOutput {
+            stdout: b"first\nsecond\n".to_vec(),
+            stderr: b"\xffwarning\n".to_vec(),
+            truncated: false,
+        };
+        assert_eq!(
+            OutputTail::new(&captured, 1024),
+            OutputTail {
+                stdout: "first\nsecond\n".to_string(),
+                stderr: "\u{fffd}warning\n".to_string(),
+                truncated: false,
+            }
+        );
+        assert_eq!(
+            OutputTail::new(&captured, 7),
+            OutputTail {
+                stdout: "second\n".to_string(),
+                stderr: "arning\n".to_string(),
+                truncated: true,
+            }
+        );
+
+        let status = JobStatus::Completed {
+            result: None,
+            output: Some(OutputTail::new(&captured, 7)),
+        };
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["output"]["stdout"], "second\n");
+        assert_eq!(
+            serde_json::to_value(status.without_output()).unwrap(),
+            serde_json::json!({"kind": "completed"})
+        );
+    }
 }
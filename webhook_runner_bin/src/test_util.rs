@@ -0,0 +1,323 @@
+//! Fixtures shared by the tests of several modules, including a harness that serves a local
+//! repository and posts signed webhooks to the application, to test a webhook from end to end.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::Extension;
+use git2::{Oid, Repository};
+use hmac::Mac;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use sequoia_openpgp as openpgp;
+use tempdir::TempDir;
+use tower::ServiceExt;
+
+use openpgp::armor::{Kind, Writer};
+use openpgp::cert::prelude::*;
+use openpgp::packet::signature::SignatureBuilder;
+use openpgp::policy::StandardPolicy;
+use openpgp::serialize::Serialize;
+use openpgp::types::SignatureType;
+use openpgp::{Cert, Packet};
+
+use crate::cli::Args;
+use crate::events::EventSink;
+use crate::jobs::JobQueue;
+use crate::status::{DeathReason, JobStatus};
+use crate::KeyringFiles;
+
+/// A writer for log lines that keeps everything written to it, so tests can inspect the lines.
+/// Clones write to the same buffer.
+#[derive(Clone, Default)]
+pub(crate) struct LogOutput(Arc<Mutex<Vec<u8>>>);
+
+impl LogOutput {
+    /// Parse the single JSON log line written so far.
+    pub(crate) fn json_line(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.0.lock().unwrap()).expect("log line is not json")
+    }
+
+    /// Parse every JSON log line written so far.
+    pub(crate) fn json_lines(&self) -> Vec<serde_json::Value> {
+        self.0
+            .lock()
+            .unwrap()
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).expect("log line is not json"))
+            .collect()
+    }
+}
+
+impl std::io::Write for LogOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// {{{ end to end harness
+
+/// A repository with a single commit on `refs/heads/main`, which webhooks clone with a `file://`
+/// URL the same way as a remote repository.
+pub(crate) struct TestRepository {
+    dir: TempDir,
+    pub(crate) commit: Oid,
+}
+
+impl TestRepository {
+    /// Create the repository, signing the commit with `signer` if given.
+    pub(crate) fn new(signer: Option<&Cert>) -> Self {
+        let dir = TempDir::new("webhook-runner-test").expect("unable to create repository dir");
+        Repository::init(dir.path()).expect("unable to init repository");
+        let mut repository = TestRepository {
+            dir,
+            commit: Oid::zero(),
+        };
+        repository.commit(signer);
+        repository
+    }
+
+    /// Add a commit to `refs/heads/main`, signed by `signer` if given, which becomes the commit
+    /// that is pushed.
+    pub(crate) fn commit(&mut self, signer: Option<&Cert>) -> Oid {
+        let repo = self.open();
+        let sig = git2::Signature::now("Test User", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parent = repo.find_commit(self.commit).ok();
+        let parents = parent.iter().collect::<Vec<_>>();
+        let commit = match signer {
+            Some(signer) => {
+                let buffer = repo
+                    .commit_create_buffer(&sig, &sig, "test\n", &tree, &parents)
+                    .expect("unable to create commit buffer");
+                repo.commit_signed(buffer.as_str().unwrap(), &sign(signer, &buffer), None)
+                    .expect("unable to create signed commit")
+            }
+            None => repo
+                .commit(None, &sig, &sig, "test\n", &tree, &parents)
+                .expect("unable to create commit"),
+        };
+        repo.reference("refs/heads/main", commit, true, "test")
+            .expect("unable to update branch");
+        self.commit = commit;
+        commit
+    }
+
+    /// Tag the commit as `name`, with an annotated tag signed by `signer` if given, or with a
+    /// lightweight tag otherwise.
+    pub(crate) fn tag(&self, name: &str, signer: Option<&Cert>) {
+        let repo = self.open();
+        let target = match signer {
+            Some(signer) => {
+                let seconds = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let mut tag = format!(
+                    "object {}\ntype commit\ntag {name}\n\
+                    tagger Test User <test@example.com> {seconds} +0000\n\nrelease\n",
+                    self.commit
+                )
+                .into_bytes();
+                let signature = sign(signer, &tag);
+                tag.extend_from_slice(signature.as_bytes());
+                repo.odb()
+                    .unwrap()
+                    .write(git2::ObjectType::Tag, &tag)
+                    .expect("unable to create tag")
+            }
+            None => self.commit,
+        };
+        repo.reference(&format!("refs/tags/{name}"), target, true, "test")
+            .expect("unable to update tag");
+    }
+
+    pub(crate) fn open(&self) -> Repository {
+        Repository::open(self.dir.path()).expect("unable to open repository")
+    }
+
+    pub(crate) fn clone_url(&self) -> String {
+        format!("file://{}", self.dir.path().display())
+    }
+
+    /// Build the body of a push of the commit to `git_ref`, based on a push recorded from GitHub.
+    pub(crate) fn push_body(&self, git_ref: &str) -> String {
+        let mut push: serde_json::Value =
+            serde_json::from_str(include_str!("testdata/github_tag_push.json")).unwrap();
+        let commit = self.commit.to_string();
+        push["ref"] = git_ref.into();
+        push["after"] = commit.clone().into();
+        push["head_commit"]["id"] = commit.into();
+        push["commits"] = serde_json::json!([push["head_commit"]]);
+        push["repository"]["clone_url"] = self.clone_url().into();
+        push.to_string()
+    }
+}
+
+/// Generate a certificate with a signing subkey. The certificate is created an hour ago, since
+/// commits are verified as of their committer timestamp, which is truncated to the second.
+pub(crate) fn generate_cert() -> Cert {
+    CertBuilder::new()
+        .set_creation_time(SystemTime::now() - Duration::from_secs(60 * 60))
+        .add_userid("Test User <test@example.com>")
+        .add_signing_subkey()
+        .generate()
+        .expect("unable to generate cert")
+        .0
+}
+
+/// Write the public portion of `cert` to a keyring file in `dir`.
+pub(crate) fn write_keyring(dir: &Path, cert: &Cert) -> PathBuf {
+    let path = dir.join("keyring.pgp");
+    let mut file = std::fs::File::create(&path).expect("unable to create keyring file");
+    cert.serialize(&mut file).expect("unable to write cert");
+    path
+}
+
+/// A repository to push, with arguments that verify its commits with a keyring, then run a
+/// command that creates `marker` and reports the branch it ran for.
+pub(crate) struct EndToEnd {
+    pub(crate) repository: TestRepository,
+    pub(crate) marker: PathBuf,
+    pub(crate) args: Vec<String>,
+    // Holds the keyring and the marker
+    _dir: TempDir,
+}
+
+impl EndToEnd {
+    /// Create the repository with a commit signed by `signer` if given, which is verified with a
+    /// keyring of `trusted`.
+    pub(crate) fn new(signer: Option<&Cert>, trusted: &Cert) -> Self {
+        let repository = TestRepository::new(signer);
+        let dir = TempDir::new("webhook-runner-test").expect("unable to create keyring dir");
+        let keyring = write_keyring(dir.path(), trusted);
+        let marker = dir.path().join("ran");
+        let args = [
+            "webhook-runner",
+            "--webhook-secret-key",
+            "testingkey",
+            "--commit-keyring",
+            keyring.to_str().unwrap(),
+            "--commit-command",
+            &format!(
+                "touch {} && echo \"{{\\\"branch\\\": \\\"$WEBHOOK_BRANCH\\\"}}\"",
+                marker.display()
+            ),
+            "--command-env",
+            "branch",
+            "--command-output-format",
+            "json-last-line",
+        ]
+        .map(str::to_string)
+        .to_vec();
+        EndToEnd {
+            repository,
+            marker,
+            args,
+            _dir: dir,
+        }
+    }
+
+    /// The arguments, followed by `extra_args`.
+    pub(crate) fn argv<'a>(&'a self, extra_args: &[&'a str]) -> Vec<&'a str> {
+        self.args
+            .iter()
+            .map(String::as_str)
+            .chain(extra_args.iter().copied())
+            .collect()
+    }
+}
+
+/// Create an armored detached signature over `data` with the signing key of `cert`.
+fn sign(cert: &Cert, data: &[u8]) -> String {
+    let policy = StandardPolicy::new();
+    let mut keypair = cert
+        .keys()
+        .unencrypted_secret()
+        .with_policy(&policy, None)
+        .for_signing()
+        .next()
+        .expect("cert has no signing key")
+        .key()
+        .clone()
+        .into_keypair()
+        .expect("unable to build keypair");
+    let sig = SignatureBuilder::new(SignatureType::Binary)
+        .set_signature_creation_time(SystemTime::now())
+        .expect("unable to set signature creation time")
+        .sign_message(&mut keypair, data)
+        .expect("unable to sign message");
+    let mut writer = Writer::new(vec![], Kind::Signature).expect("unable to create armor writer");
+    Packet::from(sig)
+        .serialize(&mut writer)
+        .expect("unable to serialize signature");
+    String::from_utf8(writer.finalize().expect("unable to finalize armor")).unwrap()
+}
+
+/// The `X-Hub-Signature-256` header that GitHub sends for `body` when configured with `key`.
+pub(crate) fn hub_signature(key: &str, body: &str) -> String {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Post a webhook to the application configured with `argv`, loading its keyrings the same way
+/// as when serving, and return the status and the decoded body of the response.
+pub(crate) async fn post_webhook(
+    argv: &[&str],
+    body: String,
+    signature: Option<String>,
+) -> (StatusCode, serde_json::Value) {
+    let args = Args::parse_with_config(argv.iter().copied()).expect("invalid arguments");
+    let (keyrings, _) = crate::load_keyrings(&args, true).expect("unable to load keyrings");
+    let mut request = Request::post("/")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("x-github-event", "push")
+        .header("x-github-delivery", "72d3162e");
+    if let Some(signature) = signature {
+        request = request.header("x-hub-signature-256", signature);
+    }
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let job_queue = Arc::new(crate::jobs::JobQueue::from_args(&args));
+    let response = crate::app(Arc::new(args), keyrings, None, job_queue, handle)
+        .oneshot(request.body(Body::from(body)).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+/// Call the webhook handler with a push delivered as `body`, without keyrings or a GitHub App,
+/// reporting events as configured by `args`.
+pub(crate) async fn push_webhook(
+    args: Arc<Args>,
+    job_queue: Arc<JobQueue>,
+    body: impl Into<Bytes>,
+) -> Result<JobStatus, DeathReason> {
+    let mut headers = HeaderMap::new();
+    headers.insert(crate::github::GITHUB_EVENT, "push".parse().unwrap());
+    headers.insert("x-github-delivery", "72d3162e".parse().unwrap());
+    let event_sink = Arc::new(EventSink::from_args(&args));
+    crate::webhook::webhook(
+        Extension(args),
+        Extension(Arc::new(KeyringFiles::default())),
+        Extension(None),
+        Extension(job_queue),
+        Extension(event_sink),
+        headers,
+        body.into(),
+    )
+    .await
+}
+
+// }}}
@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use axum::http::HeaderMap;
+use axum::{Extension, Json};
+use serde::Deserialize;
+use tracing::instrument;
+
+use webhook_runner_lib::github_app::GitHubApp;
+use webhook_runner_lib::payload::{CommitStats, Payload, PushRepository};
+
+use crate::cli::Args;
+use crate::events::{EventSink, RunEvents, Stage};
+use crate::jobs::JobQueue;
+use crate::status::{DeathReason, JobStatus};
+use crate::webhook::handle_push;
+use crate::KeyringFiles;
+
+/// Whether a manual trigger runs the command for a branch or for a tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TriggerTarget {
+    Commit,
+    Tag,
+}
+
+/// A request to run the command for a commit that was already pushed, such as to redeploy a
+/// known-good commit.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Trigger {
+    /// Name of the branch or tag, with or without the `refs/heads/` or `refs/tags/` prefix
+    #[serde(rename = "ref")]
+    _ref: String,
+
+    /// The commit to check out, which must be the commit the checkout resolves to
+    sha: String,
+
+    target: TriggerTarget,
+
+    /// Full name of the repository, used to find the configuration of individual repositories
+    #[serde(default)]
+    repository: String,
+}
+
+impl Trigger {
+    /// The fully qualified ref of the branch or tag, rejecting refs qualified for another target.
+    fn git_ref(&self) -> Result<String, DeathReason> {
+        let prefix = match self.target {
+            TriggerTarget::Commit => "refs/heads/",
+            TriggerTarget::Tag => "refs/tags/",
+        };
+        if self._ref.starts_with(prefix) {
+            Ok(self._ref.clone())
+        } else if self._ref.starts_with("refs/") {
+            Err(DeathReason::InvalidWebhook {
+                field_path: "ref".to_string(),
+                value: Some(self._ref.clone()),
+            })
+        } else {
+            Ok(format!("{prefix}{}", self._ref))
+        }
+    }
+
+    /// Convert the trigger to the push that GitHub would have sent for the commit. The commit is
+    /// marked as truncated, since the changed files are not known, so that the command is always
+    /// run.
+    fn into_payload(self, git_ref: String, clone_url: &str) -> Payload {
+        let commit = CommitStats {
+            id: self.sha.clone(),
+            ..CommitStats::default()
+        };
+        Payload::Push {
+            after: self.sha.clone(),
+            base_ref: None,
+            before: String::new(),
+            commits: vec![commit.clone()],
+            commits_truncated: true,
+            compare: String::new(),
+            created: false,
+            deleted: false,
+            forced: false,
+            head_commit: commit,
+            pusher: Default::default(),
+            _ref: git_ref,
+            repository: PushRepository {
+                clone_url: clone_url.to_string(),
+                full_name: self.repository,
+                ..PushRepository::default()
+            },
+            sender: Default::default(),
+        }
+    }
+}
+
+/// Run the command for a branch or tag as if the commit had just been pushed, cloning and
+/// verifying it the same way. The route is only served if both `--webhook-secret-key` and
+/// `--git-repository` are configured, and is verified the same way as webhooks. Unlike webhooks,
+/// the response always includes the end of the output of the command.
+#[instrument(skip_all)]
+#[axum_macros::debug_handler]
+pub(crate) async fn trigger(
+    args: Extension<Arc<Args>>,
+    keyring_dirs: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    Extension(event_sink): Extension<Arc<EventSink>>,
+    headers: HeaderMap,
+    Json(trigger): Json<Trigger>,
+) -> Result<JobStatus, DeathReason> {
+    let git_ref = trigger.git_ref()?;
+    let events = RunEvents::new(
+        event_sink,
+        &headers,
+        "trigger",
+        &trigger.repository,
+        &git_ref,
+        &trigger.sha,
+    );
+    events.publish(Stage::Received);
+
+    let clone_url = args
+        .git_repository
+        .clone()
+        .expect("trigger routed without a git repository");
+    let payload = trigger.into_payload(git_ref, &clone_url);
+    let result = handle_push(args, keyring_dirs, github_app, job_queue, &events, payload).await;
+    events.finish(&result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_qualify_trigger_ref() {
+        let trigger = |_ref: &str, target| Trigger {
+            _ref: _ref.to_string(),
+            sha: String::new(),
+            target,
+            repository: String::new(),
+        };
+        let git_ref = |_ref, target| trigger(_ref, target).git_ref().ok();
+        assert_eq!(
+            git_ref("main", TriggerTarget::Commit).as_deref(),
+            Some("refs/heads/main")
+        );
+        assert_eq!(
+            git_ref("refs/heads/main", TriggerTarget::Commit).as_deref(),
+            Some("refs/heads/main")
+        );
+        assert_eq!(
+            git_ref("v1.0.0", TriggerTarget::Tag).as_deref(),
+            Some("refs/tags/v1.0.0")
+        );
+        assert_eq!(git_ref("refs/heads/main", TriggerTarget::Tag), None);
+    }
+
+    #[tokio::test]
+    async fn can_trigger_commit_that_was_just_deployed() {
+        use clap::Parser;
+
+        use crate::test_util::push_webhook;
+
+        let repository = crate::test_util::TestRepository::new(None);
+        let args = Arc::new(Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "true",
+            "--git-repository",
+            &repository.clone_url(),
+            "--completed-job-ttl",
+            "60",
+        ]));
+        let job_queue = Arc::new(JobQueue::from_args(&args));
+        let push = || {
+            push_webhook(
+                args.clone(),
+                job_queue.clone(),
+                repository.push_body("refs/heads/main"),
+            )
+        };
+        let redeploy = || {
+            trigger(
+                Extension(args.clone()),
+                Extension(Arc::new(KeyringFiles::default())),
+                Extension(None),
+                Extension(job_queue.clone()),
+                Extension(Arc::new(EventSink::default())),
+                HeaderMap::new(),
+                Json(Trigger {
+                    _ref: "main".to_string(),
+                    sha: repository.commit.to_string(),
+                    target: TriggerTarget::Commit,
+                    repository: "octocat/Hello-World".to_string(),
+                }),
+            )
+        };
+
+        match push().await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("push was not run: {e:?}"),
+        }
+        // Redeploying the commit is the point of a trigger, however recently it was run
+        for _ in 0..2 {
+            match redeploy().await {
+                Ok(JobStatus::Completed { .. }) => (),
+                e => panic!("trigger of deployed commit was not run: {e:?}"),
+            }
+        }
+        // Triggers don't change which deliveries of webhooks are skipped
+        match push().await {
+            Ok(JobStatus::Skipped { .. }) => (),
+            e => panic!("push delivered again was not skipped: {e:?}"),
+        }
+    }
+}
@@ -1,136 +1,2609 @@
+use std::collections::BTreeSet;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
+use axum::body::Bytes;
+use axum::http::HeaderMap;
 use axum::{Extension, Json};
-use git2::Oid;
-use tracing::{debug, instrument};
+use git2::{Commit, Oid, Repository};
+use glob::Pattern;
+use metrics::counter;
+use tempdir::TempDir;
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
 
-use crate::cli::Args;
-use crate::payload::Payload;
-use crate::repository::{clone_repository, verify_commit};
-use crate::status::DeathReason;
+use webhook_runner_lib::cert_builder::{KeyringFile, Signer};
+use webhook_runner_lib::command::{
+    expand_placeholders, run_command, CapturedOutput, CommandOutput, WebhookEnv,
+};
+use webhook_runner_lib::github_app::GitHubApp;
+use webhook_runner_lib::payload::{is_zero_id, CommitStats, Payload};
+use webhook_runner_lib::repository::{CloneOptions, Credentials};
+use webhook_runner_lib::watcher::SharedKeyringFile;
+use webhook_runner_lib::{ConfiguredKeyring, ProcessingError};
+
+use crate::cli::{
+    Args, CommandConfig, CommandOutputFormat, PathCommand, RepositoryConfig, TagVerification,
+    VerifyMode, WebhookVariable,
+};
+use crate::events::{EventSink, RunEvents, Stage, Verification};
+use crate::gitea::{self, GITEA_EVENT};
+use crate::github::{self, GITHUB_EVENT};
+use crate::gitlab::GitLabPayload;
+use crate::jobs::{catch_panic, Job, JobQueue};
+use crate::repository::{
+    changed_files, clone_and_verify, commits_between, in_repo_keyring, url_host, verify_commit,
+    verify_tag, verify_tag_message,
+};
+use crate::status::{DeathReason, JobStatus, OutputTail};
 use crate::KeyringFiles;
 
+/// GitHub includes at most this many commits in the payload of a push.
+const MAX_PAYLOAD_COMMITS: usize = 2048;
+
+/// The commands to run for a push: either the command configured for the ref, or the commands for
+/// changes to paths, of which those matching the changed files are run.
+#[derive(Debug)]
+enum PushCommand<'a> {
+    Single(&'a CommandConfig),
+    Paths(&'a [PathCommand]),
+}
+
+/// Determine the paths of files changed by a push from the union of the files added, modified,
+/// and removed by each commit in the payload, or `None` if the payload may be missing commits.
+fn changed_files_from_payload(commits: &[CommitStats], truncated: bool) -> Option<Vec<String>> {
+    if truncated || commits.len() >= MAX_PAYLOAD_COMMITS {
+        return None;
+    }
+    let files = commits
+        .iter()
+        .flat_map(|c| c.added.iter().chain(&c.modified).chain(&c.removed))
+        .collect::<BTreeSet<_>>();
+    Some(files.into_iter().cloned().collect())
+}
+
+/// Check that `git_ref` is a well-formed branch or tag: a ref under `refs/heads/` or `refs/tags/`
+/// with a non-empty name that follows the rules of `git check-ref-format`, so that no component is
+/// empty, `.`, or `..`.
+fn check_ref(git_ref: &str) -> Result<(), DeathReason> {
+    let name = git_ref
+        .strip_prefix("refs/heads/")
+        .or_else(|| git_ref.strip_prefix("refs/tags/"));
+    if name.is_some_and(|name| !name.is_empty()) && git2::Reference::is_valid_name(git_ref) {
+        Ok(())
+    } else {
+        Err(DeathReason::InvalidWebhook {
+            field_path: "_ref".to_string(),
+            value: Some(git_ref.to_string()),
+        })
+    }
+}
+
+/// Check that `id` is the full name of a commit, as lowercase hex of a SHA-1 hash, so that a
+/// malformed id is rejected before anything is cloned. Ids of SHA-256 repositories are rejected
+/// the same way [`clone_repository`](webhook_runner_lib::clone_repository) would reject them.
+fn check_commit_id(id: &str) -> Result<(), DeathReason> {
+    let is_hex = id
+        .bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    if is_hex && id.len() == 40 {
+        Ok(())
+    } else if is_hex && id.len() == 64 {
+        Err(DeathReason::FailedClone {
+            reason: ProcessingError::UnsupportedObjectFormat {
+                format: "sha256".to_string(),
+            }
+            .to_string(),
+        })
+    } else {
+        Err(DeathReason::InvalidWebhook {
+            field_path: "commits[].id".to_string(),
+            value: Some(id.to_string()),
+        })
+    }
+}
+
+/// Check that the webhook's `clone_url` is on one of the allowed clone hosts, so that a forged
+/// payload can't point the runner at another server. Any URL is allowed if no hosts are
+/// configured, and the URL is ignored if a Git repository is configured.
+fn check_clone_url(args: &Args, clone_url: &str, field_path: &str) -> Result<(), DeathReason> {
+    if args.allowed_clone_host.is_empty() || args.git_repository.is_some() {
+        return Ok(());
+    }
+    match url_host(clone_url) {
+        Some(host)
+            if args
+                .allowed_clone_host
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host)) =>
+        {
+            Ok(())
+        }
+        _ => Err(DeathReason::InvalidWebhook {
+            field_path: field_path.to_string(),
+            value: Some(clone_url.to_string()),
+        }),
+    }
+}
+
+/// Determine whether any of `files` matches any of `filters`; every list of files matches if no
+/// filters are configured.
+fn matches_path_filter(files: &[String], filters: &[Pattern]) -> bool {
+    filters.is_empty()
+        || files
+            .iter()
+            .any(|file| filters.iter().any(|pattern| pattern.matches(file)))
+}
+
+/// Determine the paths of files changed by a push, using the commits from the payload unless the
+/// payload may be missing commits, in which case the commits before and after the push are
+/// compared. An empty list is returned if neither is possible.
+fn changed_files_for_push(
+    repository: &Repository,
+    before: &str,
+    after: &str,
+    commits: &[CommitStats],
+    truncated: bool,
+) -> Vec<String> {
+    if let Some(files) = changed_files_from_payload(commits, truncated) {
+        return files;
+    }
+    // A new ref has no commit before the push to compare with
+    if is_zero_id(before) {
+        debug!("ref was created, using an empty list of changed files");
+        return vec![];
+    }
+
+    let result = Oid::from_str(before)
+        .and_then(|before| Ok((before, Oid::from_str(after)?)))
+        .map_err(ProcessingError::from)
+        .and_then(|(before, after)| changed_files(repository, before, after));
+    result.unwrap_or_else(|e| {
+        warn!(%e, "unable to determine changed files, using an empty list");
+        vec![]
+    })
+}
+
+/// List the commits introduced by a push, other than the commit `after` that is checked out, so
+/// that each can be verified. Payloads that may be missing commits are completed from the
+/// repository, and the push is rejected if that is not possible, such as when the history was not
+/// fetched because of the clone depth. Pushes that introduce more than `max_commits` are rejected.
+fn pushed_commits(
+    repository: &Repository,
+    before: &str,
+    after: &str,
+    commits: &[CommitStats],
+    truncated: bool,
+    max_commits: Option<usize>,
+) -> Result<Vec<String>, DeathReason> {
+    // Manual triggers don't know the commit before the push, and only list the commit to check out
+    let ids = if (truncated || commits.len() >= MAX_PAYLOAD_COMMITS) && !before.is_empty() {
+        if is_zero_id(before) {
+            return Err(DeathReason::KeyringVerification {
+                reason: "unable to list every commit of a push that created a ref".to_string(),
+                kept_clone: None,
+            });
+        }
+        Oid::from_str(before)
+            .and_then(|before| Ok((before, Oid::from_str(after)?)))
+            .map_err(ProcessingError::from)
+            .and_then(|(before, after)| commits_between(repository, before, after))
+            .map_err(|e| DeathReason::KeyringVerification {
+                reason: format!("unable to list every commit of the push: {e}"),
+                kept_clone: None,
+            })?
+            .iter()
+            .map(Oid::to_string)
+            .collect()
+    } else {
+        commits
+            .iter()
+            .filter(|commit| !commit.id.is_empty())
+            .map(|commit| commit.id.clone())
+            .collect::<Vec<_>>()
+    };
+    if let Some(max) = max_commits.filter(|max| ids.len() > *max) {
+        return Err(DeathReason::TooManyCommits {
+            commits: ids.len(),
+            max,
+        });
+    }
+    Ok(ids.into_iter().filter(|id| id != after).collect())
+}
+
+/// Determine the commit a push points to. Pushes of a tag pointing at an existing commit don't list
+/// any commits, so the head commit is used instead, falling back to `after` if there is none.
+/// `after` is only a last resort, since it is the tag object rather than a commit for annotated
+/// tags.
+fn push_target<'a>(
+    commits: &'a [CommitStats],
+    head_commit: &'a CommitStats,
+    after: &'a str,
+) -> &'a str {
+    let id = &commits.last().unwrap_or(head_commit).id;
+    if id.is_empty() {
+        after
+    } else {
+        id
+    }
+}
+
+/// Extract the structured result of a command from its output. Output that does not hold a result
+/// in the given format is treated as having no result, rather than as an error.
+fn structured_result(
+    format: CommandOutputFormat,
+    output: &CommandOutput,
+) -> Option<serde_json::Value> {
+    let last_line = match (format, &output.last_line) {
+        (CommandOutputFormat::JsonLastLine, Some(last_line)) => last_line,
+        (CommandOutputFormat::JsonLastLine, None) | (CommandOutputFormat::Text, _) => return None,
+    };
+    match serde_json::from_str(last_line) {
+        Ok(result @ serde_json::Value::Object(_)) => {
+            info!(%result, "command reported result");
+            Some(result)
+        }
+        Ok(_) => {
+            warn!("last line of command output is not a json object, ignoring");
+            None
+        }
+        Err(e) => {
+            warn!(%e, "last line of command output is not valid json, ignoring");
+            None
+        }
+    }
+}
+
+/// Run a configured command in the cloned repository, or in its directory within the repository,
+/// with the timeout and environment variables of the command if it sets any. Commands that exit
+/// with a code that is retried are run again, up to `retries` times, waiting longer before each
+/// attempt.
+async fn run_configured(
+    args: &Args,
+    command: &CommandConfig,
+    repository_directory: &Path,
+    env: &WebhookEnv,
+    stdin: Option<Vec<u8>>,
+    retries: u32,
+) -> webhook_runner_lib::Result<CommandOutput> {
+    let directory = match &command.cwd {
+        Some(cwd) => repository_directory.join(cwd),
+        None => repository_directory.to_path_buf(),
+    };
+    let mut env = env.clone();
+    for (name, value) in &command.env {
+        env.set_configured(name, value);
+    }
+    let timeout = command.timeout.unwrap_or(args.command_timeout);
+    let run = expand_placeholders(&command.run)?;
+    let options = args.process_options()?;
+    let mut delay = Duration::from_secs(args.command_retry_delay);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        // The output of each attempt is logged within its own span, to tell the attempts apart
+        let result = run_command(
+            &run,
+            &args.command_mode(),
+            &directory,
+            &env,
+            stdin.clone(),
+            Duration::from_secs(timeout.into()),
+            &options,
+        )
+        .instrument(info_span!("attempt", attempt))
+        .await;
+        match result {
+            Err(ProcessingError::Command { exit_code, .. })
+                if attempt <= retries && args.retries_exit_code(exit_code) =>
+            {
+                warn!(
+                    exit_code,
+                    attempt,
+                    ?delay,
+                    "command failed, running it again"
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Describe a command that failed to run or exited unsuccessfully, keeping its exit code and the
+/// last `output_bytes` of its output.
+fn command_failed(e: &ProcessingError, reason: String, output_bytes: usize) -> DeathReason {
+    match e {
+        ProcessingError::Command { exit_code, output } => DeathReason::CommandFailed {
+            reason,
+            exit_code: Some(*exit_code),
+            output: Some(OutputTail::new(output, output_bytes)),
+            kept_clone: None,
+            result: None,
+        },
+        _ => DeathReason::CommandFailed {
+            reason,
+            exit_code: None,
+            output: None,
+            kept_clone: None,
+            result: None,
+        },
+    }
+}
+
+/// Run the commands for a push, either the command configured for the ref, or the commands for
+/// changes to paths that match the changed `files`, returning the structured result and output.
+/// If a command for changed paths fails, the results of the commands before it are returned with
+/// the reason it failed.
+async fn run_push_command(
+    args: &Args,
+    events: &RunEvents,
+    command: PushCommand<'_>,
+    repository_directory: &Path,
+    env: &WebhookEnv,
+    files: &[String],
+    stdin: Option<String>,
+) -> Result<(Option<serde_json::Value>, CapturedOutput), DeathReason> {
+    Ok(match command {
+        PushCommand::Single(command) => {
+            events.running(&command.run);
+            let output = run_configured(
+                args,
+                command,
+                repository_directory,
+                env,
+                stdin.map(String::into_bytes),
+                args.command_retries,
+            )
+            .await
+            .map_err(|e| command_failed(&e, e.to_string(), args.return_output_bytes))?;
+            (
+                structured_result(args.command_output_format, &output),
+                output.captured,
+            )
+        }
+        PushCommand::Paths(path_commands) => {
+            // Every command is run if the changed files could not be determined, and the
+            // results of the commands are keyed by their pattern
+            let mut results = serde_json::Map::new();
+            let mut captured = CapturedOutput::default();
+            for path_command in path_commands
+                .iter()
+                .filter(|c| files.is_empty() || c.matches(files))
+            {
+                debug!(pattern = %path_command.pattern, "running command for changed paths");
+                events.running(&path_command.command.run);
+                let output = run_configured(
+                    args,
+                    &path_command.command,
+                    repository_directory,
+                    env,
+                    stdin.clone().map(String::into_bytes),
+                    args.command_retries,
+                )
+                .await
+                .map_err(|e| {
+                    let reason = format!("command for {}: {e}", path_command.pattern);
+                    let mut reason = command_failed(&e, reason, args.return_output_bytes);
+                    if let DeathReason::CommandFailed { result, .. } = &mut reason {
+                        *result = Some(Box::new(std::mem::take(&mut results).into()));
+                    }
+                    reason
+                })?;
+                let result = structured_result(args.command_output_format, &output);
+                results.insert(path_command.pattern.to_string(), result.unwrap_or_default());
+                captured.append(&output.captured);
+            }
+            (Some(serde_json::Value::Object(results)), captured)
+        }
+    })
+}
+
+/// Run the before command, if one is configured, with the same environment as the command of the
+/// webhook. The command of the webhook is vetoed if the before command exits unsuccessfully, so it
+/// is never retried.
+async fn run_before_command(
+    args: &Args,
+    events: &RunEvents,
+    repository_directory: &Path,
+    env: &WebhookEnv,
+) -> Result<(), DeathReason> {
+    let Some(command) = &args.before_command else {
+        return Ok(());
+    };
+    events.running(&command.run);
+    match run_configured(args, command, repository_directory, env, None, 0).await {
+        Ok(_) => Ok(()),
+        Err(ProcessingError::Command { exit_code, output }) => {
+            info!(exit_code, "before command vetoed running the command");
+            Err(DeathReason::PreCommandVeto {
+                reason: format!("before command returned nonzero exit code: {exit_code}"),
+                exit_code,
+                output: Some(OutputTail::new(&output, args.return_output_bytes)),
+            })
+        }
+        Err(e) => Err(command_failed(
+            &e,
+            format!("before command: {e}"),
+            args.return_output_bytes,
+        )),
+    }
+}
+
+/// Run the after command, if one is configured, with the exit code of the command of the webhook
+/// passed in `WEBHOOK_COMMAND_EXIT`. The after command is not retried, and its failures are only
+/// logged, so that they never change the result of the webhook.
+async fn run_after_command(
+    args: &Args,
+    repository_directory: &Path,
+    env: &WebhookEnv,
+    exit_code: i32,
+) {
+    let Some(command) = &args.after_command else {
+        return;
+    };
+    let mut env = env.clone();
+    env.set("COMMAND_EXIT", &exit_code.to_string())
+        .expect("exit code is a valid variable");
+    match run_configured(args, command, repository_directory, &env, None, 0).await {
+        Ok(_) => debug!(exit_code, "after command finished"),
+        Err(e) => warn!(%e, exit_code, "after command failed, ignoring"),
+    }
+}
+
+/// Owner of the cloned repository while the command of a webhook runs, which runs the after
+/// command once the command has finished, whether it succeeded, failed, or timed out, and before
+/// the cloned repository is removed. The exit code is -1 if the command had no exit code, such as
+/// when it timed out.
+///
+/// The after command runs in its own task, so that it is not cancelled along with the webhook by
+/// `--request-timeout` or a client disconnecting. If the webhook is dropped while its command is
+/// still running, the after command is run from [`Drop`] with an exit code of -1.
+struct AfterCommand {
+    args: Arc<Args>,
+    env: WebhookEnv,
+    directory: Option<TempDir>,
+}
+
+impl AfterCommand {
+    fn new(args: Arc<Args>, env: &WebhookEnv, directory: TempDir) -> Self {
+        Self {
+            args,
+            env: env.clone(),
+            directory: Some(directory),
+        }
+    }
+
+    fn path(&self) -> &Path {
+        self.directory
+            .as_ref()
+            .expect("directory is only taken when finished")
+            .path()
+    }
+
+    /// Run the after command for the result of the command, and give back the cloned repository.
+    /// If the task of the after command panicked or was cancelled, the cloned repository went with
+    /// it, and this is only logged, since the after command does not change the result.
+    async fn finish<T>(
+        mut self,
+        events: &RunEvents,
+        result: &Result<T, DeathReason>,
+    ) -> Option<TempDir> {
+        let exit_code = match result {
+            Ok(_) => 0,
+            Err(DeathReason::CommandFailed { exit_code, .. }) => exit_code.unwrap_or(-1),
+            Err(_) => -1,
+        };
+        if let Some(command) = &self.args.after_command {
+            events.running(&command.run);
+        }
+        match self.spawn(exit_code).await {
+            Ok(directory) => Some(directory),
+            Err(e) => {
+                warn!(%e, "after command did not finish, ignoring");
+                None
+            }
+        }
+    }
+
+    fn spawn(&mut self, exit_code: i32) -> tokio::task::JoinHandle<TempDir> {
+        let args = self.args.clone();
+        let env = std::mem::take(&mut self.env);
+        let directory = self
+            .directory
+            .take()
+            .expect("directory is only taken when finished");
+        tokio::spawn(async move {
+            run_after_command(&args, directory.path(), &env, exit_code).await;
+            directory
+        })
+    }
+}
+
+impl Drop for AfterCommand {
+    fn drop(&mut self) {
+        if self.directory.is_some() && self.args.after_command.is_some() {
+            info!("webhook was dropped while its command was running, running after command");
+            // The cloned repository is removed once the after command has finished
+            drop(self.spawn(-1));
+        }
+    }
+}
+
+/// Keep the cloned repository of a webhook whose command failed or whose commit failed
+/// verification, if `--keep-failed-clones` is set, so that its working tree can be inspected. The
+/// repository is moved into `--clone-keep-dir` if one is configured, and where it was kept is
+/// logged and added to the reason. Otherwise, the repository is removed as usual.
+fn keep_failed_clone(args: &Args, directory: TempDir, mut reason: DeathReason) -> DeathReason {
+    let kept = matches!(
+        reason,
+        DeathReason::CommandFailed { .. } | DeathReason::KeyringVerification { .. }
+    );
+    if !args.keep_failed_clones || !kept {
+        return reason;
+    }
+    let path = directory.into_path();
+    let path = match &args.clone_keep_dir {
+        Some(keep_dir) => {
+            let name = path.file_name().expect("temporary directories have a name");
+            let kept_path = keep_dir.join(name);
+            match std::fs::create_dir_all(keep_dir)
+                .and_then(|()| std::fs::rename(&path, &kept_path))
+            {
+                Ok(()) => kept_path,
+                Err(e) => {
+                    warn!(%e, ?path, "unable to move failed clone, keeping it where it was cloned");
+                    path
+                }
+            }
+        }
+        None => path,
+    };
+    warn!(?path, "kept cloned repository of failed webhook");
+    if let DeathReason::CommandFailed { kept_clone, .. }
+    | DeathReason::KeyringVerification { kept_clone, .. } = &mut reason
+    {
+        *kept_clone = Some(path.display().to_string());
+    }
+    reason
+}
+
+/// Build the environment variables selected by `variables` for a push of `git_ref`, rejecting the
+/// webhook if any value could not be safely passed to a command.
+fn webhook_env(
+    variables: &[WebhookVariable],
+    git_ref: &str,
+    before: &str,
+    after: &str,
+    repository: &str,
+    pusher: &str,
+) -> Result<WebhookEnv, DeathReason> {
+    let mut env = WebhookEnv::default();
+    for variable in variables {
+        let (field_path, value) = match variable {
+            WebhookVariable::Ref => ("_ref", Some(git_ref)),
+            WebhookVariable::Before => ("before", Some(before)),
+            WebhookVariable::After => ("after", Some(after)),
+            WebhookVariable::Branch => ("_ref", git_ref.strip_prefix("refs/heads/")),
+            WebhookVariable::Tag => ("_ref", git_ref.strip_prefix("refs/tags/")),
+            WebhookVariable::Repository => ("repository.full_name", Some(repository)),
+            WebhookVariable::Pusher => ("pusher.name", Some(pusher)),
+        };
+        if let Some(value) = value {
+            env.set(variable.name(), value)
+                .map_err(|_| DeathReason::InvalidWebhook {
+                    field_path: field_path.to_string(),
+                    value: Some(value.to_string()),
+                })?;
+        }
+    }
+    Ok(env)
+}
+
+/// Set the values of the placeholders of a command for the commit `sha` of `git_ref`, rejecting
+/// the webhook if any value could not be safely passed to a command.
+fn set_placeholders(
+    env: &mut WebhookEnv,
+    sha: &str,
+    git_ref: &str,
+    repository: &str,
+) -> Result<(), DeathReason> {
+    env.set_placeholders(sha, git_ref, repository)
+        .map_err(|e| DeathReason::InvalidWebhook {
+            field_path: "placeholders".to_string(),
+            value: Some(e.to_string()),
+        })
+}
+
+/// Find the configuration and keyrings of the repository `full_name`. Repositories that are
+/// configured individually override the options of the runner, and once any are, webhooks of
+/// other repositories are rejected.
+fn repository_config<'a>(
+    args: &'a Args,
+    keyring_files: &'a KeyringFiles,
+    full_name: &str,
+) -> Result<(Option<&'a RepositoryConfig>, Option<&'a KeyringFiles>), DeathReason> {
+    if args.repositories.is_empty() {
+        return Ok((None, None));
+    }
+    match args.repositories.get(full_name) {
+        Some(config) => Ok((Some(config), keyring_files.repositories.get(full_name))),
+        None => Err(DeathReason::UnconfiguredRepository {
+            repository: full_name.to_string(),
+        }),
+    }
+}
+
+/// Resolve a configured keyring, rejecting the webhook if the keyring failed to load. A keyring
+/// that failed to load must never be treated as if no keyring was configured.
+fn configured_keyring(
+    keyring: Option<&ConfiguredKeyring>,
+) -> Result<Option<&SharedKeyringFile>, DeathReason> {
+    match keyring {
+        Some(Ok(keyring_file)) => Ok(Some(keyring_file)),
+        Some(Err(reason)) => Err(DeathReason::KeyringError {
+            reason: reason.clone(),
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Verify the pushed commit, or the pushed tag named `tag_name` if tags are verified by their own
+/// signatures. With `--tag-verification tag`, lightweight tags have no signature of their own, so
+/// the commit they point to is verified instead.
+fn verify_push(
+    args: &Args,
+    repository: &Repository,
+    commit: &Commit<'_>,
+    tag_name: Option<&str>,
+    keyring: &KeyringFile,
+) -> Result<Signer, ProcessingError> {
+    let options = args.verify_options();
+    match (tag_name, args.tag_verification) {
+        (Some(tag_name), TagVerification::Tag) => {
+            match verify_tag(repository, tag_name, commit, keyring, &options) {
+                Err(ProcessingError::NotAnnotatedTag { .. }) => {
+                    debug!(tag_name, "lightweight tag pushed, verifying its commit");
+                    verify_commit(commit.clone(), keyring, &options)
+                }
+                result => result,
+            }
+        }
+        (Some(tag_name), TagVerification::Annotated) => {
+            verify_tag(repository, tag_name, commit, keyring, &options)
+        }
+        _ => verify_commit(commit.clone(), keyring, &options),
+    }
+}
+
+/// Verify the commit `commit_id` of a cloned repository with the given keyring. If an in-repo
+/// keyring is configured, the given keyring only needs to have signed the in-repo keyring, which
+/// the commit is then verified with. Commits that fail verification are rejected, unless
+/// verification is only audited.
+fn verify_checkout(
+    args: &Args,
+    repository: &Repository,
+    git_ref: Option<&str>,
+    commit_id: &str,
+    keyring_file: &SharedKeyringFile,
+) -> Result<Verification, DeathReason> {
+    let commit = {
+        let oid = Oid::from_str(commit_id).map_err(|e| DeathReason::RepositoryError {
+            reason: e.to_string(),
+        })?;
+        repository
+            .find_commit(oid)
+            .map_err(|e| DeathReason::RepositoryError {
+                reason: e.to_string(),
+            })?
+    };
+
+    // Keyring directory exists via TempDir
+    let keyring_file = keyring_file.read().expect("keyring lock poisoned");
+    let tag_name = git_ref.and_then(|git_ref| git_ref.strip_prefix("refs/tags/"));
+    let result = match &args.in_repo_keyring {
+        Some(path) => in_repo_keyring(
+            repository,
+            &commit,
+            path,
+            &keyring_file,
+            &args.verify_options(),
+        )
+        .and_then(|keyring| verify_push(args, repository, &commit, tag_name, &keyring)),
+        None => verify_push(args, repository, &commit, tag_name, &keyring_file),
+    };
+    match (result, args.verify_mode) {
+        (Ok(signer), _) => {
+            info!(
+                commit = commit_id,
+                fingerprint = %signer.fingerprint,
+                signer = signer.user_id.as_deref(),
+                "commit verified"
+            );
+            counter!("commit_verifications_total", 1, "result" => "success");
+            Ok(Verification::Verified(signer))
+        }
+        (Err(e), VerifyMode::Audit) => {
+            warn!(%e, commit = commit_id, "commit failed verification, continuing in audit mode");
+            counter!("commit_verifications_total", 1, "result" => "failure");
+            counter!("commit_verification_audit_failures_total", 1);
+            Ok(Verification::Failed)
+        }
+        (Err(e), VerifyMode::Enforce) => {
+            counter!("commit_verifications_total", 1, "result" => "failure");
+            Err(DeathReason::KeyringVerification {
+                reason: e.to_string(),
+                kept_clone: None,
+            })
+        }
+    }
+}
+
+/// Clone the configured repository, or `clone_url` if none is configured, checking out the commit
+/// `commit_id` and verifying it with [`verify_checkout`] if a keyring is given. The clone timeout
+/// is extended for the repository size reported by the webhook, if any. Submodules are only
+/// checked out once the commit is verified. The result of verification is returned with the clone.
+async fn clone_verified(
+    args: &Args,
+    github_app: &Option<Arc<GitHubApp>>,
+    clone_url: &str,
+    repository_size: Option<u64>,
+    git_ref: Option<&str>,
+    commit_id: &str,
+    keyring_file: Option<&SharedKeyringFile>,
+) -> Result<(Repository, TempDir, Verification), DeathReason> {
+    let repository_url = args.git_repository.as_deref().unwrap_or(clone_url);
+    // Prefer the GitHub App for HTTPS URLs, falling back to a configured token
+    let credentials = match github_app {
+        Some(github_app) if repository_url.starts_with("https://") => github_app
+            .credentials()
+            .await
+            .map_err(|e| DeathReason::FailedClone {
+                reason: e.to_string(),
+            })?,
+        _ => {
+            let token = args.https_token().map_err(|e| DeathReason::FailedClone {
+                reason: format!("unable to read https token: {e}"),
+            })?;
+            let ssh_key = args
+                .ssh_credentials()
+                .map_err(|e| DeathReason::FailedClone {
+                    reason: format!("unable to read ssh key passphrase: {e}"),
+                })?;
+            Credentials::for_url(repository_url, ssh_key, token.as_deref())
+        }
+    };
+    let clone_timeout = args.clone_timeout_for(repository_size);
+    debug!(clone_timeout, "determined clone timeout");
+    let clone_options = CloneOptions {
+        timeout: clone_timeout,
+        depth: args.clone_depth,
+        submodules: !args.no_submodules,
+        allowed_hosts: args.allowed_clone_host.clone(),
+        cache_dir: args.cache_dir.clone(),
+        host_keys: args
+            .host_key_verification()
+            .map_err(|e| DeathReason::FailedClone {
+                reason: format!("unable to read known hosts: {e}"),
+            })?,
+    };
+    let (repository, repository_directory, verification) = clone_and_verify(
+        repository_url,
+        git_ref,
+        commit_id,
+        &clone_options,
+        credentials,
+        |repository| match keyring_file {
+            Some(keyring_file) => {
+                verify_checkout(args, repository, git_ref, commit_id, keyring_file)
+            }
+            None => Ok(Verification::NotRequired),
+        },
+    )
+    .await
+    .map_err(|e| DeathReason::FailedClone {
+        reason: e.to_string(),
+    })?;
+    let verification = match verification {
+        Ok(verification) => verification,
+        Err(e) => return Err(keep_failed_clone(args, repository_directory, e)),
+    };
+    // Commands run as another user must be able to write to the repository
+    args.process_options()
+        .and_then(|options| options.user.give_directory(repository_directory.path()))
+        .map_err(|e| DeathReason::FailedClone {
+            reason: format!("unable to give the repository to the command user: {e}"),
+        })?;
+
+    Ok((repository, repository_directory, verification))
+}
+
 #[instrument(skip_all)]
-async fn handle_push(
+pub(crate) async fn handle_push(
     args: Extension<Arc<Args>>,
     keyring_files: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    events: &RunEvents,
     payload: Payload,
-) -> Result<(), DeathReason> {
+) -> Result<JobStatus, DeathReason> {
     if let Payload::Push {
         _ref: git_ref,
+        after,
+        before,
         commits,
+        commits_truncated,
+        deleted,
+        head_commit,
+        pusher,
         repository,
         ..
     } = payload
     {
+        let (repository_config, repository_keyrings) =
+            repository_config(&args, &keyring_files, &repository.full_name)?;
+        if args.strict_ref_parsing {
+            check_ref(&git_ref)?;
+        }
+        if repository_config.is_some_and(|config| !config.matches_ref(&git_ref)) {
+            return Ok(JobStatus::Skipped {
+                reason: format!("ref {git_ref} is not configured for the repository"),
+            });
+        }
+        // Deleted refs have nothing to check out
+        if deleted {
+            return Ok(JobStatus::Skipped {
+                reason: format!("ref {git_ref} was deleted"),
+            });
+        }
+
         // Determine whether the push was for a tag or a branch by checking if `ref` starts
         // with an identifier for either, and depending on those options, return a command and
         // optional keyring
         let (command, keyring_file) = if git_ref.starts_with("refs/heads/") {
-            // This is a commit pushed to a branch
-            match &**args {
-                // This double deref seems dangerous. Trusting the compiler.
-                Args {
-                    commit_command: Some(command),
-                    ..
-                } => (command, &keyring_files.commit),
-                _ => return Ok(()),
-            }
+            // This is a commit pushed to a branch; commands for changes to paths take the place of
+            // the commit command of the runner, but not of the commit command of a repository
+            let command = match repository_config.and_then(|config| config.commit_command.as_ref())
+            {
+                Some(command) => Some(PushCommand::Single(command)),
+                None if !args.path_commands.is_empty() => {
+                    Some(PushCommand::Paths(&args.path_commands))
+                }
+                None => args.commit_command.as_ref().map(PushCommand::Single),
+            };
+            (
+                command,
+                repository_keyrings
+                    .and_then(|keyrings| keyrings.commit.as_ref())
+                    .or(keyring_files.commit.as_ref()),
+            )
         } else if git_ref.starts_with("refs/tags/") {
             // This is a commit pushed to a tag
-            match &**args {
-                // This double deref seems dangerous. Trusting the compiler.
-                Args {
-                    tag_command: Some(command),
-                    ..
-                } => (command, &keyring_files.tag),
-                _ => return Ok(()),
-            }
+            (
+                repository_config
+                    .and_then(|config| config.tag_command.as_ref())
+                    .or(args.tag_command.as_ref())
+                    .map(PushCommand::Single),
+                repository_keyrings
+                    .and_then(|keyrings| keyrings.tag.as_ref())
+                    .or(keyring_files.tag.as_ref()),
+            )
         } else {
             return Err(DeathReason::InvalidWebhook {
                 field_path: "_ref".to_string(),
                 value: Some(git_ref.to_string()),
             });
         };
+        let Some(command) = command else {
+            return Ok(JobStatus::Skipped {
+                reason: "no command configured".to_string(),
+            });
+        };
         debug!(?command, "determined operation to run");
 
-        let commit = match commits.last() {
-            Some(c) => c,
+        let keyring_file = configured_keyring(keyring_file)?;
+
+        // Protected branches must be verified even if a keyring would not be consulted for them
+        if let Some(branch) = git_ref.strip_prefix("refs/heads/") {
+            if keyring_file.is_none()
+                && args.is_protected_branch(branch, &repository.default_branch)
+            {
+                return Err(DeathReason::KeyringVerification {
+                    reason: format!(
+                        "branch {branch} is protected but no commit keyring is configured"
+                    ),
+                    kept_clone: None,
+                });
+            }
+        }
+
+        let mut env = webhook_env(
+            &args.command_env,
+            &git_ref,
+            &before,
+            &after,
+            &repository.full_name,
+            &pusher.name,
+        )?;
+
+        let commit_id = push_target(&commits, &head_commit, &after);
+        debug!(commit = commit_id, "determined commit to check out");
+        check_commit_id(commit_id)?;
+        check_clone_url(&args, &repository.clone_url, "repository.clone_url")?;
+        // Pushes whose payload lists too many commits are rejected before cloning
+        if let Some(max) = args.max_commits.filter(|max| commits.len() > *max) {
+            return Err(DeathReason::TooManyCommits {
+                commits: commits.len(),
+                max,
+            });
+        }
+        set_placeholders(&mut env, commit_id, &git_ref, &repository.full_name)?;
+        // Skip pushes that don't touch any filtered path before cloning, unless the payload may be
+        // missing commits, in which case the repository is compared after cloning
+        let payload_files = changed_files_from_payload(&commits, commits_truncated);
+        if let Some(files) = &payload_files {
+            if !matches_path_filter(files, &args.path_filter) {
+                return Ok(JobStatus::Skipped {
+                    reason: "no changed files match the path filter".to_string(),
+                });
+            }
+            if let PushCommand::Paths(path_commands) = command {
+                if !path_commands.iter().any(|c| c.matches(files)) {
+                    return Ok(JobStatus::Skipped {
+                        reason: "no changed files match a path command".to_string(),
+                    });
+                }
+            }
+        }
+
+        let Some(push_permit) = job_queue.allow_push(&repository.full_name).await else {
+            return Ok(JobStatus::Skipped {
+                reason: format!(
+                    "superseded by a later push to {} past the push limit",
+                    repository.full_name
+                ),
+            });
+        };
+
+        // Hold a job slot until the command has finished
+        let _job_slot = job_queue.acquire().await?;
+        // A push delivered again while the first delivery is running waits for it, and is skipped
+        // if it completed. Manual triggers are meant to run a commit again, so they are neither
+        // skipped nor recorded
+        let job = if events.is_manual() {
+            None
+        } else {
+            let job = Job {
+                repository: repository.full_name.clone(),
+                commit: commit_id.to_string(),
+                target: git_ref.clone(),
+            };
+            let Some(running) = job_queue.start_job(job).await else {
+                return Ok(JobStatus::Skipped {
+                    reason: format!("{commit_id} was already run for {git_ref}"),
+                });
+            };
+            Some(running)
+        };
+
+        let (repository, repository_directory, mut verification) = clone_verified(
+            &args,
+            &github_app,
+            &repository.clone_url,
+            repository.size,
+            Some(&git_ref),
+            commit_id,
+            keyring_file,
+        )
+        .await?;
+        match keyring_file {
+            Some(keyring_file) if args.verify_all_commits => {
+                let ids = pushed_commits(
+                    &repository,
+                    &before,
+                    commit_id,
+                    &commits,
+                    commits_truncated,
+                    args.max_commits,
+                );
+                let verified = ids.and_then(|ids| {
+                    ids.iter().try_fold(verification, |verification, id| {
+                        let result = verify_checkout(&args, &repository, None, id, keyring_file)?;
+                        Ok(match result {
+                            Verification::Failed => result,
+                            _ => verification,
+                        })
+                    })
+                });
+                verification = match verified {
+                    Ok(verification) => verification,
+                    Err(e) => return Err(keep_failed_clone(&args, repository_directory, e)),
+                };
+            }
+            _ => (),
+        }
+        push_permit.verified();
+        events.verified(verification);
+
+        if let (Some(tag_name), Some(pattern)) = (
+            git_ref.strip_prefix("refs/tags/"),
+            &args.tag_message_pattern,
+        ) {
+            verify_tag_message(&repository, tag_name, pattern).map_err(|e| match e {
+                ProcessingError::GitOperation { .. } => DeathReason::RepositoryError {
+                    reason: e.to_string(),
+                },
+                _ => DeathReason::TagPolicy {
+                    reason: e.to_string(),
+                },
+            })?;
+        }
+
+        let files = match payload_files {
+            Some(files) => files,
+            None if args.changed_files_to_stdin
+                || !args.path_filter.is_empty()
+                || matches!(command, PushCommand::Paths(_)) =>
+            {
+                changed_files_for_push(&repository, &before, commit_id, &commits, commits_truncated)
+            }
+            None => vec![],
+        };
+        // Files that could not be determined can't be filtered, so the command is still run
+        if !files.is_empty() && !matches_path_filter(&files, &args.path_filter) {
+            return Ok(JobStatus::Skipped {
+                reason: "no changed files match the path filter".to_string(),
+            });
+        }
+        if let PushCommand::Paths(path_commands) = command {
+            if !files.is_empty() && !path_commands.iter().any(|c| c.matches(&files)) {
+                return Ok(JobStatus::Skipped {
+                    reason: "no changed files match a path command".to_string(),
+                });
+            }
+        }
+
+        let stdin = args.changed_files_to_stdin.then(|| {
+            files
+                .iter()
+                .map(|file| format!("{file}\n"))
+                .collect::<String>()
+        });
+        run_before_command(&args, events, repository_directory.path(), &env).await?;
+        events.publish(Stage::Started);
+        let after = AfterCommand::new(args.0.clone(), &env, repository_directory);
+        let ran = run_push_command(&args, events, command, after.path(), &env, &files, stdin).await;
+        let repository_directory = after.finish(events, &ran).await;
+        let (result, captured) = match (ran, repository_directory) {
+            (Ok(ran), _) => ran,
+            (Err(e), Some(directory)) => return Err(keep_failed_clone(&args, directory, e)),
+            (Err(e), None) => return Err(e),
+        };
+
+        if let Some(job) = job {
+            job.completed();
+        }
+        Ok(JobStatus::Completed {
+            result,
+            output: Some(OutputTail::new(&captured, args.return_output_bytes)),
+        })
+    } else {
+        panic!("must be called with Payload::Push value")
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle_deployment(
+    args: Extension<Arc<Args>>,
+    keyring_files: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    events: &RunEvents,
+    payload: Payload,
+) -> Result<JobStatus, DeathReason> {
+    if let Payload::Deployment {
+        deployment,
+        repository,
+        ..
+    } = payload
+    {
+        let (_, repository_keyrings) =
+            repository_config(&args, &keyring_files, &repository.full_name)?;
+        let command = match &args.deployment_command {
+            Some(command) => command,
             None => {
-                return Err(DeathReason::InvalidWebhook {
-                    field_path: "commits".to_string(),
-                    value: None,
+                return Ok(JobStatus::Skipped {
+                    reason: "no command configured".to_string(),
                 })
             }
         };
-        let repository_url = args
-            .git_repository
-            .as_ref()
-            .unwrap_or(&repository.clone_url);
-        let ssh_key = args.ssh_key.as_ref();
-        let (repository, _repository_directory) = match clone_repository(
-            repository_url,
-            commit.id.as_str(),
-            args.clone_timeout,
-            ssh_key,
+        debug!(?command, environment = ?deployment.environment, "determined deployment to run");
+
+        // Deployments are of commits, so they are verified the same way as pushed commits
+        let keyring_file = configured_keyring(
+            repository_keyrings
+                .and_then(|keyrings| keyrings.commit.as_ref())
+                .or(keyring_files.commit.as_ref()),
+        )?;
+
+        let mut env = WebhookEnv::default();
+        env.set("ENVIRONMENT", &deployment.environment)
+            .map_err(|_| DeathReason::InvalidWebhook {
+                field_path: "deployment.environment".to_string(),
+                value: Some(deployment.environment.clone()),
+            })?;
+        // The deployment ref may be a branch, a tag, or a commit, and is passed as it was sent
+        set_placeholders(
+            &mut env,
+            &deployment.sha,
+            &deployment._ref,
+            &repository.full_name,
+        )?;
+        check_clone_url(&args, &repository.clone_url, "repository.clone_url")?;
+
+        // Hold a job slot until the command has finished
+        let _job_slot = job_queue.acquire().await?;
+
+        // The deployment ref may be a branch, a tag, or a commit, so only the commit is fetched
+        let (_repository, repository_directory, verification) = clone_verified(
+            &args,
+            &github_app,
+            &repository.clone_url,
+            repository.size,
+            None,
+            &deployment.sha,
+            keyring_file,
+        )
+        .await?;
+        events.verified(verification);
+
+        run_before_command(&args, events, repository_directory.path(), &env).await?;
+        events.publish(Stage::Started);
+        events.running(&command.run);
+        let after = AfterCommand::new(args.0.clone(), &env, repository_directory);
+        let output = run_configured(
+            &args,
+            command,
+            after.path(),
+            &env,
+            None,
+            args.command_retries,
         )
         .await
-        {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(DeathReason::FailedClone {
-                    reason: e.to_string(),
+        .map_err(|e| command_failed(&e, e.to_string(), args.return_output_bytes));
+        let repository_directory = after.finish(events, &output).await;
+        let output = output.map_err(|e| match repository_directory {
+            Some(directory) => keep_failed_clone(&args, directory, e),
+            None => e,
+        })?;
+
+        Ok(JobStatus::Completed {
+            result: structured_result(args.command_output_format, &output),
+            output: Some(OutputTail::new(&output.captured, args.return_output_bytes)),
+        })
+    } else {
+        panic!("must be called with Payload::Deployment value")
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle_pull_request(
+    args: Extension<Arc<Args>>,
+    keyring_files: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    events: &RunEvents,
+    payload: Payload,
+) -> Result<JobStatus, DeathReason> {
+    if let Payload::PullRequest {
+        number,
+        pull_request,
+        repository,
+        ..
+    } = payload
+    {
+        repository_config(&args, &keyring_files, &repository.full_name)?;
+        let command = match &args.pr_command {
+            Some(command) => command,
+            None => {
+                return Ok(JobStatus::Skipped {
+                    reason: "no command configured".to_string(),
                 })
             }
         };
+        debug!(?command, number, "determined pull request to run");
 
-        // Rebind keyring path to unwrap the Option<_>
-        if let Some(keyring_file) = keyring_file {
-            let commit = {
-                let oid = Oid::from_str(commit.id.as_str()).map_err(|e| {
-                    DeathReason::RepositoryError {
-                        reason: e.to_string(),
-                    }
-                })?;
-                repository
-                    .find_commit(oid)
-                    .map_err(|e| DeathReason::RepositoryError {
-                        reason: e.to_string(),
-                    })?
-            };
+        // The head commit may have been pushed to a fork, so it is cloned from where it was pushed
+        let Some(head_repository) = &pull_request.head.repo else {
+            return Err(DeathReason::InvalidWebhook {
+                field_path: "pull_request.head.repo".to_string(),
+                value: None,
+            });
+        };
+        let keyring_file = configured_keyring(keyring_files.pull_request.as_ref())?;
+        // Code pushed to forks was not reviewed by anyone with access to the repository
+        let is_fork = !head_repository
+            .full_name
+            .eq_ignore_ascii_case(&repository.full_name);
+        if is_fork && keyring_file.is_none() && !args.pr_allow_forks {
+            return Ok(JobStatus::Skipped {
+                reason: format!(
+                    "pull request from {} is not verified by a pull request keyring",
+                    head_repository.full_name
+                ),
+            });
+        }
 
-            // Keyring directory exists via TempDir
-            let result = verify_commit(commit, keyring_file);
-            result.map_err(|e| DeathReason::KeyringVerification {
-                reason: e.to_string(),
+        let mut env = WebhookEnv::default();
+        env.set("PULL_REQUEST", &number.to_string())
+            .map_err(|_| DeathReason::InvalidWebhook {
+                field_path: "number".to_string(),
+                value: Some(number.to_string()),
             })?;
-        }
+        set_placeholders(
+            &mut env,
+            &pull_request.head.sha,
+            &format!("refs/heads/{}", pull_request.head._ref),
+            &head_repository.full_name,
+        )?;
+        check_clone_url(
+            &args,
+            &head_repository.clone_url,
+            "pull_request.head.repo.clone_url",
+        )?;
 
-        Ok(())
+        // Hold a job slot until the command has finished
+        let _job_slot = job_queue.acquire().await?;
+
+        let (_repository, repository_directory, verification) = clone_verified(
+            &args,
+            &github_app,
+            &head_repository.clone_url,
+            head_repository.size,
+            None,
+            &pull_request.head.sha,
+            keyring_file,
+        )
+        .await?;
+        events.verified(verification);
+
+        run_before_command(&args, events, repository_directory.path(), &env).await?;
+        events.publish(Stage::Started);
+        events.running(&command.run);
+        let after = AfterCommand::new(args.0.clone(), &env, repository_directory);
+        let output = run_configured(
+            &args,
+            command,
+            after.path(),
+            &env,
+            None,
+            args.command_retries,
+        )
+        .await
+        .map_err(|e| command_failed(&e, e.to_string(), args.return_output_bytes));
+        let repository_directory = after.finish(events, &output).await;
+        let output = output.map_err(|e| match repository_directory {
+            Some(directory) => keep_failed_clone(&args, directory, e),
+            None => e,
+        })?;
+
+        Ok(JobStatus::Completed {
+            result: structured_result(args.command_output_format, &output),
+            output: Some(OutputTail::new(&output.captured, args.return_output_bytes)),
+        })
     } else {
-        panic!("must be called with Payload::Push value")
+        panic!("must be called with Payload::PullRequest value")
     }
 }
 
 /// Receive a webhook from a GitHub server indicating a change in code, match upon an event, and
-/// dispatch the JSON blob to a configured script.
+/// dispatch the JSON blob to a configured script. Pushes are decoded by the event in the header,
+/// so that an invalid push is reported by the field that could not be decoded.
 #[instrument(skip_all)]
 #[axum_macros::debug_handler]
 pub(crate) async fn webhook(
     args: Extension<Arc<Args>>,
     keyring_dirs: Extension<Arc<KeyringFiles>>,
-    Json(payload): Json<Payload>,
-) -> Result<Json<()>, Json<DeathReason>> {
-    /*
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    event_sink: Extension<Arc<EventSink>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<JobStatus, DeathReason> {
+    let event = headers.get(GITHUB_EVENT).map(|event| event.as_bytes());
+    let payload = github::decode_payload(event, &body, args.strict_payload)?;
+    run_webhook(
+        args,
+        keyring_dirs,
+        github_app,
+        job_queue,
+        event_sink,
+        headers,
+        payload,
+    )
+    .await
+}
+
+/// Run the command for a webhook, in the form that GitHub sends. Pushes, deployments, and pull
+/// requests that are opened or synchronized are reported to the event sink as they are received,
+/// verified, started, and finished. With `--async-processing`, they are queued to be run in the
+/// background once received, and accepted without waiting for the result.
+async fn run_webhook(
+    args: Extension<Arc<Args>>,
+    keyring_dirs: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    Extension(event_sink): Extension<Arc<EventSink>>,
+    headers: HeaderMap,
+    payload: Payload,
+) -> Result<JobStatus, DeathReason> {
+    let events = match &payload {
+        Payload::Push {
+            _ref,
+            after,
+            commits,
+            head_commit,
+            repository,
+            ..
+        } => RunEvents::new(
+            event_sink,
+            &headers,
+            "push",
+            &repository.full_name,
+            _ref,
+            push_target(commits, head_commit, after),
+        ),
+        Payload::Deployment {
+            deployment,
+            repository,
+            ..
+        } => RunEvents::new(
+            event_sink,
+            &headers,
+            "deployment",
+            &repository.full_name,
+            &deployment._ref,
+            &deployment.sha,
+        ),
+        Payload::PullRequest {
+            action,
+            pull_request,
+            repository,
+            ..
+        } if action == "opened" || action == "synchronize" => RunEvents::new(
+            event_sink,
+            &headers,
+            "pull_request",
+            &repository.full_name,
+            &pull_request.head._ref,
+            &pull_request.head.sha,
+        ),
+        Payload::PullRequest { action, .. } => {
+            return Ok(JobStatus::Skipped {
+                reason: format!("pull request was {action}, not opened or synchronized"),
+            })
+        }
+        _ => {
+            return Ok(JobStatus::Skipped {
+                reason: "event is not a push, deployment, or pull request".to_string(),
+            })
+        }
+    };
+    events.publish(Stage::Received);
+
+    if args.async_processing {
+        let delivery = events.correlation_id().to_string();
+        let queue = job_queue.0.clone();
+        let events = Arc::new(events);
+        let processing = events.clone();
+        let queued = queue.enqueue(
+            async move {
+                let result = catch_panic(handle_payload(
+                    args,
+                    keyring_dirs,
+                    github_app,
+                    job_queue,
+                    &processing,
+                    payload,
+                ))
+                .await;
+                processing.finish(&result);
+            }
+            .in_current_span(),
+        );
+        // Webhooks that were not queued are finished right away, so their result is still reported
+        if let Err(reason) = queued {
+            let result = Err(reason);
+            events.finish(&result);
+            return result;
+        }
+        return Ok(JobStatus::Accepted { delivery });
+    }
+
+    let return_output = args.return_output;
+    let result = handle_payload(args, keyring_dirs, github_app, job_queue, &events, payload).await;
+    events.finish(&result);
+    if return_output {
+        result
+    } else {
+        result
+            .map(JobStatus::without_output)
+            .map_err(DeathReason::without_output)
+    }
+}
+
+/// Run the handler of the kind of payload, whose events have already been received.
+async fn handle_payload(
+    args: Extension<Arc<Args>>,
+    keyring_dirs: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    events: &RunEvents,
+    payload: Payload,
+) -> Result<JobStatus, DeathReason> {
     match payload {
-        Payload::Push { .. } => {
-            return Ok(Json(handle_push(args, keyring_dirs, payload).await?));
+        Payload::Deployment { .. } => {
+            handle_deployment(args, keyring_dirs, github_app, job_queue, events, payload).await
+        }
+        Payload::PullRequest { .. } => {
+            handle_pull_request(args, keyring_dirs, github_app, job_queue, events, payload).await
+        }
+        _ => handle_push(args, keyring_dirs, github_app, job_queue, events, payload).await,
+    }
+}
+
+/// Receive a webhook from a GitLab server, converting pushes to the payload that GitHub would have
+/// sent for them.
+#[instrument(skip_all)]
+#[axum_macros::debug_handler]
+pub(crate) async fn gitlab_webhook(
+    args: Extension<Arc<Args>>,
+    keyring_dirs: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    event_sink: Extension<Arc<EventSink>>,
+    headers: HeaderMap,
+    Json(payload): Json<GitLabPayload>,
+) -> Result<JobStatus, DeathReason> {
+    let payload = match payload {
+        GitLabPayload::Push(push) | GitLabPayload::TagPush(push) => Payload::from(push),
+        GitLabPayload::Other => {
+            return Ok(JobStatus::Skipped {
+                reason: "event is not a push".to_string(),
+            })
+        }
+    };
+    run_webhook(
+        args,
+        keyring_dirs,
+        github_app,
+        job_queue,
+        event_sink,
+        headers,
+        payload,
+    )
+    .await
+}
+
+/// Receive a webhook from a Gitea or Forgejo server, converting pushes to the payload that GitHub
+/// would have sent for them. Gitea only identifies the kind of event with a header, so the body is
+/// only parsed once the event is known to be a push.
+#[instrument(skip_all)]
+#[axum_macros::debug_handler]
+pub(crate) async fn gitea_webhook(
+    args: Extension<Arc<Args>>,
+    keyring_dirs: Extension<Arc<KeyringFiles>>,
+    github_app: Extension<Option<Arc<GitHubApp>>>,
+    job_queue: Extension<Arc<JobQueue>>,
+    event_sink: Extension<Arc<EventSink>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<JobStatus, DeathReason> {
+    if headers.get(GITEA_EVENT).map(|event| event.as_bytes()) != Some(b"push") {
+        return Ok(JobStatus::Skipped {
+            reason: "event is not a push".to_string(),
+        });
+    }
+    let push: gitea::Push =
+        serde_json::from_slice(&body).map_err(|e| DeathReason::InvalidWebhook {
+            field_path: format!("line {} column {}", e.line(), e.column()),
+            value: Some(e.to_string()),
+        })?;
+    run_webhook(
+        args,
+        keyring_dirs,
+        github_app,
+        job_queue,
+        event_sink,
+        headers,
+        Payload::from(push),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+
+    use crate::test_util::push_webhook;
+
+    #[tokio::test]
+    async fn will_error_on_keyring_that_failed_to_load() {
+        let args = Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "true",
+            "--commit-keyring",
+            "/nonexistent",
+            "--fail-closed-on-keyring-error=false",
+        ]);
+        let keyrings = KeyringFiles {
+            commit: Some(Err("no such file or directory".to_string())),
+            ..KeyringFiles::default()
+        };
+        let payload = Payload::Push {
+            after: String::new(),
+            base_ref: None,
+            before: String::new(),
+            commits: vec![CommitStats::default()],
+            commits_truncated: false,
+            compare: String::new(),
+            created: false,
+            deleted: false,
+            forced: false,
+            head_commit: CommitStats::default(),
+            pusher: Default::default(),
+            _ref: "refs/heads/main".to_string(),
+            repository: Default::default(),
+            sender: Default::default(),
+        };
+
+        let events = RunEvents::new(Default::default(), &HeaderMap::new(), "push", "", "", "");
+        let result = handle_push(
+            Extension(Arc::new(args)),
+            Extension(Arc::new(keyrings)),
+            Extension(None),
+            Extension(Arc::new(JobQueue::new(1, Duration::from_secs(1)))),
+            &events,
+            payload,
+        )
+        .await;
+        match result {
+            Err(DeathReason::KeyringError { .. }) => (),
+            e => panic!("incorrect result from handle_push: {e:?}"),
         }
-        _ => {}
     }
-    */
-    if let Payload::Push { .. } = payload {
-        return Ok(Json(handle_push(args, keyring_dirs, payload).await?));
+
+    #[tokio::test]
+    async fn can_retry_failed_command() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        // Fails on the first two attempts, counting them in a file of the directory it runs in
+        let command = CommandConfig {
+            run: "n=$(($(cat attempts 2>/dev/null || echo 0) + 1)); echo $n > attempts; \
+                  echo attempt $n; test $n -ge 3"
+                .to_string(),
+            ..CommandConfig::default()
+        };
+        let run = |retries: &str, exit_codes: &str| {
+            let args = Args::parse_from([
+                "webhook-runner",
+                "--command-retries",
+                retries,
+                "--command-retry-delay",
+                "0",
+                "--command-retry-exit-codes",
+                exit_codes,
+            ]);
+            std::fs::remove_file(dir.path().join("attempts")).ok();
+            let (dir, command) = (dir.path().to_path_buf(), command.clone());
+            async move {
+                run_configured(
+                    &args,
+                    &command,
+                    &dir,
+                    &WebhookEnv::default(),
+                    None,
+                    args.command_retries,
+                )
+                .await
+            }
+        };
+
+        let output = run("2", "1").await.expect("command was not retried");
+        assert_eq!(output.last_line.as_deref(), Some("attempt 3"));
+
+        match run("1", "1").await {
+            Err(ProcessingError::Command { exit_code, .. }) => assert_eq!(exit_code, 1),
+            e => panic!("command succeeded without enough retries: {e:?}"),
+        }
+        match run("2", "2").await {
+            Err(ProcessingError::Command { output, .. }) => {
+                assert_eq!(output.stdout, b"attempt 1\n");
+            }
+            e => panic!("command was retried after another exit code: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn will_verify_deployment_with_commit_keyring() {
+        let args = Args::parse_from([
+            "webhook-runner",
+            "--deployment-command",
+            "true",
+            "--commit-keyring",
+            "/nonexistent",
+            "--fail-closed-on-keyring-error=false",
+        ]);
+        let keyrings = KeyringFiles {
+            commit: Some(Err("no such file or directory".to_string())),
+            ..KeyringFiles::default()
+        };
+        let events = RunEvents::new(Default::default(), &HeaderMap::new(), "push", "", "", "");
+        let deploy = |args: Args, keyrings: KeyringFiles, full_name: &str| {
+            let payload = Payload::Deployment {
+                deployment: Default::default(),
+                repository: webhook_runner_lib::payload::Repository {
+                    full_name: full_name.to_string(),
+                    ..Default::default()
+                },
+                sender: Default::default(),
+            };
+            handle_deployment(
+                Extension(Arc::new(args)),
+                Extension(Arc::new(keyrings)),
+                Extension(None),
+                Extension(Arc::new(JobQueue::new(1, Duration::from_secs(1)))),
+                &events,
+                payload,
+            )
+        };
+        match deploy(args, keyrings, "octocat/hello-world").await {
+            Err(DeathReason::KeyringError { .. }) => (),
+            e => panic!("incorrect result from handle_deployment: {e:?}"),
+        }
+
+        // Deployments of individually configured repositories use the keyrings of the repository
+        let mut args = Args::parse_from(["webhook-runner", "--deployment-command", "true"]);
+        args.repositories
+            .insert("octocat/hello-world".to_string(), Default::default());
+        let keyrings = KeyringFiles {
+            repositories: [(
+                "octocat/hello-world".to_string(),
+                KeyringFiles {
+                    commit: Some(Err("no such file or directory".to_string())),
+                    ..KeyringFiles::default()
+                },
+            )]
+            .into(),
+            ..KeyringFiles::default()
+        };
+        match deploy(args, keyrings, "octocat/hello-world").await {
+            Err(DeathReason::KeyringError { .. }) => (),
+            e => panic!("keyring of repository was not used: {e:?}"),
+        }
+
+        let mut args = Args::parse_from(["webhook-runner", "--deployment-command", "true"]);
+        args.repositories
+            .insert("octocat/hello-world".to_string(), Default::default());
+        match deploy(args, KeyringFiles::default(), "octocat/unknown").await {
+            Err(DeathReason::UnconfiguredRepository { repository }) => {
+                assert_eq!(repository, "octocat/unknown");
+            }
+            e => panic!("deployment of unconfigured repository was run: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_run_unverified_commit_in_audit_mode() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let oid = repository.commit;
+
+        let keyring_dir = TempDir::new("webhook-runner-test").unwrap();
+        let keyring = Arc::new(std::sync::RwLock::new(
+            KeyringFile::from_path(keyring_dir.path()).unwrap(),
+        ));
+        let url = repository.clone_url();
+
+        let clone = |mode: &str| {
+            let args = Args::parse_from(["webhook-runner", "--verify-mode", mode]);
+            let keyring = keyring.clone();
+            let oid = oid.to_string();
+            async move {
+                clone_verified(&args, &None, &url, None, None, &oid, Some(&keyring))
+                    .await
+                    .map(drop)
+            }
+        };
+        match clone("enforce").await {
+            Err(DeathReason::KeyringVerification { .. }) => (),
+            e => panic!("incorrect result from clone_verified: {e:?}"),
+        }
+        clone("audit")
+            .await
+            .expect("unverified commit was rejected in audit mode");
+    }
+
+    #[test]
+    fn can_find_target_of_tag_push_without_commits() {
+        let payload = include_str!("testdata/github_tag_push.json");
+        let (commits, head_commit, after) = match serde_json::from_str(payload) {
+            Ok(Payload::Push {
+                commits,
+                head_commit,
+                after,
+                ..
+            }) => (commits, head_commit, after),
+            e => panic!("incorrect payload decoded: {e:?}"),
+        };
+        assert!(commits.is_empty());
+        assert_eq!(
+            push_target(&commits, &head_commit, &after),
+            "7fd1a60b01f91b314f59955a4e4d4e80d8edf11d"
+        );
+        assert_eq!(
+            push_target(&commits, &CommitStats::default(), &after),
+            after,
+            "push without a head commit did not fall back to after"
+        );
+    }
+
+    #[test]
+    fn can_list_pushed_commits() {
+        let mut repository = crate::test_util::TestRepository::new(None);
+        let before = repository.commit.to_string();
+        let first = repository.commit(None).to_string();
+        let after = repository.commit(None).to_string();
+        let repo = repository.open();
+        let listed = |id: &str| CommitStats {
+            id: id.to_string(),
+            ..CommitStats::default()
+        };
+        let commits = [listed(&first), listed(&after)];
+
+        assert_eq!(
+            pushed_commits(&repo, &before, &after, &commits, false, None).unwrap(),
+            vec![first.clone()]
+        );
+        // Commits left out of the payload are listed from the repository
+        assert_eq!(
+            pushed_commits(&repo, &before, &after, &commits[1..], true, None).unwrap(),
+            vec![first.clone()]
+        );
+        let zero = Oid::zero().to_string();
+        assert!(matches!(
+            pushed_commits(&repo, &zero, &after, &commits[1..], true, None),
+            Err(DeathReason::KeyringVerification { .. })
+        ));
+        assert!(matches!(
+            pushed_commits(&repo, &before, &after, &commits[1..], true, Some(1)),
+            Err(DeathReason::TooManyCommits { commits: 2, max: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn can_skip_deleted_refs() {
+        let args = Arc::new(Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "true",
+        ]));
+        let delete = |deleted: bool| {
+            let mut json: serde_json::Value =
+                serde_json::from_str(include_str!("testdata/github_tag_push.json")).unwrap();
+            json["ref"] = "refs/heads/feature".into();
+            json["before"] = json["after"].clone();
+            json["after"] = Oid::zero().to_string().into();
+            json["deleted"] = deleted.into();
+            json["head_commit"] = serde_json::Value::Null;
+            json["commits"] = serde_json::json!([]);
+            // Cloning would fail, since there is no repository to clone
+            json["repository"]["clone_url"] = "/nonexistent/repository".into();
+            push_webhook(
+                args.clone(),
+                Arc::new(JobQueue::new(1, Duration::from_secs(1))),
+                serde_json::to_vec(&json).unwrap(),
+            )
+        };
+
+        // A push with an `after` of all zeros is a delete even if it is not marked as one
+        for deleted in [true, false] {
+            match delete(deleted).await {
+                Ok(JobStatus::Skipped { reason }) => {
+                    assert_eq!(reason, "ref refs/heads/feature was deleted")
+                }
+                e => panic!("deleted ref was not skipped: {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn can_skip_push_delivered_again() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let args = Arc::new(Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "true",
+        ]));
+        let job_queue = Arc::new(
+            JobQueue::new(1, Duration::from_secs(1)).with_completed_jobs(
+                Box::<crate::jobs::MemoryJobStore>::default(),
+                Duration::from_secs(60),
+            ),
+        );
+        let push = || {
+            push_webhook(
+                args.clone(),
+                job_queue.clone(),
+                repository.push_body("refs/heads/main"),
+            )
+        };
+
+        match push().await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("first delivery did not run: {e:?}"),
+        }
+        match push().await {
+            Ok(JobStatus::Skipped { reason }) => assert_eq!(
+                reason,
+                format!("{} was already run for refs/heads/main", repository.commit)
+            ),
+            e => panic!("second delivery was not skipped: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_skip_push_delivered_while_running() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let args = Arc::new(Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            "sleep 0.5",
+        ]));
+        let job_queue = Arc::new(
+            JobQueue::new(2, Duration::from_secs(1)).with_completed_jobs(
+                Box::<crate::jobs::MemoryJobStore>::default(),
+                Duration::from_secs(60),
+            ),
+        );
+        let push = || {
+            push_webhook(
+                args.clone(),
+                job_queue.clone(),
+                repository.push_body("refs/heads/main"),
+            )
+        };
+
+        // Both deliveries get a job slot, but only one of them runs the command
+        let (first, second) = tokio::join!(push(), push());
+        let skipped = [first, second]
+            .into_iter()
+            .filter(|status| match status {
+                Ok(JobStatus::Completed { .. }) => false,
+                Ok(JobStatus::Skipped { reason }) => {
+                    assert_eq!(
+                        reason,
+                        &format!("{} was already run for refs/heads/main", repository.commit)
+                    );
+                    true
+                }
+                e => panic!("delivery did not run: {e:?}"),
+            })
+            .count();
+        assert_eq!(
+            skipped, 1,
+            "concurrent deliveries of a push were not run once"
+        );
+    }
+
+    #[tokio::test]
+    async fn can_allow_clone_urls_of_configured_hosts() {
+        let args = Args::parse_from(["webhook-runner", "--allowed-clone-host", "github.com"]);
+        for url in [
+            "https://github.com/octocat/hello-world.git",
+            "https://GITHUB.COM/octocat/hello-world.git",
+            "git@github.com:octocat/hello-world.git",
+        ] {
+            assert!(
+                check_clone_url(&args, url, "repository.clone_url").is_ok(),
+                "{url}"
+            );
+        }
+        for url in [
+            "https://github.com.example.com/octocat/hello-world.git",
+            "https://169.254.169.254/latest/meta-data",
+            "/srv/git/hello-world.git",
+        ] {
+            match check_clone_url(&args, url, "repository.clone_url") {
+                Err(DeathReason::InvalidWebhook { field_path, value }) => {
+                    assert_eq!(field_path, "repository.clone_url");
+                    assert_eq!(value.as_deref(), Some(url));
+                }
+                e => panic!("{url} was not rejected: {e:?}"),
+            }
+        }
+
+        let repository = crate::test_util::TestRepository::new(None);
+        let push = |args: Vec<String>, clone_url: &str| {
+            let mut json: serde_json::Value =
+                serde_json::from_str(&repository.push_body("refs/heads/main")).unwrap();
+            json["repository"]["clone_url"] = clone_url.into();
+            push_webhook(
+                Arc::new(Args::parse_from(args)),
+                Arc::new(JobQueue::new(1, Duration::from_secs(1))),
+                serde_json::to_vec(&json).unwrap(),
+            )
+        };
+        let args = [
+            "webhook-runner",
+            "--commit-command",
+            "true",
+            "--allowed-clone-host",
+            "github.com",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        match push(args.clone(), &repository.clone_url()).await {
+            Err(DeathReason::InvalidWebhook { field_path, .. }) => {
+                assert_eq!(field_path, "repository.clone_url")
+            }
+            e => panic!("clone url of other host was not rejected: {e:?}"),
+        }
+        // The configured repository is cloned whatever the URL of the webhook
+        let mut with_repository = args;
+        with_repository.extend(["--git-repository".to_string(), repository.clone_url()]);
+        match push(with_repository, "https://attacker.example/repo.git").await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("configured repository was not cloned: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_veto_command_with_before_command() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let marker_dir = TempDir::new("webhook-runner-test").unwrap();
+        let marker = marker_dir.path().join("ran");
+        let attempts = marker_dir.path().join("attempts");
+        let push = |before_command: &str| {
+            // A veto is not retried, even if the command is
+            let args = Args::parse_from([
+                "webhook-runner",
+                "--return-output",
+                "--command-retries",
+                "2",
+                "--command-retry-delay",
+                "0",
+                "--before-command",
+                before_command,
+                "--commit-command",
+                &format!("touch {}", marker.display()),
+            ]);
+            push_webhook(
+                Arc::new(args),
+                Arc::new(JobQueue::new(1, Duration::from_secs(1))),
+                repository.push_body("refs/heads/main"),
+            )
+        };
+
+        let veto = format!(
+            "echo >> {}; echo denied by policy; exit 3",
+            attempts.display()
+        );
+        match push(&veto).await {
+            Err(DeathReason::PreCommandVeto {
+                exit_code, output, ..
+            }) => {
+                assert_eq!(exit_code, 3);
+                let output = serde_json::to_value(output).unwrap();
+                assert_eq!(output["stdout"], "denied by policy\n");
+            }
+            e => panic!("command was not vetoed: {e:?}"),
+        }
+        assert!(!marker.exists(), "vetoed command was run");
+        assert_eq!(std::fs::read_to_string(&attempts).unwrap(), "\n");
+
+        // The before command runs in the cloned repository
+        match push("git rev-parse HEAD").await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("command was not run after before command: {e:?}"),
+        }
+        assert!(marker.exists(), "command was not run");
+    }
+
+    #[tokio::test]
+    async fn can_run_after_command_whatever_the_result() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let marker_dir = TempDir::new("webhook-runner-test").unwrap();
+        let marker = marker_dir.path().join("exit");
+        let push = |command: &str| {
+            // The after command failing must not fail the webhook
+            let args = Args::parse_from([
+                "webhook-runner",
+                "--commit-command",
+                command,
+                "--after-command",
+                &format!("echo $WEBHOOK_COMMAND_EXIT > {}; exit 1", marker.display()),
+            ]);
+            push_webhook(
+                Arc::new(args),
+                Arc::new(JobQueue::new(1, Duration::from_secs(1))),
+                repository.push_body("refs/heads/main"),
+            )
+        };
+
+        match push("true").await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("incorrect result of successful command: {e:?}"),
+        }
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "0\n");
+
+        match push("exit 3").await {
+            Err(DeathReason::CommandFailed { exit_code, .. }) => assert_eq!(exit_code, Some(3)),
+            e => panic!("incorrect result of failed command: {e:?}"),
+        }
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "3\n");
+    }
+
+    #[tokio::test]
+    async fn can_run_after_command_when_webhook_is_dropped() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let marker_dir = TempDir::new("webhook-runner-test").unwrap();
+        let started = marker_dir.path().join("started");
+        let marker = marker_dir.path().join("exit");
+        let args = Args::parse_from([
+            "webhook-runner",
+            "--commit-command",
+            &format!("touch {}; sleep 30", started.display()),
+            "--after-command",
+            &format!("echo $WEBHOOK_COMMAND_EXIT > {}", marker.display()),
+        ]);
+        let mut webhook = Box::pin(push_webhook(
+            Arc::new(args),
+            Arc::new(JobQueue::new(1, Duration::from_secs(1))),
+            repository.push_body("refs/heads/main"),
+        ));
+
+        // Drop the webhook once its command has started, as a request timeout would
+        tokio::select! {
+            result = &mut webhook => panic!("command finished before it was dropped: {result:?}"),
+            _ = async {
+                while !started.exists() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            } => (),
+        }
+        drop(webhook);
+
+        for _ in 0..500 {
+            if let Ok(exit) = std::fs::read_to_string(&marker) {
+                if exit.ends_with('\n') {
+                    assert_eq!(exit, "-1\n");
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("after command was not run");
+    }
+
+    #[tokio::test]
+    async fn can_keep_clone_of_failed_command() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let keep_dir = TempDir::new("webhook-runner-test").unwrap();
+        let marker_dir = TempDir::new("webhook-runner-test").unwrap();
+        let marker = marker_dir.path().join("cwd");
+        let push = |command: &str| {
+            let args = Args::parse_from([
+                "webhook-runner",
+                "--keep-failed-clones",
+                "--clone-keep-dir",
+                keep_dir.path().to_str().unwrap(),
+                "--commit-command",
+                &format!("pwd > {}; {command}", marker.display()),
+            ]);
+            push_webhook(
+                Arc::new(args),
+                Arc::new(JobQueue::new(1, Duration::from_secs(1))),
+                repository.push_body("refs/heads/main"),
+            )
+        };
+        let cloned_into =
+            || std::path::PathBuf::from(std::fs::read_to_string(&marker).unwrap().trim());
+
+        match push("exit 3").await {
+            Err(DeathReason::CommandFailed { kept_clone, .. }) => {
+                let kept_clone = std::path::PathBuf::from(kept_clone.unwrap());
+                assert!(kept_clone.starts_with(keep_dir.path()), "{kept_clone:?}");
+                assert!(kept_clone.join(".git").is_dir(), "clone was not kept");
+                assert!(!cloned_into().exists(), "clone was not moved");
+            }
+            e => panic!("incorrect result of failed command: {e:?}"),
+        }
+
+        match push("true").await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("incorrect result of successful command: {e:?}"),
+        }
+        assert!(
+            !cloned_into().exists(),
+            "clone of successful command was kept"
+        );
+        assert_eq!(std::fs::read_dir(keep_dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn can_process_webhook_in_background() {
+        let repository = crate::test_util::TestRepository::new(None);
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let marker = dir.path().join("ran");
+        let history = dir.path().join("history.jsonl");
+        let args = Args::parse_from([
+            "webhook-runner",
+            "--async-processing",
+            "--history-file",
+            history.to_str().unwrap(),
+            "--commit-command",
+            &format!("sleep 1; touch {}", marker.display()),
+        ]);
+
+        let started = std::time::Instant::now();
+        let result = push_webhook(
+            Arc::new(args),
+            Arc::new(JobQueue::new(1, Duration::from_secs(1)).with_workers(1, 1)),
+            repository.push_body("refs/heads/main"),
+        )
+        .await;
+        match result {
+            Ok(status @ JobStatus::Accepted { .. }) => {
+                assert_eq!(
+                    status.clone(),
+                    JobStatus::Accepted {
+                        delivery: "72d3162e".to_string()
+                    }
+                );
+                let response = axum::response::IntoResponse::into_response(status);
+                assert_eq!(response.status(), axum::http::StatusCode::ACCEPTED);
+            }
+            e => panic!("webhook was not accepted: {e:?}"),
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "webhook waited for the command"
+        );
+        assert!(!marker.exists(), "command finished before the response");
+
+        // The result is only reported once the command has finished in the background
+        let deadline = started + Duration::from_secs(30);
+        let record = loop {
+            let written = std::fs::read_to_string(&history).unwrap_or_default();
+            if let Some(line) = written.lines().next() {
+                break serde_json::from_str::<serde_json::Value>(line).unwrap();
+            }
+            assert!(
+                std::time::Instant::now() < deadline,
+                "webhook never finished"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(record["correlation_id"], "72d3162e");
+        assert_eq!(record["status"], "completed", "{record}");
+        assert!(marker.exists(), "command did not run");
+    }
+
+    #[tokio::test]
+    async fn can_route_pushes_by_repository() {
+        let repository = crate::test_util::TestRepository::new(None);
+
+        let config_dir = TempDir::new("webhook-runner-test").unwrap();
+        let config = config_dir.path().join("config.toml");
+        std::fs::write(
+            &config,
+            r#"
+            command_output_format = "json-last-line"
+            tag_command = "echo '{\"command\": \"global\"}'"
+
+            [repositories."octocat/first"]
+            commit_command = "echo '{\"command\": \"first\"}'"
+
+            [repositories."octocat/second"]
+            commit_command = "echo '{\"command\": \"second\"}'"
+            refs = ["refs/heads/main", "refs/tags/*"]
+            "#,
+        )
+        .unwrap();
+        let args = Arc::new(
+            Args::parse_with_config(["webhook-runner", "--config", config.to_str().unwrap()])
+                .unwrap(),
+        );
+
+        let push = |full_name: &str, git_ref: &str| {
+            let mut push: serde_json::Value =
+                serde_json::from_str(&repository.push_body(git_ref)).unwrap();
+            push["repository"]["full_name"] = full_name.into();
+            let job_queue = Arc::new(JobQueue::new(1, Duration::from_secs(1)));
+            push_webhook(args.clone(), job_queue, push.to_string())
+        };
+        let command = |status| match status {
+            Ok(JobStatus::Completed {
+                result: Some(result),
+                ..
+            }) => result["command"].as_str().unwrap().to_string(),
+            e => panic!("incorrect result from handle_push: {e:?}"),
+        };
+
+        let status = push("octocat/first", "refs/heads/main").await;
+        assert_eq!(command(status), "first");
+        let status = push("octocat/second", "refs/heads/main").await;
+        assert_eq!(command(status), "second");
+        // Options that a repository doesn't set fall back to the options of the runner
+        let status = push("octocat/second", "refs/tags/v1.0.0").await;
+        assert_eq!(command(status), "global");
+
+        match push("octocat/second", "refs/heads/other").await {
+            Ok(JobStatus::Skipped { .. }) => (),
+            e => panic!("incorrect result from handle_push: {e:?}"),
+        }
+        match push("octocat/unknown", "refs/heads/main").await {
+            Err(DeathReason::UnconfiguredRepository { repository }) => {
+                assert_eq!(repository, "octocat/unknown");
+            }
+            e => panic!("incorrect result from handle_push: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_run_commands_for_changed_paths() {
+        let repository = crate::test_util::TestRepository::new(None);
+
+        let config_dir = TempDir::new("webhook-runner-test").unwrap();
+        let config = config_dir.path().join("config.toml");
+        let ran = config_dir.path().join("ran");
+        std::fs::write(
+            &config,
+            r#"
+            command_output_format = "json-last-line"
+
+            [path_commands]
+            "frontend/**" = "echo frontend >> RAN && echo '{\"command\": \"frontend\"}'"
+            "docs/**" = { run = "sleep 10", timeout = 1 }
+            "backend/**" = "echo backend >> RAN && echo '{\"command\": \"backend\"}'"
+            "#
+            .replace("RAN", ran.to_str().unwrap()),
+        )
+        .unwrap();
+        let args = Arc::new(
+            Args::parse_with_config(["webhook-runner", "--config", config.to_str().unwrap()])
+                .unwrap(),
+        );
+
+        let push = |files: &[&str]| {
+            let mut push: serde_json::Value =
+                serde_json::from_str(&repository.push_body("refs/heads/main")).unwrap();
+            push["commits"][0]["modified"] = files.into();
+            let job_queue = Arc::new(JobQueue::new(1, Duration::from_secs(1)));
+            push_webhook(args.clone(), job_queue, push.to_string())
+        };
+        let result = |status| match status {
+            Ok(JobStatus::Completed {
+                result: Some(result),
+                ..
+            }) => result,
+            e => panic!("incorrect result from handle_push: {e:?}"),
+        };
+
+        let status = push(&["frontend/src/main.ts"]).await;
+        assert_eq!(
+            result(status),
+            serde_json::json!({"frontend/**": {"command": "frontend"}})
+        );
+        std::fs::remove_file(&ran).unwrap();
+        let status = push(&["backend/src/main.rs", "frontend/src/main.ts"]).await;
+        assert_eq!(
+            result(status),
+            serde_json::json!({
+                "backend/**": {"command": "backend"},
+                "frontend/**": {"command": "frontend"},
+            })
+        );
+        // Commands are run in the order of the config file
+        assert_eq!(
+            std::fs::read_to_string(&ran).unwrap(),
+            "frontend\nbackend\n"
+        );
+
+        match push(&["README.md"]).await {
+            Ok(JobStatus::Skipped { .. }) => (),
+            e => panic!("incorrect result from handle_push: {e:?}"),
+        }
+        // The first command that fails, here by running past its own timeout, stops the commands
+        // after it from running, and the results of the commands before it are kept
+        std::fs::remove_file(&ran).unwrap();
+        match push(&[
+            "docs/index.md",
+            "frontend/src/main.ts",
+            "backend/src/main.rs",
+        ])
+        .await
+        {
+            Err(DeathReason::CommandFailed { reason, result, .. }) => {
+                assert!(reason.starts_with("command for docs/**: "), "{reason}");
+                assert_eq!(
+                    result,
+                    Some(Box::new(
+                        serde_json::json!({"frontend/**": {"command": "frontend"}})
+                    ))
+                );
+            }
+            e => panic!("incorrect result from handle_push: {e:?}"),
+        }
+        assert_eq!(std::fs::read_to_string(&ran).unwrap(), "frontend\n");
+    }
+
+    #[test]
+    fn can_decode_pull_request_payload() {
+        let payload = include_str!("testdata/github_pull_request.json");
+        match serde_json::from_str(payload) {
+            Ok(Payload::PullRequest {
+                action,
+                number,
+                pull_request,
+                repository,
+                ..
+            }) => {
+                assert_eq!(action, "opened");
+                assert_eq!(number, 1347);
+                assert_eq!(repository.full_name, "octocat/Hello-World");
+                assert_eq!(
+                    pull_request.head.sha,
+                    "6dcb09b5b57875f334f61aebed695e2e4193db5e"
+                );
+                // The head commit was pushed to a fork
+                assert_eq!(
+                    pull_request.head.repo.unwrap().clone_url,
+                    "https://github.com/hubot/Hello-World.git"
+                );
+            }
+            e => panic!("incorrect payload decoded: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_route_pull_requests_by_action() {
+        let repository = crate::test_util::TestRepository::new(None);
+
+        let args = Arc::new(Args::parse_from([
+            "webhook-runner",
+            "--pr-command",
+            r#"echo "{\"pull_request\": $WEBHOOK_PULL_REQUEST}""#,
+            "--command-output-format",
+            "json-last-line",
+            "--pr-allow-forks",
+        ]));
+        let pull_request_from = |args: &Arc<Args>, action: &str, head: &str| {
+            let mut json: serde_json::Value =
+                serde_json::from_str(include_str!("testdata/github_pull_request.json")).unwrap();
+            json["action"] = action.into();
+            json["pull_request"]["head"]["repo"]["full_name"] = head.into();
+            json["pull_request"]["head"]["sha"] = repository.commit.to_string().into();
+            json["pull_request"]["head"]["repo"]["clone_url"] = repository.clone_url().into();
+            webhook(
+                Extension(args.clone()),
+                Extension(Arc::new(KeyringFiles::default())),
+                Extension(None),
+                Extension(Arc::new(JobQueue::new(1, Duration::from_secs(1)))),
+                Extension(Arc::new(EventSink::default())),
+                HeaderMap::new(),
+                Bytes::from(serde_json::to_vec(&json).unwrap()),
+            )
+        };
+        let pull_request =
+            |args: &Arc<Args>, action: &str| pull_request_from(args, action, "hubot/Hello-World");
+
+        // The output of the command is left out unless asked for
+        for action in ["opened", "synchronize"] {
+            match pull_request(&args, action).await {
+                Ok(JobStatus::Completed {
+                    result: Some(result),
+                    output: None,
+                }) => assert_eq!(result, serde_json::json!({"pull_request": 1347})),
+                e => panic!("incorrect result for {action} pull request: {e:?}"),
+            }
+        }
+        let output_args = Arc::new(Args::parse_from([
+            "webhook-runner",
+            "--pr-command",
+            r#"echo "{\"pull_request\": $WEBHOOK_PULL_REQUEST}""#,
+            "--return-output",
+            "--return-output-bytes",
+            "8",
+            "--pr-allow-forks",
+        ]));
+        match pull_request(&output_args, "opened").await {
+            Ok(status @ JobStatus::Completed { .. }) => assert_eq!(
+                serde_json::to_value(status).unwrap()["output"],
+                serde_json::json!({"stdout": ": 1347}\n", "stderr": "", "truncated": true})
+            ),
+            e => panic!("incorrect result for opened pull request: {e:?}"),
+        }
+        match pull_request(&args, "closed").await {
+            Ok(JobStatus::Skipped { .. }) => (),
+            e => panic!("incorrect result for closed pull request: {e:?}"),
+        }
+
+        // Unverified pull requests from forks only run when allowed
+        let args = Arc::new(Args::parse_from(["webhook-runner", "--pr-command", "true"]));
+        match pull_request_from(&args, "opened", "hubot/Hello-World").await {
+            Ok(JobStatus::Skipped { reason }) => assert_eq!(
+                reason,
+                "pull request from hubot/Hello-World is not verified by a pull request keyring"
+            ),
+            e => panic!("pull request from fork was run: {e:?}"),
+        }
+        match pull_request_from(&args, "opened", "octocat/Hello-World").await {
+            Ok(JobStatus::Completed { .. }) => (),
+            e => panic!("pull request from repository was not run: {e:?}"),
+        }
+
+        // Pull requests to repositories that aren't configured are rejected
+        let mut unconfigured = Args::parse_from(["webhook-runner", "--pr-command", "true"]);
+        unconfigured
+            .repositories
+            .insert("octocat/other".to_string(), Default::default());
+        match pull_request_from(&Arc::new(unconfigured), "opened", "octocat/Hello-World").await {
+            Err(DeathReason::UnconfiguredRepository { repository }) => {
+                assert_eq!(repository, "octocat/Hello-World")
+            }
+            e => panic!("pull request to unconfigured repository was run: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn will_reject_malformed_refs() {
+        for git_ref in [
+            "refs/heads/main",
+            "refs/heads/release/1.0",
+            "refs/tags/v1.0.0",
+        ] {
+            assert!(check_ref(git_ref).is_ok(), "{git_ref} was rejected");
+        }
+        for git_ref in [
+            "refs/heads/",
+            "refs/tags/",
+            "refs/heads/../tags/x",
+            "refs/heads/./main",
+            "refs/heads//main",
+            "refs/heads/main/",
+            "refs/heads/main.lock",
+            "refs/heads/ma in",
+            "refs/remotes/origin/main",
+            "main",
+        ] {
+            match check_ref(git_ref) {
+                Err(DeathReason::InvalidWebhook { value, .. }) => {
+                    assert_eq!(value.as_deref(), Some(git_ref));
+                }
+                e => panic!("{git_ref} was not rejected: {e:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn will_reject_malformed_commit_ids() {
+        let sha1 = "7fd1a60b01f91b314f59955a4e4d4e80d8edf11d";
+        let sha256 = "6d3c9ee9a9d43b7b8e3d7fb7d4c6e52d5b1a2e0f9c8d7b6a5f4e3d2c1b0a9f8e";
+        assert!(check_commit_id(sha1).is_ok());
+        assert!(matches!(
+            check_commit_id(sha256),
+            Err(DeathReason::FailedClone { .. })
+        ));
+        for id in [
+            "",
+            "7fd1a60b",
+            "7FD1A60B01F91B314F59955A4E4D4E80D8EDF11D",
+            "7fd1a60b01f91b314f59955a4e4d4e80d8edf11g",
+            "7fd1a60b01f91b314f59955a4e4d4e80d8edf11d0",
+            "--upload-pack=touch /tmp/pwned;xxxxxxxxxx",
+            "main",
+        ] {
+            match check_commit_id(id) {
+                Err(DeathReason::InvalidWebhook { field_path, value }) => {
+                    assert_eq!(field_path, "commits[].id");
+                    assert_eq!(value.as_deref(), Some(id));
+                }
+                e => panic!("{id} was not rejected: {e:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn can_extract_structured_result() {
+        let output = |line: &str| CommandOutput {
+            last_line: Some(line.to_string()),
+            ..CommandOutput::default()
+        };
+        let format = CommandOutputFormat::JsonLastLine;
+
+        assert_eq!(
+            structured_result(format, &output(r#"{"status":"ok","artifacts":["a.tar"]}"#)),
+            Some(serde_json::json!({"status": "ok", "artifacts": ["a.tar"]}))
+        );
+        assert_eq!(structured_result(format, &output("build finished")), None);
+        assert_eq!(structured_result(format, &output(r#"["a.tar"]"#)), None);
+        assert_eq!(structured_result(format, &CommandOutput::default()), None);
+        assert_eq!(
+            structured_result(CommandOutputFormat::Text, &output(r#"{"status":"ok"}"#)),
+            None
+        );
+    }
+
+    #[test]
+    fn will_error_on_branch_setting_other_env() {
+        let variables = [WebhookVariable::Branch];
+        let env = webhook_env(&variables, "refs/heads/main", "", "", "", "").unwrap();
+        assert_eq!(env.iter().collect::<Vec<_>>(), [("WEBHOOK_BRANCH", "main")]);
+
+        let git_ref = "refs/heads/main\nLD_PRELOAD=/tmp/evil.so";
+        match webhook_env(&variables, git_ref, "", "", "", "") {
+            Err(DeathReason::InvalidWebhook { field_path, .. }) => assert_eq!(field_path, "_ref"),
+            e => panic!("incorrect result from webhook_env: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn can_match_path_filter_across_commits() {
+        let commits = [
+            CommitStats {
+                modified: vec!["README".to_string()],
+                ..CommitStats::default()
+            },
+            CommitStats {
+                added: vec!["deploy/production.yaml".to_string()],
+                ..CommitStats::default()
+            },
+            CommitStats {
+                removed: vec!["src/old.rs".to_string()],
+                ..CommitStats::default()
+            },
+        ];
+        let files = changed_files_from_payload(&commits, false).unwrap();
+        let filter = |patterns: &[&str]| {
+            patterns
+                .iter()
+                .map(|p| Pattern::new(p).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(matches_path_filter(&files, &[]));
+        assert!(matches_path_filter(&files, &filter(&["deploy/*"])));
+        assert!(matches_path_filter(
+            &files,
+            &filter(&["docs/*", "src/*.rs"])
+        ));
+        assert!(!matches_path_filter(&files, &filter(&["docs/*"])));
+        assert!(!matches_path_filter(&files[..1], &filter(&["deploy/*"])));
+    }
+
+    #[test]
+    fn can_list_changed_files_from_payload() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let repository = Repository::init(dir.path()).unwrap();
+        let commits = [
+            CommitStats {
+                added: vec!["src/new.rs".to_string()],
+                modified: vec!["README".to_string()],
+                ..CommitStats::default()
+            },
+            CommitStats {
+                modified: vec!["README".to_string()],
+                removed: vec!["src/old.rs".to_string()],
+                ..CommitStats::default()
+            },
+        ];
+
+        let files = changed_files_for_push(&repository, "", "", &commits, false);
+        assert_eq!(files, ["README", "src/new.rs", "src/old.rs"]);
     }
-    Ok(Json(()))
 }
@@ -1,11 +1,20 @@
-use std::path::Path;
-use tracing::{debug, error};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use metrics::gauge;
+use tracing::{debug, error, warn};
+
+use openpgp::armor::ReaderMode;
 use openpgp::cert::prelude::*;
+use openpgp::packet::key;
 use openpgp::parse::{
-    stream::{MessageLayer, MessageStructure, VerificationHelper},
-    PacketParser, Parse,
+    stream::{GoodChecksum, MessageLayer, MessageStructure, VerificationError, VerificationHelper},
+    Dearmor, PacketParserBuilder, PacketParserResult, Parse,
 };
+use openpgp::policy::{AsymmetricAlgorithm, StandardPolicy};
+use openpgp::types::{HashAlgorithm, RevocationStatus};
+use openpgp::Fingerprint;
 use sequoia_openpgp as openpgp;
 
 use crate::error::{ProcessingError, Result};
@@ -15,8 +24,122 @@ pub struct KeyringFile {
     certs: Vec<Cert>,
 }
 
+/// The certificate that made a good signature, identifying who a commit was verified by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signer {
+    /// Fingerprint of the key that made the signature, which may be a subkey of the certificate
+    pub fingerprint: String,
+
+    /// Primary user ID of the certificate, such as `Jane Doe <jane@example.com>`
+    pub user_id: Option<String>,
+}
+
+/// Which of the signatures on a commit must be good for the commit to be verified.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureRequirement {
+    /// Any good signature of the commit by a certificate in the keyring is enough, even if the
+    /// commit carries other signatures. Tags merged by the commit are not verified, the same as
+    /// with `git verify-commit`.
+    #[default]
+    Any,
+
+    /// Every signature of the commit must be good, and every tag merged by the commit must be
+    /// signed with good signatures as well.
+    All,
+}
+
+impl FromStr for SignatureRequirement {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(SignatureRequirement::Any),
+            "all" => Ok(SignatureRequirement::All),
+            _ => Err(format!(
+                "invalid signature requirement: {s}, expected `any` or `all`"
+            )),
+        }
+    }
+}
+
+/// Which cryptographic algorithms signatures, and the keys that make them, may use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CryptoPolicy {
+    /// The standard policy of Sequoia, which accepts RSA keys of at least 2048 bits as well as
+    /// Ed25519 and NIST P-256, P-384, and P-521 keys, but rejects SHA-1
+    #[default]
+    Standard,
+
+    /// The standard policy, but also accepting SHA-1 and 1024-bit RSA and DSA keys, for keys that
+    /// were made long ago. SHA-1 signatures can be forged with collisions, so this should only be
+    /// used until such keys are replaced.
+    AllowWeak,
+}
+
+impl CryptoPolicy {
+    pub(crate) fn policy(self) -> StandardPolicy<'static> {
+        let mut policy = StandardPolicy::new();
+        if self == CryptoPolicy::AllowWeak {
+            policy.accept_hash(HashAlgorithm::SHA1);
+            policy.accept_asymmetric_algo(AsymmetricAlgorithm::RSA1024);
+            policy.accept_asymmetric_algo(AsymmetricAlgorithm::DSA1024);
+        }
+        policy
+    }
+}
+
+/// The fingerprint of a certificate, or of one of its keys, that is required to have signed a
+/// commit, written in hex with or without spaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignerFingerprint(Fingerprint);
+
+impl SignerFingerprint {
+    /// Whether the signature was made by this certificate, or by this key of a certificate.
+    fn matches(&self, ka: &ValidErasedKeyAmalgamation<'_, key::PublicParts>) -> bool {
+        self.0 == ka.fingerprint() || self.0 == ka.cert().fingerprint()
+    }
+}
+
+impl FromStr for SignerFingerprint {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match Fingerprint::from_hex(s) {
+            Ok(Fingerprint::Invalid(_)) | Err(_) => Err(format!(
+                "invalid fingerprint: {s}, expected the hex fingerprint of a certificate or key"
+            )),
+            Ok(fingerprint) => Ok(SignerFingerprint(fingerprint)),
+        }
+    }
+}
+
+/// Checks signatures against the certificates of a keyring, remembering the signer of the first
+/// good signature. If any signers are required, good signatures made by other certificates are
+/// rejected. The first signature rejected for its signer, or by the cryptographic policy, is
+/// remembered so that it can be reported with a more specific error.
+pub(crate) struct KeyringHelper<'a> {
+    keyring: &'a KeyringFile,
+    policy: CryptoPolicy,
+    requirement: SignatureRequirement,
+    required_signers: &'a [SignerFingerprint],
+    signer: Option<Signer>,
+    rejection: Option<ProcessingError>,
+}
+
+impl KeyringHelper<'_> {
+    pub(crate) fn into_signer(self) -> Option<Signer> {
+        self.signer
+    }
+
+    /// Error naming why the first rejected signature was rejected, if it was rejected for its
+    /// signer or by the cryptographic policy.
+    pub(crate) fn into_rejection(self) -> Option<ProcessingError> {
+        self.rejection
+    }
+}
+
 impl KeyringFile {
-    /// Load `OpenPGP` certificates ("pubkeys") from a file
+    /// Load `OpenPGP` certificates ("pubkeys") from a file, either binary or ASCII-armored
     ///
     /// # Errors
     ///
@@ -26,53 +149,470 @@ impl KeyringFile {
     /// ensure that a single invalid `Packet` does not break the ability to run the webhook runner,
     /// which may be self-hosting and therefore somewhat irreparable if broken.
     pub fn from_path<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Self> {
-        debug!(?path, "loading keyrings from path");
-        let ppr = PacketParser::from_file(path)
+        Self::from_paths(&[path])
+    }
+
+    /// Load `OpenPGP` certificates from multiple files, merging certificates that are present in
+    /// more than one file by fingerprint. Paths that are directories load every file inside the
+    /// directory.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error under the same conditions as [`KeyringFile::from_path`],
+    /// for any of the given files.
+    pub fn from_paths<P: AsRef<Path> + std::fmt::Debug>(paths: &[P]) -> Result<Self> {
+        let mut certs: Vec<Cert> = vec![];
+        for path in paths
+            .iter()
+            .map(expand_path)
+            .collect::<Result<Vec<_>>>()?
+            .concat()
+        {
+            for cert in read_certs(&path)? {
+                match certs
+                    .iter()
+                    .position(|c| c.fingerprint() == cert.fingerprint())
+                {
+                    Some(i) => {
+                        debug!(fp = %cert.fingerprint(), "merging duplicate cert");
+                        let existing = certs.swap_remove(i);
+                        certs.push(
+                            existing
+                                .merge_public(cert)
+                                .map_err(|e| ProcessingError::InvalidKeyringFile { source: e })?,
+                        );
+                    }
+                    None => certs.push(cert),
+                }
+            }
+        }
+        Ok(KeyringFile { certs })
+    }
+
+    /// Load `OpenPGP` certificates from the contents of a keyring file, such as a keyring stored in
+    /// a repository.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error under the same conditions as [`KeyringFile::from_path`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let ppr = PacketParserBuilder::from_bytes(data)
+            .and_then(build_parser)
             .map_err(|e| ProcessingError::InvalidKeyringFile { source: e })?;
-        let mut certs = vec![];
-        for cert in CertParser::from(ppr) {
-            match cert {
-                Ok(cert) => certs.push(cert),
-                // Parsing an invalid packet should not cause a fatal error. The worst thing that
-                // could happen is that keyring verification fails. Report early, but don't
-                // terminate because of an invalid packet... since that could break the ability
-                // to add a *working* packet.
-                Err(e) => error!(e = ?e, "error parsing OpenPGP packet"),
+        Ok(KeyringFile {
+            certs: parse_certs(ppr),
+        })
+    }
+
+    /// Build a helper to check signatures against the keyring with. Signatures are only good if
+    /// made by one of `required_signers`, unless none are given. Revocations of the keys that
+    /// made them are checked under `policy`, which should match the policy of the verifier.
+    pub(crate) fn helper<'a>(
+        &'a self,
+        policy: CryptoPolicy,
+        requirement: SignatureRequirement,
+        required_signers: &'a [SignerFingerprint],
+    ) -> KeyringHelper<'a> {
+        KeyringHelper {
+            keyring: self,
+            policy,
+            requirement,
+            required_signers,
+            signer: None,
+            rejection: None,
+        }
+    }
+
+    /// Whether or not the keyring contains no certificates at all.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.certs.is_empty()
+    }
+
+    /// Ensure that at least one certificate in the keyring is able to verify signatures right now
+    /// under `policy`, meaning that the certificate is valid, not expired, and not revoked, and has
+    /// a signing key that is as well. This catches keyrings that would reject every signature,
+    /// such as the wrong file or one whose keys have all expired, before any webhook arrives.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ProcessingError::UnusableKeyring`] if no certificate is usable.
+    pub fn check_usable(&self, policy: CryptoPolicy) -> Result<()> {
+        let policy = policy.policy();
+        let usable = self.certs.iter().any(|cert| {
+            let Ok(cert) = cert.with_policy(&policy, None) else {
+                return false;
+            };
+            if cert.alive().is_err()
+                || matches!(cert.revocation_status(), RevocationStatus::Revoked(_))
+            {
+                return false;
             }
+            cert.keys()
+                .for_signing()
+                .alive()
+                .revoked(false)
+                .next()
+                .is_some()
+        });
+        if !usable {
+            return Err(ProcessingError::UnusableKeyring {
+                certs: self.certs.len(),
+            });
         }
-        for cert in &certs {
-            // print the first ID of a cert
-            match cert.userids().next() {
-                Some(uid) => debug!(uid = %uid.userid(), "found cert"),
-                None => debug!(fp = %cert.fingerprint(), "found cert"),
+        Ok(())
+    }
+
+    /// Find the time at which the first certificate in the keyring to expire will expire, or
+    /// `None` if no certificate has an expiration time.
+    #[must_use]
+    pub fn nearest_expiration(&self) -> Option<SystemTime> {
+        self.expirations().map(|(_, expiration)| expiration).min()
+    }
+
+    /// Record metrics for the keyring, such as the number of days until the nearest certificate
+    /// expiration, and warn about any certificate expiring within `window`.
+    ///
+    /// This should be called whenever the keyring is (re)loaded, so that monitoring can alert
+    /// before verification starts failing because of an expired key.
+    pub fn report_expiration(&self, keyring: &str, window: Duration) {
+        let now = SystemTime::now();
+        gauge!(
+            "keyring_loaded_timestamp_seconds",
+            now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            "keyring" => keyring.to_string()
+        );
+
+        for (fp, expiration) in self.expirations() {
+            if expiration <= now + window {
+                warn!(keyring, %fp, ?expiration, "cert in keyring expires soon");
             }
         }
-        Ok(KeyringFile { certs })
+
+        if let Some(expiration) = self.nearest_expiration() {
+            let days = match expiration.duration_since(now) {
+                Ok(remaining) => remaining.as_secs_f64(),
+                Err(e) => -e.duration().as_secs_f64(),
+            } / (24.0 * 60.0 * 60.0);
+            gauge!("keyring_cert_expiry_days", days, "keyring" => keyring.to_string());
+        }
+    }
+
+    /// List the fingerprint and expiration time of every certificate that has an expiration time.
+    fn expirations(&self) -> impl Iterator<Item = (Fingerprint, SystemTime)> + '_ {
+        let policy = StandardPolicy::new();
+        self.certs.iter().filter_map(move |cert| {
+            let expiration = cert
+                .with_policy(&policy, None)
+                .ok()?
+                .primary_key()
+                .key_expiration_time()?;
+            Some((cert.fingerprint(), expiration))
+        })
     }
 }
 
-// Note: This should be & to be usable with VerifierBuilder; all methods take &Self or &mut Self
-impl VerificationHelper for &KeyringFile {
+/// Expand a keyring path into the files it refers to; directories are expanded into the files
+/// they contain, in a stable order.
+fn expand_path<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Read all valid certificates from a single keyring file.
+fn read_certs<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Vec<Cert>> {
+    debug!(?path, "loading keyrings from path");
+    let ppr = PacketParserBuilder::from_file(path)
+        .and_then(build_parser)
+        .map_err(|e| ProcessingError::InvalidKeyringFile { source: e })?;
+    Ok(parse_certs(ppr))
+}
+
+/// Build a parser that reads keyrings either as binary packets, as exported by `gpg --export`, or
+/// as ASCII armor, as exported by `gpg --export --armor`. Parsers dearmor input by default, but
+/// search the whole file for anything that looks like base64; armor read by this parser must have
+/// intact header and footer lines instead.
+fn build_parser(builder: PacketParserBuilder<'_>) -> openpgp::Result<PacketParserResult<'_>> {
+    builder
+        .dearmor(Dearmor::Auto(ReaderMode::Tolerant(None)))
+        .build()
+}
+
+/// Parse all valid certificates from a keyring, logging and skipping invalid packets.
+fn parse_certs(ppr: PacketParserResult<'_>) -> Vec<Cert> {
+    let mut certs = vec![];
+    for cert in CertParser::from(ppr) {
+        match cert {
+            Ok(cert) => certs.push(cert),
+            // Parsing an invalid packet should not cause a fatal error. The worst thing that
+            // could happen is that keyring verification fails. Report early, but don't
+            // terminate because of an invalid packet... since that could break the ability
+            // to add a *working* packet.
+            Err(e) => error!(e = ?e, "error parsing OpenPGP packet"),
+        }
+    }
+    for cert in &certs {
+        // print the first ID of a cert
+        match cert.userids().next() {
+            Some(uid) => debug!(uid = %uid.userid(), "found cert"),
+            None => debug!(fp = %cert.fingerprint(), "found cert"),
+        }
+    }
+    certs
+}
+
+impl VerificationHelper for KeyringHelper<'_> {
     fn get_certs(&mut self, _ids: &[openpgp::KeyHandle]) -> openpgp::Result<Vec<openpgp::Cert>> {
-        Ok(self.certs.clone())
+        Ok(self.keyring.certs.clone())
     }
 
+    /// Check every signature of the message, accepting it if any signature is good, or only if all
+    /// of them are, depending on the requirement. Otherwise, the error of the first signature that
+    /// is not good is returned.
     fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        let policy = self.policy.policy();
         let mut good = false;
+        let mut errors = vec![];
         for (i, layer) in structure.into_iter().enumerate() {
-            match (i, layer) {
-                (0, MessageLayer::SignatureGroup { results }) => match results.into_iter().next() {
-                    Some(Ok(_)) => good = true,
-                    Some(Err(e)) => return Err(openpgp::Error::from(e).into()),
-                    None => return Err(anyhow::anyhow!("No signature")),
-                },
-                _ => return Err(anyhow::anyhow!("Unexpected message structure")),
+            let MessageLayer::SignatureGroup { results } = layer else {
+                return Err(anyhow::anyhow!("Unexpected message structure"));
+            };
+            if i != 0 {
+                return Err(anyhow::anyhow!("Unexpected message structure"));
+            }
+            for result in results {
+                match result {
+                    // The verifier only considers revocations that were in effect when the
+                    // signature was made, but a cert that has been revoked since then should no
+                    // longer be trusted either.
+                    Ok(GoodChecksum { ka, .. }) if is_revoked(&ka, &policy) => {
+                        errors.push(anyhow::anyhow!(
+                            "Signing key {} has been revoked",
+                            ka.fingerprint()
+                        ));
+                    }
+                    Ok(GoodChecksum { ka, .. }) => {
+                        let signer = Signer {
+                            fingerprint: ka.fingerprint().to_hex(),
+                            user_id: ka.cert().primary_userid().ok().map(|user_id| {
+                                String::from_utf8_lossy(user_id.userid().value()).into_owned()
+                            }),
+                        };
+                        if self.required_signers.is_empty()
+                            || self.required_signers.iter().any(|fp| fp.matches(&ka))
+                        {
+                            self.signer.get_or_insert(signer);
+                            good = true;
+                        } else {
+                            errors.push(anyhow::anyhow!(
+                                "Signing key {} is not a required signer",
+                                ka.fingerprint()
+                            ));
+                            self.rejection.get_or_insert_with(|| {
+                                ProcessingError::SignerNotAllowed {
+                                    signer: signer.user_id.unwrap_or(signer.fingerprint),
+                                    fingerprint: ka.cert().fingerprint().to_hex(),
+                                }
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        if let Some(reason) = policy_violation(&e) {
+                            self.rejection
+                                .get_or_insert(ProcessingError::WeakCrypto { reason });
+                        }
+                        errors.push(openpgp::Error::from(e).into());
+                    }
+                }
             }
         }
-        if good {
+        let accepted = match self.requirement {
+            SignatureRequirement::Any => good,
+            SignatureRequirement::All => good && errors.is_empty(),
+        };
+        if accepted {
             Ok(())
         } else {
-            Err(anyhow::anyhow!("Signature verification failed"))
+            Err(errors
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| anyhow::anyhow!("No signature")))
+        }
+    }
+}
+
+/// Find the reason a signature was rejected by the cryptographic policy, such as for being made
+/// with SHA-1, if that is why it could not be verified.
+fn policy_violation(e: &VerificationError<'_>) -> Option<String> {
+    let error = match e {
+        VerificationError::MalformedSignature { error, .. }
+        | VerificationError::UnboundKey { error, .. }
+        | VerificationError::BadKey { error, .. }
+        | VerificationError::BadSignature { error, .. } => error,
+        _ => return None,
+    };
+    error.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(openpgp::Error::PolicyViolation(what, _)) => Some(what.clone()),
+        _ => None,
+    })
+}
+
+/// Determine whether the certificate or the specific key that made a signature is revoked under
+/// `policy` as of the current time.
+fn is_revoked(
+    ka: &ValidErasedKeyAmalgamation<'_, key::PublicParts>,
+    policy: &StandardPolicy<'_>,
+) -> bool {
+    let cert = ka.cert().cert();
+    if let RevocationStatus::Revoked(_) = cert.revocation_status(policy, None) {
+        return true;
+    }
+    cert.keys()
+        .key_handle(ka.fingerprint())
+        .with_policy(policy, None)
+        .any(|key| matches!(key.revocation_status(), RevocationStatus::Revoked(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openpgp::serialize::Serialize;
+    use tempdir::TempDir;
+
+    use crate::test_util::{days, generate_cert, write_keyring};
+
+    #[test]
+    fn can_merge_certs_from_multiple_files() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let first = generate_cert(SystemTime::now() - days(1), None);
+        let second = generate_cert(SystemTime::now() - days(1), None);
+        let paths = [
+            write_keyring(dir.path(), "first.pgp", &[&first]),
+            write_keyring(dir.path(), "second.pgp", &[&second, &first]),
+        ];
+
+        let keyring = KeyringFile::from_paths(&paths).expect("unable to load keyrings");
+        assert_eq!(keyring.certs.len(), 2, "duplicate certs were not merged");
+        for cert in [&first, &second] {
+            assert!(
+                keyring
+                    .certs
+                    .iter()
+                    .any(|c| c.fingerprint() == cert.fingerprint()),
+                "cert missing from merged keyring"
+            );
+        }
+    }
+
+    #[test]
+    fn can_load_armored_and_binary_keyrings() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let certs = [
+            generate_cert(SystemTime::now() - days(1), None),
+            generate_cert(SystemTime::now() - days(1), None),
+        ];
+        let binary = write_keyring(dir.path(), "keyring.gpg", &[&certs[0], &certs[1]]);
+        let armored = dir.path().join("keyring.asc");
+        let mut writer = openpgp::armor::Writer::new(
+            std::fs::File::create(&armored).unwrap(),
+            openpgp::armor::Kind::PublicKey,
+        )
+        .unwrap();
+        for cert in &certs {
+            cert.serialize(&mut writer).unwrap();
+        }
+        writer.finalize().unwrap();
+        assert!(std::fs::read_to_string(&armored)
+            .unwrap()
+            .starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+
+        let binary = KeyringFile::from_path(&binary).expect("unable to load binary keyring");
+        let armored = KeyringFile::from_path(&armored).expect("unable to load armored keyring");
+        assert_eq!(armored.certs.len(), 2);
+        assert_eq!(binary.certs, armored.certs);
+        let bytes =
+            KeyringFile::from_bytes(&std::fs::read(dir.path().join("keyring.asc")).unwrap())
+                .expect("unable to load armored keyring from bytes");
+        assert_eq!(binary.certs, bytes.certs);
+    }
+
+    #[test]
+    fn can_find_nearest_expiration() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        // OpenPGP timestamps only have a resolution of seconds
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let created = UNIX_EPOCH + Duration::from_secs(now) - days(1);
+        let paths = [write_keyring(
+            dir.path(),
+            "keyring.pgp",
+            &[
+                &generate_cert(created, None),
+                &generate_cert(created, Some(days(30))),
+                &generate_cert(created, Some(days(10))),
+            ],
+        )];
+
+        let keyring = KeyringFile::from_paths(&paths).expect("unable to load keyring");
+        assert_eq!(keyring.nearest_expiration(), Some(created + days(10)));
+
+        let paths = [write_keyring(
+            dir.path(),
+            "unexpiring.pgp",
+            &[&generate_cert(created, None)],
+        )];
+        let keyring = KeyringFile::from_paths(&paths).expect("unable to load keyring");
+        assert_eq!(keyring.nearest_expiration(), None);
+    }
+
+    #[test]
+    fn can_check_keyring_is_usable() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let expired = generate_cert(SystemTime::now() - days(10), Some(days(1)));
+        let valid = generate_cert(SystemTime::now() - days(1), None);
+        let paths = [write_keyring(
+            dir.path(),
+            "keyring.pgp",
+            &[&expired, &valid],
+        )];
+        KeyringFile::from_paths(&paths)
+            .expect("unable to load keyring")
+            .check_usable(CryptoPolicy::Standard)
+            .expect("keyring with a valid cert is not usable");
+    }
+
+    #[test]
+    fn will_error_on_unusable_keyring() {
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let empty = write_keyring(dir.path(), "empty.pgp", &[]);
+        let expired = write_keyring(
+            dir.path(),
+            "expired.pgp",
+            &[
+                &generate_cert(SystemTime::now() - days(10), Some(days(1))),
+                &generate_cert(SystemTime::now() - days(30), Some(days(20))),
+            ],
+        );
+        for (path, count) in [(empty, 0), (expired, 2)] {
+            let keyring = KeyringFile::from_path(&path).expect("unable to load keyring");
+            match keyring.check_usable(CryptoPolicy::Standard) {
+                Err(ProcessingError::UnusableKeyring { certs }) => assert_eq!(certs, count),
+                e => panic!("unusable keyring {path:?} was not rejected: {e:?}"),
+            }
         }
     }
 }
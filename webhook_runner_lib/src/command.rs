@@ -0,0 +1,965 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tracing::{debug, info, instrument, warn};
+
+use crate::error::{ProcessingError, Result};
+use crate::user::CommandUser;
+
+/// Prefix of every environment variable set from webhook data, so that data from a webhook can
+/// never override variables such as `PATH` or `LD_PRELOAD`.
+pub const WEBHOOK_ENV_PREFIX: &str = "WEBHOOK_";
+
+/// Environment variables holding webhook data, to be passed to a command. Every variable is named
+/// with [`WEBHOOK_ENV_PREFIX`], and values containing control characters are rejected. Variables
+/// configured for a command are passed as well, before the variables holding webhook data.
+#[derive(Clone, Debug, Default)]
+pub struct WebhookEnv {
+    configured: Vec<(String, String)>,
+    vars: Vec<(String, String)>,
+}
+
+impl WebhookEnv {
+    /// Set the variable `WEBHOOK_{name}` to `value`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `name` contains anything other than uppercase ASCII
+    /// letters, digits, and underscores, or if `value` contains a control character such as a
+    /// newline or NUL.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let name = format!("{WEBHOOK_ENV_PREFIX}{name}");
+        let valid_name = name
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+        if !valid_name {
+            return Err(ProcessingError::InvalidEnvironmentVariable {
+                name,
+                reason: "name may only contain A-Z, 0-9, and _".to_string(),
+            });
+        }
+        if value.chars().any(char::is_control) {
+            return Err(ProcessingError::InvalidEnvironmentVariable {
+                name,
+                reason: "value contains a control character".to_string(),
+            });
+        }
+        self.vars.push((name, value.to_string()));
+        Ok(())
+    }
+
+    /// Set the variable `name` to `value` as configured for a command, without the prefix.
+    /// Configured variables are trusted, but are set before the variables holding webhook data, so
+    /// that they can't override them.
+    pub fn set_configured(&mut self, name: &str, value: &str) {
+        self.configured.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set the variables that the placeholders of a command refer to, for the commit `sha` of
+    /// `git_ref` in `repository`. The branch or tag is empty if `git_ref` is not one.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if any value contains a control character.
+    pub fn set_placeholders(&mut self, sha: &str, git_ref: &str, repository: &str) -> Result<()> {
+        let values = [
+            ("sha", sha),
+            ("ref", git_ref),
+            ("repo", repository),
+            (
+                "branch",
+                git_ref.strip_prefix("refs/heads/").unwrap_or_default(),
+            ),
+            (
+                "tag",
+                git_ref.strip_prefix("refs/tags/").unwrap_or_default(),
+            ),
+        ];
+        for (placeholder, value) in values {
+            self.set(&placeholder_variable(placeholder), value)?;
+        }
+        Ok(())
+    }
+
+    /// Iterate over the names and values of the variables.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.configured
+            .iter()
+            .chain(&self.vars)
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+/// Placeholders that may be used in a command, such as `deploy.sh {sha} {ref}`.
+pub const PLACEHOLDERS: [&str; 5] = ["sha", "ref", "repo", "branch", "tag"];
+
+/// Name the variable holding the value of a placeholder, without the prefix.
+fn placeholder_variable(placeholder: &str) -> String {
+    format!("PLACEHOLDER_{}", placeholder.to_ascii_uppercase())
+}
+
+/// Replace each `{placeholder}` in a shell command with a quoted reference to the variable set by
+/// [`WebhookEnv::set_placeholders`], so that the value is passed to the command as a single word
+/// and is never parsed by the shell. Braces that are not a placeholder, such as `${HOME}` or
+/// `{ make; }`, and anything within single quotes, such as `awk '{print}'`, are left as is.
+///
+/// # Errors
+///
+/// This function returns an error if the command uses a placeholder that is not one of
+/// [`PLACEHOLDERS`].
+pub fn expand_placeholders(command: &str) -> Result<String> {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Quote {
+        None,
+        Single,
+        Double,
+    }
+
+    let mut expanded = String::with_capacity(command.len());
+    let mut quote = Quote::None;
+    let mut chars = command.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            (Quote::Single, '\'') => quote = Quote::None,
+            (Quote::Single, _) => (),
+            (_, '\\') => {
+                expanded.push(c);
+                if let Some((_, escaped)) = chars.next() {
+                    expanded.push(escaped);
+                }
+                continue;
+            }
+            (Quote::None, '\'') => quote = Quote::Single,
+            (Quote::None, '"') => quote = Quote::Double,
+            (Quote::Double, '"') => quote = Quote::None,
+            // Parameter expansions such as `${HOME}` are left for the shell
+            (_, '$') if chars.peek().is_some_and(|(_, next)| *next == '{') => {
+                let end = command[index..]
+                    .find('}')
+                    .map_or(command.len(), |end| index + end + 1);
+                expanded.push_str(&command[index..end]);
+                while chars.peek().is_some_and(|(next, _)| *next < end) {
+                    chars.next();
+                }
+                continue;
+            }
+            (_, '{') => {
+                let rest = &command[index + 1..];
+                let name = rest.find('}').map(|end| &rest[..end]).filter(|name| {
+                    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                });
+                if let Some(name) = name {
+                    if !PLACEHOLDERS.contains(&name) {
+                        return Err(ProcessingError::UnknownPlaceholder {
+                            placeholder: name.to_string(),
+                        });
+                    }
+                    let variable =
+                        format!("${{{WEBHOOK_ENV_PREFIX}{}}}", placeholder_variable(name));
+                    if quote == Quote::Double {
+                        expanded.push_str(&variable);
+                    } else {
+                        expanded.push_str(&format!("\"{variable}\""));
+                    }
+                    for _ in 0..=name.len() {
+                        chars.next();
+                    }
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        expanded.push(c);
+    }
+    Ok(expanded)
+}
+
+/// Replace each reference to a placeholder variable that [`expand_placeholders`] left in a word
+/// with the value of the variable, since there is no shell to expand it. Values are inserted as
+/// is, and are not searched for further references.
+fn substitute_placeholders(word: &str, env: &WebhookEnv) -> String {
+    let prefix = format!("${{{WEBHOOK_ENV_PREFIX}PLACEHOLDER_");
+    let mut substituted = String::with_capacity(word.len());
+    let mut rest = word;
+    while let Some(start) = rest.find(&prefix) {
+        substituted.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[2..end];
+        // The shell expands a variable that was never set to an empty string
+        let value = env
+            .iter()
+            .filter(|(variable, _)| *variable == name)
+            .last()
+            .map_or("", |(_, value)| value);
+        substituted.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    substituted.push_str(rest);
+    substituted
+}
+
+/// A shell to run commands with, written as the program and the arguments that come before the
+/// command, such as `/bin/sh -c`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shell {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell {
+            program: "/bin/sh".to_string(),
+            args: vec!["-c".to_string()],
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut words = shlex::split(s)
+            .ok_or_else(|| format!("invalid shell: {s}, quotes are not balanced"))?
+            .into_iter();
+        let program = words
+            .next()
+            .ok_or_else(|| "invalid shell, expected a program".to_string())?;
+        Ok(Shell {
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+/// How a command is run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandMode {
+    /// Pass the command to a shell as its last argument, so that the command may use pipes,
+    /// redirections, and any other syntax of the shell
+    Shell(Shell),
+
+    /// Split the command into words the way a shell would, and run the first word as the program
+    /// with the other words as its arguments, without a shell
+    Direct,
+}
+
+impl Default for CommandMode {
+    fn default() -> Self {
+        CommandMode::Shell(Shell::default())
+    }
+}
+
+impl CommandMode {
+    /// The program that runs `command`: the shell, or without a shell, the first word of the
+    /// command, which may still refer to variables that are only set once a webhook is received.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the command can't be split into words.
+    pub fn program(&self, command: &str) -> Result<String> {
+        match self {
+            CommandMode::Shell(shell) => Ok(shell.program.clone()),
+            CommandMode::Direct => shlex::split(command)
+                .and_then(|words| words.into_iter().next())
+                .ok_or_else(|| ProcessingError::InvalidCommand {
+                    command: command.to_string(),
+                }),
+        }
+    }
+
+    /// Build the process that runs `command`, along with the name of the program it starts.
+    fn process(&self, command: &str, env: &WebhookEnv) -> Result<(Command, String)> {
+        match self {
+            CommandMode::Shell(shell) => {
+                let mut process = Command::new(&shell.program);
+                process.args(&shell.args).arg(command);
+                Ok((process, shell.program.clone()))
+            }
+            CommandMode::Direct => {
+                let mut words = shlex::split(command)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|word| substitute_placeholders(&word, env));
+                let program = words
+                    .next()
+                    .ok_or_else(|| ProcessingError::InvalidCommand {
+                        command: command.to_string(),
+                    })?;
+                let mut process = Command::new(&program);
+                process.args(words);
+                Ok((process, program))
+            }
+        }
+    }
+}
+
+/// Number of bytes of each output stream of a command that are kept, counting from the end.
+pub const CAPTURED_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// The last bytes that a command wrote to stdout and to stderr, up to [`CAPTURED_OUTPUT_BYTES`]
+/// of each, as raw bytes, since commands are not guaranteed to write UTF-8.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether earlier output was dropped to stay within the limit
+    pub truncated: bool,
+}
+
+impl CapturedOutput {
+    /// Append the output of another command, such as a command run after this one.
+    pub fn append(&mut self, other: &CapturedOutput) {
+        let stdout = keep_tail(&mut self.stdout, &other.stdout);
+        let stderr = keep_tail(&mut self.stderr, &other.stderr);
+        self.truncated |= other.truncated || stdout || stderr;
+    }
+}
+
+/// Append `bytes` to `buffer`, dropping the start of the buffer so that it holds at most
+/// [`CAPTURED_OUTPUT_BYTES`], and return whether anything was dropped.
+fn keep_tail(buffer: &mut Vec<u8>, bytes: &[u8]) -> bool {
+    buffer.extend_from_slice(bytes);
+    let excess = buffer.len().saturating_sub(CAPTURED_OUTPUT_BYTES);
+    buffer.drain(..excess);
+    excess > 0
+}
+
+/// Output of a command that exited successfully.
+#[derive(Clone, Debug, Default)]
+pub struct CommandOutput {
+    /// The last line that was not empty that the command wrote to stdout, if any
+    pub last_line: Option<String>,
+
+    /// The end of everything the command wrote
+    pub captured: CapturedOutput,
+}
+
+/// What was read from a single output stream of a command.
+#[derive(Default)]
+struct StreamOutput {
+    last_line: Option<String>,
+    tail: Vec<u8>,
+    truncated: bool,
+}
+
+/// Log each line of output from a command, returning the last line that was not empty along with
+/// the end of the output. Output is read as raw bytes and only converted, lossily, for logging,
+/// since commands are not guaranteed to write UTF-8.
+async fn log_output(reader: impl AsyncRead + Unpin, stream: &str) -> std::io::Result<StreamOutput> {
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    let mut read = StreamOutput::default();
+    while reader.read_until(b'\n', &mut line).await? != 0 {
+        let output = String::from_utf8_lossy(&line);
+        let output = output.trim_end();
+        info!(stream, output, "command output");
+        if !output.is_empty() {
+            read.last_line = Some(output.to_string());
+        }
+        read.truncated |= keep_tail(&mut read.tail, &line);
+        line.clear();
+    }
+    Ok(read)
+}
+
+/// How the process of a command is started, beyond the command and its environment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProcessOptions {
+    /// The user and group to run the command as, which is ignored other than on Unix
+    pub user: CommandUser,
+
+    /// Niceness to run the command with, from -20 for the highest priority to 19 for the lowest
+    pub nice: Option<i32>,
+
+    /// Bytes of address space that the command may use, beyond which allocations fail
+    pub memory_limit: Option<u64>,
+
+    /// Seconds of CPU time that the command may use before it is killed
+    pub cpu_limit: Option<u64>,
+}
+
+impl ProcessOptions {
+    fn has_limits(&self) -> bool {
+        self.nice.is_some() || self.memory_limit.is_some() || self.cpu_limit.is_some()
+    }
+}
+
+/// Apply the niceness and resource limits of a command to the current process. This is called in
+/// the forked child before the command is executed, so it must only make system calls, and never
+/// allocate or take a lock.
+#[cfg(unix)]
+fn limit_process(
+    nice: Option<i32>,
+    memory_limit: Option<u64>,
+    cpu_limit: Option<u64>,
+) -> std::io::Result<()> {
+    if let Some(nice) = nice {
+        // SAFETY: setpriority only changes the priority of this process
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    for (resource, limit) in [
+        (libc::RLIMIT_AS, memory_limit),
+        (libc::RLIMIT_CPU, cpu_limit),
+    ] {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let limit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        // SAFETY: the limit is valid for the call
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Run a command in `directory`, as set by `mode`, with the variables in `env` added to its
+/// environment, optionally writing `stdin` to the command, and wait up to `timeout` for the
+/// command to exit successfully. The process is started as set by `options`. The last line of the
+/// output of the command is returned, so that commands can report a result.
+///
+/// # Errors
+///
+/// This function returns an error if the command could not be started, if the command exits with
+/// a nonzero exit code or is killed by a signal, or if the timeout expires. The command is killed
+/// if the timeout expires. If the shell, or the program the command starts, could not be found,
+/// the error names the program that was attempted. Commands run without a shell are also
+/// rejected if their quotes are not balanced.
+#[instrument(skip(env, stdin, options))]
+pub async fn run_command(
+    command: &str,
+    mode: &CommandMode,
+    directory: &Path,
+    env: &WebhookEnv,
+    stdin: Option<Vec<u8>>,
+    timeout: Duration,
+    options: &ProcessOptions,
+) -> Result<CommandOutput> {
+    let (mut process, program) = mode.process(command, env)?;
+    #[cfg(unix)]
+    {
+        if let Some(uid) = options.user.uid {
+            process.uid(uid);
+        }
+        if let Some(gid) = options.user.gid {
+            process.gid(gid);
+        }
+        if options.has_limits() {
+            let (nice, memory_limit, cpu_limit) =
+                (options.nice, options.memory_limit, options.cpu_limit);
+            // SAFETY: the closure only makes system calls, which are safe to make after forking
+            unsafe {
+                process.pre_exec(move || limit_process(nice, memory_limit, cpu_limit));
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if options.user.is_set() {
+            tracing::warn!(
+                "commands can only be run as another user on unix, running as the current user"
+            );
+        }
+        if options.has_limits() {
+            tracing::warn!("resource limits of commands are only supported on unix, ignoring them");
+        }
+    }
+    let mut child = process
+        .current_dir(directory)
+        .envs(env.iter())
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| match e.kind() {
+            // A missing directory is reported the same way as a missing program
+            std::io::ErrorKind::NotFound if directory.is_dir() => {
+                ProcessingError::CommandNotFound { program }
+            }
+            _ => e.into(),
+        })?;
+
+    let stdin_pipe = child.stdin.take();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let write_stdin = async {
+        if let (Some(input), Some(mut pipe)) = (stdin, stdin_pipe) {
+            debug!(bytes = input.len(), "writing to stdin of command");
+            // The command is free to exit without reading all of stdin
+            match pipe.write_all(&input).await {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+                _ => drop(pipe),
+            }
+        }
+        Ok(())
+    };
+    let run = async {
+        let (status, (), stdout, stderr) = tokio::try_join!(
+            child.wait(),
+            write_stdin,
+            log_output(stdout, "stdout"),
+            log_output(stderr, "stderr"),
+        )?;
+        Ok::<_, std::io::Error>((status, stdout, stderr))
+    };
+    let (status, stdout, stderr) = tokio::time::timeout(timeout, run).await??;
+    let captured = CapturedOutput {
+        truncated: stdout.truncated || stderr.truncated,
+        stdout: stdout.tail,
+        stderr: stderr.tail,
+    };
+
+    debug!(?status, "command exited");
+
+    match status.code() {
+        Some(0) => Ok(CommandOutput {
+            last_line: stdout.last_line,
+            captured,
+        }),
+        // A command killed by a signal has no exit code
+        exit_code => {
+            // Shells exit with 127 when they can't find a program, but so may any command
+            if exit_code == Some(127) && matches!(mode, CommandMode::Shell(_)) {
+                warn!("command exited with 127, a program it runs may not have been found");
+            }
+            Err(ProcessingError::Command {
+                exit_code: exit_code.unwrap_or(-1),
+                output: captured,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn can_set_webhook_env_without_overriding_env() {
+        let mut env = WebhookEnv::default();
+        env.set("BRANCH", "main LD_PRELOAD=/tmp/evil.so").unwrap();
+        env.set("LD_PRELOAD", "/tmp/evil.so").unwrap();
+        run_command(
+            r#"test -z "$LD_PRELOAD" \
+                && test "$WEBHOOK_BRANCH" = 'main LD_PRELOAD=/tmp/evil.so' \
+                && test "$WEBHOOK_LD_PRELOAD" = /tmp/evil.so"#,
+            &CommandMode::default(),
+            Path::new("."),
+            &env,
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("webhook env overrode env");
+    }
+
+    #[tokio::test]
+    async fn can_set_configured_env_without_overriding_webhook_env() {
+        let mut env = WebhookEnv::default();
+        env.set("BRANCH", "main").unwrap();
+        env.set_configured("RUST_LOG", "debug");
+        env.set_configured("WEBHOOK_BRANCH", "other");
+        run_command(
+            r#"test "$RUST_LOG" = debug && test "$WEBHOOK_BRANCH" = main"#,
+            &CommandMode::default(),
+            Path::new("."),
+            &env,
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("configured env overrode webhook env");
+    }
+
+    #[test]
+    fn will_error_on_invalid_webhook_env() {
+        let mut env = WebhookEnv::default();
+        for (name, value) in [
+            ("BRANCH", "main\nLD_PRELOAD=/tmp/evil.so"),
+            ("BRANCH", "main\0"),
+            ("X=1 LD_PRELOAD", "/tmp/evil.so"),
+            ("branch", "main"),
+        ] {
+            match env.set(name, value) {
+                Err(ProcessingError::InvalidEnvironmentVariable { .. }) => (),
+                e => panic!("incorrect result from WebhookEnv::set({name:?}): {e:?}"),
+            }
+        }
+        assert_eq!(env.iter().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn can_write_stdin_to_command() {
+        let stdin = b"README\nsrc/main.rs\n".to_vec();
+        run_command(
+            r#"test "$(cat)" = "$(printf 'README\nsrc/main.rs')""#,
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            Some(stdin),
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command did not receive stdin");
+    }
+
+    #[tokio::test]
+    async fn can_run_command_with_binary_output() {
+        let output = run_command(
+            r"printf 'text\n\377\376\000binary\n\200'; printf '\377' >&2",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("binary output caused an error");
+        assert_eq!(output.captured.stdout, b"text\n\xff\xfe\0binary\n\x80");
+        assert_eq!(output.captured.stderr, b"\xff");
+        assert!(!output.captured.truncated);
+    }
+
+    #[tokio::test]
+    async fn can_capture_end_of_output() {
+        let output = run_command(
+            "head -c 70000 /dev/zero | tr '\\0' a; echo; echo end",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(output.captured.stdout.len(), CAPTURED_OUTPUT_BYTES);
+        assert!(output.captured.stdout.ends_with(b"aaa\nend\n"));
+        assert!(output.captured.truncated);
+
+        match run_command(
+            "echo failed >&2; exit 3",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        {
+            Err(ProcessingError::Command { output, .. }) => {
+                assert_eq!(output.stderr, b"failed\n");
+            }
+            e => panic!("incorrect result from run_command: {e:?}"),
+        }
+
+        let mut combined = output.captured.clone();
+        combined.append(&CapturedOutput {
+            stdout: b"next\n".to_vec(),
+            ..CapturedOutput::default()
+        });
+        assert_eq!(combined.stdout.len(), CAPTURED_OUTPUT_BYTES);
+        assert!(combined.stdout.ends_with(b"end\nnext\n"));
+    }
+
+    #[tokio::test]
+    async fn can_return_last_line_of_output() {
+        let output = run_command(
+            r#"echo first; echo '{"status":"ok"}'; echo; echo error >&2"#,
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(output.last_line.as_deref(), Some(r#"{"status":"ok"}"#));
+
+        let output = run_command(
+            "true",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(output.last_line, None);
+    }
+
+    #[tokio::test]
+    async fn will_error_on_nonzero_exit_code() {
+        match run_command(
+            "exit 3",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        {
+            Err(ProcessingError::Command { exit_code: 3, .. }) => (),
+            e => panic!("incorrect result from run_command: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn will_error_on_command_not_found() {
+        match run_command(
+            "/nonexistent/deploy.sh --production",
+            &CommandMode::Direct,
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        {
+            Err(e @ ProcessingError::CommandNotFound { .. }) => {
+                assert_eq!(
+                    e.to_string(),
+                    "command was not found: /nonexistent/deploy.sh"
+                );
+            }
+            e => panic!("incorrect result from run_command: {e:?}"),
+        }
+
+        // Only the shell was started, so its exit code is reported as any other
+        match run_command(
+            "/nonexistent/deploy.sh --production",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        {
+            Err(ProcessingError::Command { exit_code: 127, .. }) => (),
+            e => panic!("incorrect result from run_command: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn can_expand_placeholders() {
+        assert_eq!(
+            expand_placeholders("deploy.sh {sha} {ref}").unwrap(),
+            r#"deploy.sh "${WEBHOOK_PLACEHOLDER_SHA}" "${WEBHOOK_PLACEHOLDER_REF}""#
+        );
+        assert_eq!(
+            expand_placeholders(r#"echo "tag {tag} of {repo}""#).unwrap(),
+            r#"echo "tag ${WEBHOOK_PLACEHOLDER_TAG} of ${WEBHOOK_PLACEHOLDER_REPO}""#
+        );
+        // Braces that are not placeholders are left for the shell
+        for command in [
+            "awk '{print $1}' '{unknown}'",
+            "echo ${HOME} \\{unknown}",
+            "{ make; make install; }",
+            "echo {a,b}",
+        ] {
+            assert_eq!(expand_placeholders(command).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn will_error_on_unknown_placeholder() {
+        for command in ["deploy.sh {commit}", r#"echo "{branch} {Branch}""#] {
+            match expand_placeholders(command) {
+                Err(ProcessingError::UnknownPlaceholder { .. }) => (),
+                e => panic!("incorrect result from expand_placeholders: {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn can_pass_placeholders_without_parsing_them() {
+        let mut env = WebhookEnv::default();
+        env.set_placeholders(
+            "abc123",
+            "refs/heads/$(touch pwned);`id`",
+            "octocat/Hello-World",
+        )
+        .unwrap();
+        let command = expand_placeholders(r#"printf '%s|' {branch} "{repo}" {tag}"#).unwrap();
+        let output = run_command(
+            &command,
+            &CommandMode::default(),
+            Path::new("."),
+            &env,
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(
+            output.last_line.as_deref(),
+            Some("$(touch pwned);`id`|octocat/Hello-World||")
+        );
+    }
+
+    #[tokio::test]
+    async fn can_run_piped_command_through_shell() {
+        let shell: Shell = "/bin/sh -e -c".parse().unwrap();
+        assert_eq!(shell.program, "/bin/sh");
+        assert_eq!(shell.args, ["-e", "-c"]);
+        let output = run_command(
+            "printf 'a\\nb\\n' | wc -l | tr -d ' '",
+            &CommandMode::Shell(shell),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(output.last_line.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn can_run_command_without_shell() {
+        let mut env = WebhookEnv::default();
+        env.set_placeholders("abc123", "refs/heads/main; id", "octocat/Hello-World")
+            .unwrap();
+        let command = expand_placeholders(r#"printf '%s,' a | "b c" {branch} x{sha}"#).unwrap();
+        let output = run_command(
+            &command,
+            &CommandMode::Direct,
+            Path::new("."),
+            &env,
+            None,
+            TIMEOUT,
+            &ProcessOptions::default(),
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(
+            output.last_line.as_deref(),
+            Some("a,|,b c,main; id,xabc123,")
+        );
+
+        for (command, expected) in [
+            (
+                "/nonexistent/deploy.sh",
+                "command was not found: /nonexistent/deploy.sh",
+            ),
+            (
+                "echo 'unbalanced",
+                "command could not be split into words: echo 'unbalanced",
+            ),
+        ] {
+            match run_command(
+                command,
+                &CommandMode::Direct,
+                Path::new("."),
+                &env,
+                None,
+                TIMEOUT,
+                &ProcessOptions::default(),
+            )
+            .await
+            {
+                Err(e) => assert_eq!(e.to_string(), expected),
+                e => panic!("incorrect result from run_command: {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn will_error_on_timeout() {
+        match run_command(
+            "sleep 10",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            Duration::from_millis(100),
+            &ProcessOptions::default(),
+        )
+        .await
+        {
+            Err(ProcessingError::Timeout { .. }) => (),
+            e => panic!("incorrect result from run_command: {e:?}"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn can_limit_resources_of_command() {
+        // Reading 100 MB into a variable needs more memory than the limit allows
+        let command = r#"x=$(head -c 100000000 /dev/zero | tr '\0' a); echo ${#x}"#;
+        for memory_limit in [None, Some(64 * 1024 * 1024)] {
+            let options = ProcessOptions {
+                memory_limit,
+                ..ProcessOptions::default()
+            };
+            let result = run_command(
+                command,
+                &CommandMode::default(),
+                Path::new("."),
+                &WebhookEnv::default(),
+                None,
+                TIMEOUT,
+                &options,
+            )
+            .await;
+            match (memory_limit, result) {
+                (None, Ok(output)) => assert_eq!(output.last_line.as_deref(), Some("100000000")),
+                (Some(_), Err(ProcessingError::Command { .. })) => (),
+                (_, e) => panic!("incorrect result with memory limit {memory_limit:?}: {e:?}"),
+            }
+        }
+
+        let options = ProcessOptions {
+            nice: Some(19),
+            cpu_limit: Some(60),
+            ..ProcessOptions::default()
+        };
+        let output = run_command(
+            "nice",
+            &CommandMode::default(),
+            Path::new("."),
+            &WebhookEnv::default(),
+            None,
+            TIMEOUT,
+            &options,
+        )
+        .await
+        .expect("command failed with niceness");
+        assert_eq!(output.last_line.as_deref(), Some("19"));
+    }
+}
@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, instrument};
+
+use crate::error::{ProcessingError, Result};
+
+/// GitHub asks for at least a second between requests that create content, such as comments.
+const COMMENT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct CommentRequest<'a> {
+    body: &'a str,
+}
+
+/// Posts comments on commits through the GitHub API with a token, one at a time and at most one
+/// each second.
+pub struct CommitCommenter {
+    token: String,
+    api_url: String,
+    client: reqwest::Client,
+    last_comment: Mutex<Option<Instant>>,
+}
+
+impl std::fmt::Debug for CommitCommenter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommitCommenter")
+            .field("api_url", &self.api_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CommitCommenter {
+    /// Create a commenter authenticating with a token that may write to the repositories.
+    pub fn new(token: impl Into<String>) -> Self {
+        CommitCommenter {
+            token: token.into(),
+            api_url: "https://api.github.com".to_string(),
+            client: reqwest::Client::new(),
+            last_comment: Mutex::new(None),
+        }
+    }
+
+    /// Use a different API, such as the API of a GitHub Enterprise server.
+    #[must_use]
+    pub fn with_api_url(mut self, api_url: impl Into<String>) -> Self {
+        self.api_url = api_url.into();
+        self
+    }
+
+    /// Comment on `commit` in the repository with the full name `repository`, waiting until a
+    /// second has passed since the previous comment.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if GitHub did not accept the comment.
+    #[instrument(skip(self, body))]
+    pub async fn comment(&self, repository: &str, commit: &str, body: &str) -> Result<()> {
+        let mut last_comment = self.last_comment.lock().await;
+        if let Some(last_comment) = *last_comment {
+            tokio::time::sleep_until(last_comment + COMMENT_INTERVAL).await;
+        }
+
+        debug!("posting commit comment");
+
+        let url = format!(
+            "{}/repos/{repository}/commits/{commit}/comments",
+            self.api_url
+        );
+        let result = self
+            .client
+            .post(url)
+            .bearer_auth(&self.token)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "webhook-runner")
+            .json(&CommentRequest { body })
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        last_comment.replace(Instant::now());
+        result
+            .map(drop)
+            .map_err(|e| ProcessingError::CommitComment { source: e.into() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Accept commit comments on a local port, returning the URL of the server and a channel
+    /// receiving each request.
+    async fn serve_comments() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                // Read until the JSON body has been received
+                while !request.ends_with(b"}") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.send(String::from_utf8(request).unwrap()).unwrap();
+                let response = "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n\
+                    Content-Length: 2\r\nConnection: close\r\n\r\n{}";
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn can_post_comments_one_second_apart() {
+        let (url, mut received) = serve_comments().await;
+        let commenter = CommitCommenter::new("secret-token").with_api_url(url);
+
+        let start = Instant::now();
+        commenter
+            .comment("octocat/hello-world", "7fd1a60b", "first")
+            .await
+            .unwrap();
+        commenter
+            .comment("octocat/hello-world", "7fd1a60b", "second")
+            .await
+            .unwrap();
+        assert!(
+            start.elapsed() >= COMMENT_INTERVAL,
+            "comments were not rate limited"
+        );
+
+        let request = received.recv().await.unwrap();
+        assert!(request.starts_with("POST /repos/octocat/hello-world/commits/7fd1a60b/comments "));
+        assert!(request.contains("authorization: Bearer secret-token"));
+        assert!(request.ends_with(r#"{"body":"first"}"#));
+        assert!(received
+            .recv()
+            .await
+            .unwrap()
+            .ends_with(r#"{"body":"second"}"#));
+        assert!(!format!("{commenter:?}").contains("secret-token"));
+    }
+}
@@ -1,5 +1,8 @@
 use thiserror::Error;
 
+use crate::command::CapturedOutput;
+use crate::signature::SignatureAlgorithm;
+
 pub type Result<T> = std::result::Result<T, ProcessingError>;
 
 #[derive(Error, Debug)]
@@ -18,7 +21,25 @@ pub enum ProcessingError {
     },
 
     #[error("process returned nonzero exit code: {exit_code}")]
-    Command { exit_code: i32 },
+    Command {
+        exit_code: i32,
+        output: CapturedOutput,
+    },
+
+    #[error("command was not found: {program}")]
+    CommandNotFound { program: String },
+
+    #[error("command could not be split into words: {command}")]
+    InvalidCommand { command: String },
+
+    #[error("user to run commands as was not found: {name}")]
+    UnknownUser { name: String },
+
+    #[error("group to run commands as was not found: {name}")]
+    UnknownGroup { name: String },
+
+    #[error("unknown placeholder in command: {{{placeholder}}}")]
+    UnknownPlaceholder { placeholder: String },
 
     #[error("timeout expired: {timeout}")]
     Timeout {
@@ -29,6 +50,12 @@ pub enum ProcessingError {
     #[error("the ref we're on ({actual}) is not the ref we expect: ({expected})")]
     RepositoryIntegrity { actual: String, expected: String },
 
+    #[error("submodule {name} is on a host that is not allowed: {url}")]
+    DisallowedSubmodule { name: String, url: String },
+
+    #[error("repository uses the {format} object format, which is not supported")]
+    UnsupportedObjectFormat { format: String },
+
     #[error("performing git operation on repository failed: {source}")]
     GitOperation {
         #[from]
@@ -38,9 +65,93 @@ pub enum ProcessingError {
     #[error("loading openpgp certificates from file failed: {source}")]
     InvalidKeyringFile { source: anyhow::Error },
 
+    #[error("none of the {certs} certificates in the keyring has a valid signing key")]
+    UnusableKeyring { certs: usize },
+
+    #[error("commit {commit} is not signed")]
+    UnsignedCommit { commit: String },
+
+    #[error("tag {tag} is not signed")]
+    UnsignedTag { tag: String },
+
     #[error("parsing gpgsig header as signature failed: {source}")]
     MalformedSignature { source: anyhow::Error },
 
     #[error("verifying gpgsig header failed: {source}")]
     InvalidSignature { source: anyhow::Error },
+
+    #[error("commit was signed by {signer} ({fingerprint}), which is not a required signer")]
+    SignerNotAllowed { signer: String, fingerprint: String },
+
+    #[error("signature was rejected by the cryptographic policy: {reason}")]
+    WeakCrypto { reason: String },
+
+    #[error("known hosts file has an invalid entry on line {line}")]
+    InvalidKnownHosts { line: usize },
+
+    #[error("host {host} is not in the known hosts file")]
+    UnknownHost { host: String },
+
+    #[error("host key of {host} does not match the known hosts file")]
+    HostKeyMismatch { host: String },
+
+    #[error("host key of {host} is revoked")]
+    RevokedHostKey { host: String },
+
+    #[error("watching keyring for changes failed: {source}")]
+    Watch {
+        #[from]
+        source: notify::Error,
+    },
+
+    #[error("loading GitHub App private key failed: {source}")]
+    InvalidGitHubAppKey { source: anyhow::Error },
+
+    #[error("creating GitHub App installation token failed: {source}")]
+    GitHubAppToken { source: anyhow::Error },
+
+    #[error("posting commit comment failed: {source}")]
+    CommitComment { source: anyhow::Error },
+
+    #[error("tag {tag} is not an annotated tag")]
+    NotAnnotatedTag { tag: String },
+
+    #[error("message of tag {tag} does not match pattern: {pattern}")]
+    TagMessagePolicy { tag: String, pattern: String },
+
+    #[error("keyring {path} in repository is not trusted: {source}")]
+    UntrustedKeyring { path: String, source: anyhow::Error },
+
+    #[error("invalid environment variable {name}: {reason}")]
+    InvalidEnvironmentVariable { name: String, reason: String },
+
+    #[error("webhook signature header was malformed: {header}")]
+    MalformedWebhookSignature { header: String },
+
+    #[error("webhook signature was incorrect size: {length} != {intended}")]
+    WebhookSignatureLength { length: usize, intended: usize },
+
+    #[error("webhook signature algorithm {algorithm} is weaker than the minimum of {minimum}")]
+    WeakWebhookSignature {
+        algorithm: SignatureAlgorithm,
+        minimum: SignatureAlgorithm,
+    },
+
+    #[error("hex value of webhook signature was malformed: {source}")]
+    WebhookSignatureHex {
+        #[from]
+        source: hex::FromHexError,
+    },
+
+    #[error("invalid length of hmac key: {source}")]
+    HmacKeyLength {
+        #[from]
+        source: hmac::digest::InvalidLength,
+    },
+
+    #[error("hmac did not match expected: {source}")]
+    HmacVerification {
+        #[from]
+        source: hmac::digest::MacError,
+    },
 }
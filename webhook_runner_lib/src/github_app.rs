@@ -0,0 +1,235 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, instrument};
+
+use crate::error::{ProcessingError, Result};
+use crate::repository::Credentials;
+
+/// Installation tokens are valid for one hour after they are created.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Request a new installation token once the current one is this close to expiring, so a token
+/// does not expire while a clone is in progress.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Claims of the JSON Web Token used to authenticate as a GitHub App.
+#[derive(Serialize)]
+struct Claims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+/// Response from creating an installation access token.
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    token: String,
+}
+
+struct InstallationToken {
+    token: String,
+    expires_at: SystemTime,
+}
+
+/// A GitHub App installation that can mint short-lived tokens for cloning repositories over
+/// HTTPS. Tokens are cached and refreshed shortly before they expire.
+pub struct GitHubApp {
+    app_id: u64,
+    installation_id: u64,
+    key: EncodingKey,
+    api_url: String,
+    client: reqwest::Client,
+    token: Mutex<Option<InstallationToken>>,
+}
+
+impl std::fmt::Debug for GitHubApp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitHubApp")
+            .field("app_id", &self.app_id)
+            .field("installation_id", &self.installation_id)
+            .field("api_url", &self.api_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GitHubApp {
+    /// Create a GitHub App from a PEM-encoded RSA private key.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the private key can't be parsed.
+    pub fn new(app_id: u64, installation_id: u64, private_key: &[u8]) -> Result<Self> {
+        let key = EncodingKey::from_rsa_pem(private_key)
+            .map_err(|e| ProcessingError::InvalidGitHubAppKey { source: e.into() })?;
+        Ok(Self {
+            app_id,
+            installation_id,
+            key,
+            api_url: "https://api.github.com".to_string(),
+            client: reqwest::Client::new(),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Create a GitHub App from a file containing a PEM-encoded RSA private key.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the file can't be read or the private key can't be
+    /// parsed.
+    pub fn from_key_file(
+        app_id: u64,
+        installation_id: u64,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        Self::new(app_id, installation_id, &std::fs::read(path)?)
+    }
+
+    /// Use a different API, such as the API of a GitHub Enterprise server.
+    #[must_use]
+    pub fn with_api_url(mut self, api_url: impl Into<String>) -> Self {
+        self.api_url = api_url.into();
+        self
+    }
+
+    /// Get credentials for cloning repositories the installation has access to, requesting a new
+    /// installation token if the cached token is missing or about to expire.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a new installation token could not be created.
+    pub async fn credentials(&self) -> Result<Credentials> {
+        Ok(Credentials::token(&self.token().await?))
+    }
+
+    async fn token(&self) -> Result<String> {
+        let mut cached = self.token.lock().await;
+        if let Some(token) = &*cached {
+            if token.expires_at > SystemTime::now() + TOKEN_REFRESH_MARGIN {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let token = self.create_token().await?;
+        let value = token.token.clone();
+        cached.replace(token);
+        Ok(value)
+    }
+
+    #[instrument]
+    async fn create_token(&self) -> Result<InstallationToken> {
+        let now = SystemTime::now();
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        // Issue the JWT slightly in the past to allow for clock drift; GitHub rejects JWTs that
+        // expire more than ten minutes in the future
+        let claims = Claims {
+            iat: seconds - 60,
+            exp: seconds + 9 * 60,
+            iss: self.app_id.to_string(),
+        };
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(|e| ProcessingError::GitHubAppToken { source: e.into() })?;
+
+        debug!("requesting new installation token");
+
+        let url = format!(
+            "{}/app/installations/{}/access_tokens",
+            self.api_url, self.installation_id
+        );
+        let response: AccessTokenResponse = self
+            .client
+            .post(url)
+            .bearer_auth(jwt)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "webhook-runner")
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| ProcessingError::GitHubAppToken { source: e.into() })?
+            .json()
+            .await
+            .map_err(|e| ProcessingError::GitHubAppToken { source: e.into() })?;
+
+        Ok(InstallationToken {
+            token: response.token,
+            expires_at: now + TOKEN_LIFETIME,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const PRIVATE_KEY: &[u8] = include_bytes!("testdata/github_app.pem");
+
+    /// Serve installation tokens on a local port, returning the URL of the server and a counter
+    /// of how many tokens have been created.
+    async fn serve_tokens() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                assert!(request.starts_with("POST /app/installations/2/access_tokens "));
+                assert!(request.contains("authorization: Bearer "));
+
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let body = format!(r#"{{"token":"token-{n}"}}"#);
+                let response = format!(
+                    "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn can_reuse_and_refresh_installation_token() {
+        let (url, requests) = serve_tokens().await;
+        let app = GitHubApp::new(1, 2, PRIVATE_KEY).unwrap().with_api_url(url);
+
+        assert_eq!(app.token().await.unwrap(), "token-0");
+        assert_eq!(app.token().await.unwrap(), "token-0");
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            1,
+            "cached token not reused"
+        );
+
+        // Pretend the cached token is about to expire
+        app.token.lock().await.as_mut().unwrap().expires_at = SystemTime::now();
+        assert_eq!(app.token().await.unwrap(), "token-1");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn will_error_on_invalid_key() {
+        match GitHubApp::new(1, 2, b"not a key") {
+            Err(ProcessingError::InvalidGitHubAppKey { .. }) => (),
+            e => panic!("incorrect result from GitHubApp::new: {e:?}"),
+        }
+    }
+}
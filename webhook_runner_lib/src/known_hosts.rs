@@ -0,0 +1,284 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use sequoia_openpgp as openpgp;
+use tracing::debug;
+
+use openpgp::crypto::hash::Digest;
+use openpgp::types::HashAlgorithm;
+
+use crate::error::{ProcessingError, Result};
+
+/// Host keys trusted for SSH remotes, read from a file in the format of OpenSSH's `known_hosts`,
+/// such as the output of `ssh-keyscan github.com`.
+///
+/// Hosts may be listed by name, with `*` and `?` wildcards and `!` negations, as `[name]:port`, or
+/// hashed as written by `ssh-keygen -H`. Keys marked `@revoked` are always rejected, while
+/// `@cert-authority` lines are ignored, since host certificates are not supported.
+///
+/// Reference: <https://man.openbsd.org/sshd.8#SSH_KNOWN_HOSTS_FILE_FORMAT>
+#[derive(Clone, Debug, Default)]
+pub struct KnownHosts {
+    entries: Vec<KnownHost>,
+}
+
+#[derive(Clone, Debug)]
+struct KnownHost {
+    hosts: HostPattern,
+    /// The public key in the SSH wire format, as it is sent by the host
+    key: Vec<u8>,
+    revoked: bool,
+}
+
+#[derive(Clone, Debug)]
+enum HostPattern {
+    Names(Vec<String>),
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Names(names) => {
+                let matching = |name: &str| {
+                    // Hosts on another port are written as `[name]:port`, but libgit2 only gives
+                    // the name of the host
+                    let name = match name.strip_prefix('[').and_then(|n| n.split_once("]:")) {
+                        Some((name, _port)) => name,
+                        None => name,
+                    };
+                    wildcard_match(&name.to_ascii_lowercase(), &host.to_ascii_lowercase())
+                };
+                let negated = names
+                    .iter()
+                    .filter_map(|name| name.strip_prefix('!'))
+                    .any(matching);
+                !negated
+                    && names
+                        .iter()
+                        .filter(|name| !name.starts_with('!'))
+                        .any(|name| matching(name))
+            }
+            HostPattern::Hashed { salt, hash } => {
+                hmac_sha1(salt, host.as_bytes()).is_some_and(|digest| digest == *hash)
+            }
+        }
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any number of characters and `?` matches
+/// exactly one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len())
+            .filter(|i| text.is_char_boundary(*i))
+            .any(|i| wildcard_match(&pattern[1..], &text[i..])),
+        Some(c) => match text.chars().next() {
+            Some(t) if c == '?' || c == t => {
+                wildcard_match(&pattern[c.len_utf8()..], &text[t.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Compute the HMAC-SHA1 of `message`, which hashed host names are stored as, keyed by their salt.
+fn hmac_sha1(key: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+    const BLOCK_SIZE: usize = 64;
+    // Salts written by `ssh-keygen` are as long as a digest, so they never need to be hashed
+    if key.len() > BLOCK_SIZE {
+        return None;
+    }
+    let digest = |pad: u8, data: &[u8]| {
+        let mut block = [pad; BLOCK_SIZE];
+        for (b, k) in block.iter_mut().zip(key) {
+            *b ^= k;
+        }
+        let mut context = HashAlgorithm::SHA1.context().ok()?;
+        context.update(&block);
+        context.update(data);
+        let mut digest = vec![0; context.digest_size()];
+        context.digest(&mut digest).ok()?;
+        Some(digest)
+    };
+    digest(0x5c, &digest(0x36, message)?)
+}
+
+impl KnownHosts {
+    /// Load host keys from a known hosts file.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the file can't be read, or if any line of the file is
+    /// not a valid entry.
+    pub fn from_path<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Self> {
+        debug!(?path, "loading known hosts");
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Verify that `key`, in the SSH wire format, is a known key of `host`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ProcessingError::UnknownHost`] if no key is known for the host,
+    /// [`ProcessingError::HostKeyMismatch`] if the host sent a key other than the known keys, and
+    /// [`ProcessingError::RevokedHostKey`] if the key was revoked.
+    pub fn verify(&self, host: &str, key: &[u8]) -> Result<()> {
+        let entries = self
+            .entries
+            .iter()
+            .filter(|entry| entry.hosts.matches(host))
+            .collect::<Vec<_>>();
+        if entries
+            .iter()
+            .any(|entry| entry.revoked && entry.key == key)
+        {
+            return Err(ProcessingError::RevokedHostKey {
+                host: host.to_string(),
+            });
+        }
+        let mut known = entries.iter().filter(|entry| !entry.revoked).peekable();
+        if known.peek().is_none() {
+            return Err(ProcessingError::UnknownHost {
+                host: host.to_string(),
+            });
+        }
+        if known.any(|entry| entry.key == key) {
+            debug!(host, "host key is known");
+            Ok(())
+        } else {
+            Err(ProcessingError::HostKeyMismatch {
+                host: host.to_string(),
+            })
+        }
+    }
+}
+
+impl FromStr for KnownHosts {
+    type Err = ProcessingError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut entries = vec![];
+        for (number, line) in s.lines().enumerate() {
+            let invalid = || ProcessingError::InvalidKnownHosts { line: number + 1 };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace().peekable();
+            let revoked = match fields.next_if(|field| field.starts_with('@')) {
+                Some("@revoked") => true,
+                Some("@cert-authority") => continue,
+                Some(_) => return Err(invalid()),
+                None => false,
+            };
+            let (Some(hosts), Some(_key_type), Some(key)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let hosts = match hosts.strip_prefix("|1|") {
+                Some(hashed) => {
+                    let (salt, hash) = hashed.split_once('|').ok_or_else(invalid)?;
+                    HostPattern::Hashed {
+                        salt: base64::decode(salt).map_err(|_| invalid())?,
+                        hash: base64::decode(hash).map_err(|_| invalid())?,
+                    }
+                }
+                None => HostPattern::Names(hosts.split(',').map(str::to_string).collect()),
+            };
+            entries.push(KnownHost {
+                hosts,
+                key: base64::decode(key).map_err(|_| invalid())?,
+                revoked,
+            });
+        }
+        Ok(KnownHosts { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GITHUB_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIMCwEJ2UOd5X/jzwOr7MvFL0yYFwxzLTtpr15jlazldO";
+    const GITLAB_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIJ2W2dWxrd1+fmEZojseW19oVFMSv+yyHRzcavIrhii4";
+    const REVOKED_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIEu0fxht8jPkiwnSQe5N77ghrdDDWsgxFGn+FSLGgT3V";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAICLBoiw1ow8Fo7tEuqSniIkxC3f7mjdXF4NhTZMC73/3";
+
+    fn known_hosts() -> KnownHosts {
+        format!(
+            "# written by ssh-keyscan\n\
+            github.com,*.github.com,!evil.github.com ssh-ed25519 {GITHUB_KEY}\n\
+            [git.example.com]:2222 ssh-ed25519 {GITHUB_KEY} comment\n\
+            |1|spXRFxNal2PaKC59rnOlyn0+WxE=|nqGkyDRYrui48d/Lkm3+GG2lGgw= ssh-ed25519 {GITLAB_KEY}\n\
+            @revoked * ssh-ed25519 {REVOKED_KEY}\n\
+            @cert-authority *.example.com ssh-ed25519 {OTHER_KEY}\n"
+        )
+        .parse()
+        .expect("unable to parse known hosts")
+    }
+
+    #[test]
+    fn can_verify_known_host_keys() {
+        let known_hosts = known_hosts();
+        let key = |key| base64::decode(key).unwrap();
+        for host in [
+            "github.com",
+            "GitHub.com",
+            "ssh.github.com",
+            "git.example.com",
+        ] {
+            known_hosts
+                .verify(host, &key(GITHUB_KEY))
+                .unwrap_or_else(|e| panic!("key of {host} was rejected: {e}"));
+        }
+        // Hashed host names can only be verified by hashing the name of the host
+        known_hosts
+            .verify("gitlab.com", &key(GITLAB_KEY))
+            .expect("key of hashed host was rejected");
+    }
+
+    #[test]
+    fn will_error_on_mismatching_host_key() {
+        let known_hosts = known_hosts();
+        let key = |key| base64::decode(key).unwrap();
+        match known_hosts.verify("github.com", &key(OTHER_KEY)) {
+            Err(ProcessingError::HostKeyMismatch { host }) => assert_eq!(host, "github.com"),
+            e => panic!("mismatching key was not rejected: {e:?}"),
+        }
+        match known_hosts.verify("gitlab.com", &key(GITHUB_KEY)) {
+            Err(ProcessingError::HostKeyMismatch { .. }) => (),
+            e => panic!("mismatching key of hashed host was not rejected: {e:?}"),
+        }
+        match known_hosts.verify("evil.github.com", &key(GITHUB_KEY)) {
+            Err(ProcessingError::UnknownHost { host }) => assert_eq!(host, "evil.github.com"),
+            e => panic!("negated host was not rejected: {e:?}"),
+        }
+        match known_hosts.verify("bitbucket.org", &key(GITHUB_KEY)) {
+            Err(ProcessingError::UnknownHost { .. }) => (),
+            e => panic!("unknown host was not rejected: {e:?}"),
+        }
+        match known_hosts.verify("github.com", &key(REVOKED_KEY)) {
+            Err(ProcessingError::RevokedHostKey { .. }) => (),
+            e => panic!("revoked key was not rejected: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn will_error_on_invalid_known_hosts() {
+        let invalid = [
+            "github.com ssh-ed25519",
+            "github.com ssh-ed25519 not-base64!",
+            "@marker github.com ssh-ed25519 AAAA",
+        ];
+        for line in invalid {
+            match format!("# comment\n\n{line}\n").parse::<KnownHosts>() {
+                Err(ProcessingError::InvalidKnownHosts { line }) => assert_eq!(line, 3),
+                e => panic!("invalid entry {line:?} was not rejected: {e:?}"),
+            }
+        }
+    }
+}
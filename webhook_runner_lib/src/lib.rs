@@ -1,13 +1,59 @@
 pub use crate::cert_builder::*;
+pub use crate::command::*;
+pub use crate::commit_comment::*;
 pub use crate::error::*;
+pub use crate::github_app::*;
+pub use crate::known_hosts::*;
+pub use crate::payload::Payload;
 pub use crate::repository::*;
+pub use crate::signature::*;
+pub use crate::user::*;
+pub use crate::watcher::*;
 
 pub mod cert_builder;
+pub mod command;
+pub mod commit_comment;
 pub mod error;
+pub mod github_app;
+pub mod known_hosts;
+pub mod payload;
 pub mod repository;
+pub mod signature;
+pub mod user;
+pub mod watcher;
+
+#[cfg(test)]
+mod test_util;
+
+/// A configured keyring, or the reason it could not be loaded.
+pub type ConfiguredKeyring = std::result::Result<watcher::SharedKeyringFile, String>;
 
 #[derive(Debug, Default)]
 pub struct KeyringFiles {
-    pub tag: Option<cert_builder::KeyringFile>,
-    pub commit: Option<cert_builder::KeyringFile>,
+    pub tag: Option<ConfiguredKeyring>,
+    pub commit: Option<ConfiguredKeyring>,
+    pub pull_request: Option<ConfiguredKeyring>,
+    /// Keyrings configured for individual repositories, by the full name of the repository
+    pub repositories: std::collections::BTreeMap<String, KeyringFiles>,
+}
+
+impl KeyringFiles {
+    /// List every configured keyring by a name describing it, such as `commit keyring` or
+    /// `tag keyring of octocat/hello-world`.
+    pub fn configured(&self) -> impl Iterator<Item = (String, &ConfiguredKeyring)> {
+        let repositories = self.repositories.iter().flat_map(|(repository, keyrings)| {
+            [
+                (format!("commit keyring of {repository}"), &keyrings.commit),
+                (format!("tag keyring of {repository}"), &keyrings.tag),
+            ]
+        });
+        [
+            ("commit keyring".to_string(), &self.commit),
+            ("tag keyring".to_string(), &self.tag),
+            ("pull request keyring".to_string(), &self.pull_request),
+        ]
+        .into_iter()
+        .chain(repositories)
+        .filter_map(|(name, keyring)| Some((name, keyring.as_ref()?)))
+    }
 }
@@ -10,6 +10,20 @@ pub struct Value {
     pub json: serde_json::Value,
 }
 
+/// The body of a webhook sent by GitHub, decoded as whichever kind of event its fields match.
+///
+/// ```
+/// use webhook_runner_lib::Payload;
+///
+/// fn pushed_ref(body: &[u8]) -> Option<String> {
+///     match serde_json::from_slice(body).ok()? {
+///         Payload::Push { _ref, deleted, .. } if !deleted => Some(_ref),
+///         _ => None,
+///     }
+/// }
+///
+/// assert_eq!(pushed_ref(br#"{"zen": "Keep it logically awesome."}"#), None);
+/// ```
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
@@ -100,10 +114,6 @@ pub enum Payload {
         sender: User,
         zen: String,
     },
-    Public {
-        repository: Repository,
-        sender: User,
-    },
     PullRequest {
         action: String,
         number: u64,
@@ -123,10 +133,16 @@ pub enum Payload {
         base_ref: Option<String>,
         before: String,
         commits: Vec<CommitStats>,
+        /// Whether commits were left out of `commits`, such as by a GitLab push with over 20
+        /// commits; never sent by GitHub
+        #[serde(skip)]
+        commits_truncated: bool,
         compare: String,
         created: bool,
         deleted: bool,
         forced: bool,
+        /// Null when a branch or tag is deleted
+        #[serde(deserialize_with = "default_if_null")]
         head_commit: CommitStats,
         pusher: UserRef, // note there aren't may fields here
         #[serde(rename = "ref")]
@@ -172,6 +188,12 @@ pub enum Payload {
         repository: Repository,
         sender: User,
     },
+    /// Only holds the fields that most events have, so it has to come last for other events to be
+    /// matched by their own variant
+    Public {
+        repository: Repository,
+        sender: User,
+    },
 }
 
 #[allow(dead_code)]
@@ -268,8 +290,23 @@ pub struct DeploymentStatus {
     pub url: String,
 }
 
+/// Determine whether `id` is the id of all zeros that is sent as `before` when a ref is created,
+/// and as `after` when a ref is deleted.
+pub fn is_zero_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b == b'0')
+}
+
+/// Deserialize a value that may be null, using the default value in its place.
+pub fn default_if_null<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
 #[allow(dead_code)]
-#[derive(Default, Debug, Deserialize)]
+#[derive(Clone, Default, Debug, Deserialize)]
 pub struct CommitStats {
     pub added: Vec<String>,
     pub author: GitUser,
@@ -367,7 +404,7 @@ pub struct Pages {
 #[derive(Default, Debug, Deserialize)]
 pub struct PullRequestDetails {
     pub _links: PullRequestLinks,
-    pub assignee: Option<String>,
+    pub assignee: Option<User>,
     pub base: PullSource,
     pub body: Option<String>,
     pub closed_at: Option<String>,
@@ -380,7 +417,8 @@ pub struct PullRequestDetails {
     pub id: u64,
     pub issue_url: String,
     pub locked: bool,
-    pub merge_commit_sha: String,
+    /// Not yet known when a pull request is opened
+    pub merge_commit_sha: Option<String>,
     pub merged_at: Option<String>,
     pub milestone: Option<String>,
     pub number: u64,
@@ -485,6 +523,9 @@ pub struct Release {
 pub struct UserRef {
     pub name: String,
     pub email: Option<String>,
+    /// Only sent for the owner of a repository
+    pub login: Option<String>,
+    pub id: Option<u64>,
 }
 
 #[allow(dead_code)]
@@ -492,6 +533,7 @@ pub struct UserRef {
 #[derive(Default, Debug, Deserialize)]
 pub struct PushRepository {
     pub archive_url: String,
+    pub archived: Option<bool>,
     pub assignees_url: String,
     pub blobs_url: String,
     pub branches_url: String,
@@ -504,10 +546,13 @@ pub struct PushRepository {
     pub contributors_url: String,
     pub created_at: u64,
     pub default_branch: String,
+    pub deployments_url: Option<String>,
     pub description: String,
+    pub disabled: Option<bool>,
     pub downloads_url: String,
     pub events_url: String,
     pub fork: bool,
+    pub forks: Option<u64>,
     pub forks_count: u64,
     pub forks_url: String,
     pub full_name: String,
@@ -518,6 +563,7 @@ pub struct PushRepository {
     pub has_downloads: bool,
     pub has_issues: bool,
     pub has_pages: bool,
+    pub has_projects: Option<bool>,
     pub has_wiki: bool,
     pub homepage: Option<String>,
     pub hooks_url: String,
@@ -530,10 +576,13 @@ pub struct PushRepository {
     pub labels_url: String,
     pub language: Option<String>,
     pub languages_url: String,
+    pub license: Option<serde_json::Value>,
+    pub master_branch: Option<String>,
     pub merges_url: String,
     pub milestones_url: String,
     pub mirror_url: Option<String>,
     pub name: String,
+    pub node_id: Option<String>,
     pub notifications_url: String,
     pub open_issues: u64,
     pub open_issues_count: u64,
@@ -542,8 +591,10 @@ pub struct PushRepository {
     pub pulls_url: String,
     pub pushed_at: u64,
     pub releases_url: String,
-    pub size: u64,
+    /// Size of the repository in kilobytes
+    pub size: Option<u64>,
     pub ssh_url: String,
+    pub stargazers: Option<u64>,
     pub stargazers_count: u64,
     pub stargazers_url: String,
     pub statuses_url: String,
@@ -613,7 +664,8 @@ pub struct Repository {
     pub pulls_url: String,
     pub pushed_at: String,
     pub releases_url: String,
-    pub size: u64,
+    /// Size of the repository in kilobytes
+    pub size: Option<u64>,
     pub ssh_url: String,
     pub stargazers_count: u64,
     pub stargazers_url: String,
@@ -643,7 +695,7 @@ pub struct Team {
 }
 
 #[allow(dead_code)]
-#[derive(Default, Debug, Deserialize)]
+#[derive(Clone, Default, Debug, Deserialize)]
 pub struct GitUser {
     pub email: String,
     pub name: String,
@@ -672,7 +724,8 @@ pub struct PullSource {
     pub label: String,
     #[serde(rename = "ref")]
     pub _ref: String,
-    pub repo: Repository,
+    /// Missing if the repository, such as a fork, was deleted
+    pub repo: Option<Repository>,
     pub sha: String,
     pub user: User,
 }
@@ -750,6 +803,7 @@ pub struct User {
     pub html_url: String,
     pub id: u64,
     pub login: String,
+    pub node_id: Option<String>,
     pub organizations_url: String,
     pub received_events_url: String,
     pub repos_url: String,
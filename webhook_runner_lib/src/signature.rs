@@ -0,0 +1,373 @@
+use std::fmt;
+use std::str::FromStr;
+
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use tracing::debug;
+
+use crate::error::{ProcessingError, Result};
+
+/// The digest algorithm of a webhook signature, ordered from the weakest to the strongest so that
+/// a minimum algorithm can be required.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignatureAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl SignatureAlgorithm {
+    /// The name of the algorithm, which signature headers put before the signature as a prefix
+    /// such as `sha256=`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Sha1 => "sha1",
+            SignatureAlgorithm::Sha256 => "sha256",
+            SignatureAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// The number of hex digits in a signature made with the algorithm.
+    fn hex_length(self) -> usize {
+        match self {
+            SignatureAlgorithm::Sha1 => 40,
+            SignatureAlgorithm::Sha256 => 64,
+            SignatureAlgorithm::Sha512 => 128,
+        }
+    }
+}
+
+impl fmt::Display for SignatureAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SignatureAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(SignatureAlgorithm::Sha1),
+            "sha256" => Ok(SignatureAlgorithm::Sha256),
+            "sha512" => Ok(SignatureAlgorithm::Sha512),
+            _ => Err(format!(
+                "invalid signature algorithm: {s}, expected `sha1`, `sha256`, or `sha512`"
+            )),
+        }
+    }
+}
+
+/// An hmac signature of the body of a webhook, which GitHub sends in the `X-Hub-Signature-256`
+/// header with a prefix naming the algorithm, such as `sha256=`, and Gitea and Forgejo send in the
+/// `X-Gitea-Signature` header as hmac-sha256 without one.
+///
+/// Reference: <https://docs.github.com/en/webhooks/using-webhooks/validating-webhook-deliveries>
+#[derive(Clone, Debug)]
+pub struct HubSignature {
+    algorithm: SignatureAlgorithm,
+    mac: Vec<u8>,
+}
+
+impl HubSignature {
+    /// Parse a hex-encoded signature made with `algorithm`, without any prefix, as sent by Gitea.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ProcessingError::WebhookSignatureLength`] if the value does not
+    /// have as many hex digits as a signature of the algorithm, and
+    /// [`ProcessingError::WebhookSignatureHex`] if it is not valid hex.
+    pub fn from_hex(algorithm: SignatureAlgorithm, value: &str) -> Result<Self> {
+        let len = value.len();
+        let intended = algorithm.hex_length();
+        if len != intended {
+            return Err(ProcessingError::WebhookSignatureLength {
+                length: len,
+                intended,
+            });
+        }
+        Ok(HubSignature {
+            algorithm,
+            mac: hex::decode(value)?,
+        })
+    }
+
+    /// The algorithm that the signature was made with.
+    #[must_use]
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+
+    /// Ensure that the signature was made with an algorithm at least as strong as `minimum`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ProcessingError::WeakWebhookSignature`] if the algorithm of the
+    /// signature is weaker.
+    pub fn require(&self, minimum: SignatureAlgorithm) -> Result<()> {
+        if self.algorithm < minimum {
+            return Err(ProcessingError::WeakWebhookSignature {
+                algorithm: self.algorithm,
+                minimum,
+            });
+        }
+        Ok(())
+    }
+
+    /// Verify that a hmac signature from the given key, using the algorithm of the signature, will
+    /// produce the same result as the stored mac.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ProcessingError::HmacVerification`] if the signature does not
+    /// match.
+    pub fn verify(&self, key: &[u8], content: &[u8]) -> Result<()> {
+        debug!(algorithm = %self.algorithm, received_hmac = ?self.mac, "ensuring equality");
+        match self.algorithm {
+            SignatureAlgorithm::Sha1 => verify_mac::<Hmac<Sha1>>(key, content, &self.mac),
+            SignatureAlgorithm::Sha256 => verify_mac::<Hmac<Sha256>>(key, content, &self.mac),
+            SignatureAlgorithm::Sha512 => verify_mac::<Hmac<Sha512>>(key, content, &self.mac),
+        }
+    }
+}
+
+/// Verify that the mac of `content` with `key` is `expected`, in constant time.
+fn verify_mac<M: Mac + KeyInit>(key: &[u8], content: &[u8], expected: &[u8]) -> Result<()> {
+    let mut mac = <M as KeyInit>::new_from_slice(key)?;
+    mac.update(content);
+    mac.verify_slice(expected)?;
+    Ok(())
+}
+
+impl TryFrom<&str> for HubSignature {
+    type Error = ProcessingError;
+
+    fn try_from(value: &str) -> Result<HubSignature> {
+        let malformed = || ProcessingError::MalformedWebhookSignature {
+            header: value.to_string(),
+        };
+        let (prefix, signature) = value.split_once('=').ok_or_else(malformed)?;
+        let algorithm = prefix.parse().map_err(|_| malformed())?;
+        HubSignature::from_hex(algorithm, signature)
+    }
+}
+
+/// Verify the `X-Hub-Signature-256` header that GitHub sends with a webhook, which signs `body`
+/// with the secret key of the webhook, using an algorithm at least as strong as `minimum`.
+///
+/// ```
+/// use webhook_runner_lib::{verify_signature, SignatureAlgorithm};
+///
+/// let header = "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72";
+/// let minimum = SignatureAlgorithm::Sha256;
+/// assert!(verify_signature(b"testingkey", b"hello", header, minimum).is_ok());
+/// assert!(verify_signature(b"testingkey", b"goodbye", header, minimum).is_err());
+/// ```
+///
+/// # Errors
+///
+/// This function returns an error if the header is malformed, if its algorithm is weaker than
+/// `minimum`, or if it does not match the body.
+pub fn verify_signature(
+    secret: &[u8],
+    body: &[u8],
+    header: &str,
+    minimum: SignatureAlgorithm,
+) -> Result<()> {
+    let signature = HubSignature::try_from(header)?;
+    signature.require(minimum)?;
+    signature.verify(secret, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // {{{ HubSignature decoding
+
+    #[test]
+    fn can_decode_signature_header_from_str() {
+        HubSignature::try_from(
+            "sha256=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
+        )
+        .expect("signature was not correctly parsed");
+    }
+
+    #[test]
+    fn will_error_on_invalid_length() {
+        assert!(
+            HubSignature::try_from(
+                "sha256=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fa5ec56f9",
+            )
+            .is_err(),
+            "length should be too short"
+        );
+        assert!(
+            HubSignature::try_from(
+                "sha256=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9ca",
+            )
+            .is_err(),
+            "length should be too long"
+        );
+        let err = HubSignature::try_from("sha256=");
+        match err {
+            Err(ProcessingError::WebhookSignatureLength { .. }) => (),
+            e => {
+                assert!(e.is_err(), "length should be too short");
+                e.expect("incorrect error variant from HubSignature::<&str>::try_from");
+            }
+        }
+    }
+
+    #[test]
+    fn will_error_on_malformed_header() {
+        let err = HubSignature::try_from(
+            "sha255=2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
+        );
+        match err {
+            Err(ProcessingError::MalformedWebhookSignature { .. }) => (),
+            e => {
+                assert!(e.is_err(), "content should be invalid");
+                e.expect("incorrect error variant from HubSignature::<&str>::try_from");
+            }
+        }
+    }
+
+    #[test]
+    fn will_error_on_header_without_prefix() {
+        for header in [
+            "",
+            "2ed61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
+        ] {
+            match HubSignature::try_from(header) {
+                Err(ProcessingError::MalformedWebhookSignature { .. }) => (),
+                e => panic!("incorrect result from HubSignature::try_from: {e:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn will_error_on_invalid_hex() {
+        let err = HubSignature::try_from(
+            "sha256=2gd61cca0a6e94c01c51ab6d396b4308f12fe39d0daffc5738fab9285ec56f9c",
+        );
+        match err {
+            Err(ProcessingError::WebhookSignatureHex { .. }) => (),
+            e => {
+                assert!(e.is_err(), "content should be invalid");
+                e.expect("incorrect error variant from HubSignature::<&str>::try_from");
+            }
+        }
+    }
+
+    #[test]
+    fn can_decode_gitea_signature_from_str() {
+        let signature = HubSignature::from_hex(
+            SignatureAlgorithm::Sha256,
+            "aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+        )
+        .expect("signature was not correctly parsed");
+        signature
+            .verify(b"testingkey", b"hello")
+            .expect("invalid signature verification");
+
+        match HubSignature::from_hex(
+            SignatureAlgorithm::Sha256,
+            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+        ) {
+            Err(ProcessingError::WebhookSignatureLength { .. }) => (),
+            e => panic!("incorrect result from HubSignature::from_hex: {e:?}"),
+        }
+    }
+
+    // }}}
+
+    // {{{ HubSignature verifying
+    #[test]
+    fn can_verify_valid_signature() {
+        let signature = HubSignature::try_from(
+            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+        )
+        .expect("unable to parse signature header");
+        signature
+            .verify(b"testingkey", b"hello")
+            .expect("invalid signature verification");
+    }
+
+    #[test]
+    fn can_verify_signature_of_each_algorithm() {
+        let headers = [
+            (
+                SignatureAlgorithm::Sha1,
+                "sha1=235a794f6d0e9786b17f2c88cbb3a550da3f7d7c",
+            ),
+            (
+                SignatureAlgorithm::Sha256,
+                "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+            ),
+            (
+                SignatureAlgorithm::Sha512,
+                "sha512=612e1b253590d439edde9b362f8fce2a28996a5ed97e42b76c5fce1d5e746ef2\
+                 b29db961ab31d5f28c2ce50cfb73443e694eec7bba7973992e93131918eab823",
+            ),
+        ];
+        for (algorithm, header) in headers {
+            let signature =
+                HubSignature::try_from(header).expect("unable to parse signature header");
+            assert_eq!(signature.algorithm(), algorithm);
+            signature
+                .verify(b"testingkey", b"hello")
+                .unwrap_or_else(|e| panic!("invalid {algorithm} signature verification: {e}"));
+            assert!(
+                signature.verify(b"testingkey", b"heloo").is_err(),
+                "didn't error on modified body with {algorithm}"
+            );
+            verify_signature(b"testingkey", b"hello", header, SignatureAlgorithm::Sha1)
+                .unwrap_or_else(|e| panic!("{algorithm} is not at least sha1: {e}"));
+        }
+    }
+
+    #[test]
+    fn will_error_on_weaker_than_minimum_algorithm() {
+        let sha1 = "sha1=235a794f6d0e9786b17f2c88cbb3a550da3f7d7c";
+        let sha256 = "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72";
+        for (header, minimum) in [
+            (sha1, SignatureAlgorithm::Sha256),
+            (sha256, SignatureAlgorithm::Sha512),
+        ] {
+            match verify_signature(b"testingkey", b"hello", header, minimum) {
+                Err(ProcessingError::WeakWebhookSignature { minimum: m, .. }) => {
+                    assert_eq!(m, minimum);
+                }
+                e => panic!("weak signature was not rejected: {e:?}"),
+            }
+        }
+        verify_signature(b"testingkey", b"hello", sha256, SignatureAlgorithm::Sha256)
+            .expect("signature of the minimum algorithm was rejected");
+    }
+
+    #[test]
+    fn will_error_on_incorrect_signature() {
+        let signature = HubSignature::try_from(
+            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d73",
+        )
+        .expect("unable to parse signature header");
+        assert!(
+            signature.verify(b"testingkey", b"hello").is_err(),
+            "didn't error on modified signature"
+        );
+
+        let signature = HubSignature::try_from(
+            "sha256=aa5f1f4ddf25689f59c16b7caef668db08d6c2656d85c899df8457d32d771d72",
+        )
+        .expect("unable to parse signature header");
+        assert!(
+            signature.verify(b"testingkey", b"heloo").is_err(),
+            "didn't error on modified body"
+        );
+    }
+    // }}}
+}
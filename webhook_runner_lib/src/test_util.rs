@@ -0,0 +1,158 @@
+//! Fixtures for building certificates, keyrings, and repositories with signed commits.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use git2::{Oid, Repository, Signature, Time};
+
+use openpgp::armor::{Kind, Writer};
+use openpgp::cert::prelude::*;
+use openpgp::packet::signature::SignatureBuilder;
+use openpgp::policy::StandardPolicy;
+use openpgp::serialize::Serialize;
+use openpgp::types::{HashAlgorithm, SignatureType};
+use openpgp::{Cert, Packet};
+use sequoia_openpgp as openpgp;
+
+pub(crate) fn days(n: u64) -> Duration {
+    Duration::from_secs(n * 24 * 60 * 60)
+}
+
+/// Generate a certificate with a signing subkey, created at `creation_time` and optionally
+/// expiring after `validity`.
+pub(crate) fn generate_cert(creation_time: SystemTime, validity: Option<Duration>) -> Cert {
+    generate_cert_with_suite(CipherSuite::default(), creation_time, validity)
+}
+
+/// Generate a certificate like [`generate_cert`], with keys of the given algorithms.
+pub(crate) fn generate_cert_with_suite(
+    suite: CipherSuite,
+    creation_time: SystemTime,
+    validity: Option<Duration>,
+) -> Cert {
+    CertBuilder::new()
+        .set_cipher_suite(suite)
+        .add_userid("Test User <test@example.com>")
+        .add_signing_subkey()
+        .set_creation_time(creation_time)
+        .set_validity_period(validity)
+        .generate()
+        .expect("unable to generate cert")
+        .0
+}
+
+/// Write the public portion of the given certificates to a keyring file in `dir`.
+pub(crate) fn write_keyring(dir: &Path, name: &str, certs: &[&Cert]) -> PathBuf {
+    let path = dir.join(name);
+    let mut file = std::fs::File::create(&path).expect("unable to create keyring file");
+    for cert in certs {
+        cert.serialize(&mut file).expect("unable to write cert");
+    }
+    file.flush().expect("unable to flush keyring file");
+    path
+}
+
+/// Create an armored detached signature over `data`, using the signing key that was valid at the
+/// time the certificate was created but stamping the signature with `time`.
+pub(crate) fn sign(cert: &Cert, data: &[u8], time: SystemTime) -> String {
+    sign_with_hash(cert, data, time, HashAlgorithm::default())
+}
+
+/// Create an armored detached signature like [`sign`], hashing `data` with the given algorithm.
+pub(crate) fn sign_with_hash(
+    cert: &Cert,
+    data: &[u8],
+    time: SystemTime,
+    hash: HashAlgorithm,
+) -> String {
+    let policy = StandardPolicy::new();
+    let key = cert
+        .keys()
+        .unencrypted_secret()
+        .with_policy(&policy, cert.primary_key().creation_time())
+        .for_signing()
+        .next()
+        .expect("cert has no signing key");
+    let mut keypair = key
+        .key()
+        .clone()
+        .into_keypair()
+        .expect("unable to build keypair");
+    let sig = SignatureBuilder::new(SignatureType::Binary)
+        .set_signature_creation_time(time)
+        .expect("unable to set signature creation time")
+        .set_hash_algo(hash)
+        .sign_message(&mut keypair, data)
+        .expect("unable to sign message");
+
+    let mut writer = Writer::new(vec![], Kind::Signature).expect("unable to create armor writer");
+    Packet::from(sig)
+        .serialize(&mut writer)
+        .expect("unable to serialize signature");
+    String::from_utf8(writer.finalize().expect("unable to finalize armor"))
+        .expect("armor is not utf8")
+}
+
+/// Initialize a repository with a single file in `dir`.
+pub(crate) fn init_repository(dir: &Path) -> Repository {
+    let repo = Repository::init(dir).expect("unable to init repository");
+    std::fs::write(dir.join("README"), "hello\n").expect("unable to write file");
+    repo
+}
+
+/// Create a bare repository in `dir` and push `refspecs` from `source` to it, returning the
+/// `file://` URL of the bare repository, so that it can be cloned like a remote repository.
+pub(crate) fn bare_remote(source: &Repository, dir: &Path, refspecs: &[&str]) -> String {
+    Repository::init_bare(dir).expect("unable to init bare repository");
+    let url = format!("file://{}", dir.display());
+    source
+        .remote_anonymous(&url)
+        .expect("unable to create remote")
+        .push(refspecs, None)
+        .expect("unable to push to bare repository");
+    url
+}
+
+/// Create a commit on top of `HEAD` made at `time`, signed by `cert` if given. The commit is
+/// stored on `refs/heads/main`.
+pub(crate) fn commit(repo: &Repository, cert: Option<&Cert>, time: SystemTime) -> Oid {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let sig = Signature::new(
+        "Test User",
+        "test@example.com",
+        &Time::new(seconds.try_into().unwrap(), 0),
+    )
+    .expect("unable to build signature");
+
+    let mut index = repo.index().expect("unable to open index");
+    index
+        .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+        .expect("unable to add files");
+    index.write().expect("unable to write index");
+    let tree = repo
+        .find_tree(index.write_tree().expect("unable to write tree"))
+        .expect("unable to find tree");
+
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents = parent.iter().collect::<Vec<_>>();
+
+    let buffer = repo
+        .commit_create_buffer(&sig, &sig, "test commit\n", &tree, &parents)
+        .expect("unable to create commit buffer");
+    let buffer = buffer.as_str().expect("commit is not utf8");
+
+    let oid = match cert {
+        Some(cert) => repo
+            .commit_signed(buffer, &sign(cert, buffer.as_bytes(), time), None)
+            .expect("unable to create signed commit"),
+        None => repo
+            .commit(None, &sig, &sig, "test commit\n", &tree, &parents)
+            .expect("unable to create commit"),
+    };
+    repo.reference("refs/heads/main", oid, true, "test commit")
+        .expect("unable to update branch");
+    repo.set_head("refs/heads/main")
+        .expect("unable to update HEAD");
+    oid
+}
@@ -0,0 +1,272 @@
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::error::{ProcessingError, Result};
+
+/// The user and group that commands are run as in place of the user of the runner, so that
+/// commands can run as a less privileged account. Changing the user of a command is only possible
+/// on Unix, and only when the runner is run as root.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CommandUser {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl CommandUser {
+    /// Resolve a user and a group, each given by name or by numeric id. Commands run as a user
+    /// without a group run as the primary group of the user, so that they never keep the group of
+    /// the runner.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ProcessingError::UnknownUser`] if the user does not exist, or if a
+    /// user given by id has no entry to take the primary group from and no group is given, and
+    /// [`ProcessingError::UnknownGroup`] if the group does not exist.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Self> {
+        let gid = group.map(lookup_group).transpose()?;
+        let Some(user) = user else {
+            return Ok(CommandUser { uid: None, gid });
+        };
+        let unknown = || ProcessingError::UnknownUser {
+            name: user.to_string(),
+        };
+        let (uid, primary_gid) = match user.parse::<u32>() {
+            Ok(uid) => (uid, lookup_user(&UserKey::Id(uid))?.map(|(_, gid)| gid)),
+            Err(_) => {
+                let (uid, gid) = lookup_user(&UserKey::Name(user))?.ok_or_else(unknown)?;
+                (uid, Some(gid))
+            }
+        };
+        let gid = gid.or(primary_gid).ok_or_else(unknown)?;
+        debug!(user, uid, gid, "resolved user to run commands as");
+        Ok(CommandUser {
+            uid: Some(uid),
+            gid: Some(gid),
+        })
+    }
+
+    /// Whether commands run as a user or group other than those of the runner.
+    #[must_use]
+    pub fn is_set(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some()
+    }
+
+    /// Give `path` and everything within it to the user and group, so that commands are able to
+    /// read and write the repository they run in. Symbolic links are changed rather than followed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the owner of any file could not be changed.
+    pub fn give_directory(&self, path: &Path) -> Result<()> {
+        if !self.is_set() {
+            return Ok(());
+        }
+        debug!(
+            ?path,
+            uid = self.uid,
+            gid = self.gid,
+            "changing owner of directory"
+        );
+        chown_all(path, self.uid, self.gid)?;
+        Ok(())
+    }
+}
+
+/// Whether the runner is able to run commands as another user, which requires running as root.
+#[must_use]
+pub fn can_change_user() -> bool {
+    #[cfg(unix)]
+    // SAFETY: geteuid has no preconditions and can't fail
+    return unsafe { libc::geteuid() } == 0;
+    #[cfg(not(unix))]
+    return false;
+}
+
+#[cfg(unix)]
+fn chown_all(path: &Path, uid: Option<u32>, gid: Option<u32>) -> std::io::Result<()> {
+    std::os::unix::fs::lchown(path, uid, gid)?;
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            chown_all(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn chown_all(_path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+enum UserKey<'a> {
+    Name(&'a str),
+    Id(u32),
+}
+
+/// Size of the buffer that the strings of a passwd or group entry are written to, which is far
+/// larger than any entry in practice.
+#[cfg(unix)]
+const ENTRY_BUFFER_BYTES: usize = 16 * 1024;
+
+/// Look up the id and primary group of a user in the passwd database.
+#[cfg(unix)]
+fn lookup_user(key: &UserKey) -> Result<Option<(u32, u32)>> {
+    let mut entry = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut buffer = vec![0; ENTRY_BUFFER_BYTES];
+    let mut found = std::ptr::null_mut();
+    let code = match key {
+        UserKey::Name(name) => {
+            let Ok(name) = std::ffi::CString::new(*name) else {
+                return Ok(None);
+            };
+            // SAFETY: every pointer is valid for the call, and the length of the buffer is given
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    entry.as_mut_ptr(),
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            }
+        }
+        // SAFETY: as above
+        UserKey::Id(uid) => unsafe {
+            libc::getpwuid_r(
+                *uid,
+                entry.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        },
+    };
+    if code != 0 {
+        return Err(std::io::Error::from_raw_os_error(code).into());
+    }
+    if found.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: the entry was written, since the user was found
+    let entry = unsafe { entry.assume_init() };
+    Ok(Some((entry.pw_uid, entry.pw_gid)))
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_key: &UserKey) -> Result<Option<(u32, u32)>> {
+    Ok(None)
+}
+
+/// Look up a group by name or numeric id in the group database.
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let unknown = || ProcessingError::UnknownGroup {
+        name: group.to_string(),
+    };
+    let name = std::ffi::CString::new(group).map_err(|_| unknown())?;
+    let mut entry = std::mem::MaybeUninit::<libc::group>::uninit();
+    let mut buffer = vec![0; ENTRY_BUFFER_BYTES];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call, and the length of the buffer is given
+    let code = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if code != 0 {
+        return Err(std::io::Error::from_raw_os_error(code).into());
+    }
+    if found.is_null() {
+        return Err(unknown());
+    }
+    // SAFETY: the entry was written, since the group was found
+    Ok(unsafe { entry.assume_init() }.gr_gid)
+}
+
+#[cfg(not(unix))]
+fn lookup_group(group: &str) -> Result<u32> {
+    group.parse().map_err(|_| ProcessingError::UnknownGroup {
+        name: group.to_string(),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::command::{run_command, CommandMode, ProcessOptions, WebhookEnv};
+
+    #[test]
+    fn can_resolve_users_and_groups() {
+        let root = CommandUser {
+            uid: Some(0),
+            gid: Some(0),
+        };
+        assert_eq!(CommandUser::resolve(Some("root"), None).unwrap(), root);
+        assert_eq!(CommandUser::resolve(Some("0"), None).unwrap(), root);
+        assert_eq!(
+            CommandUser::resolve(Some("root"), Some("12345")).unwrap(),
+            CommandUser {
+                uid: Some(0),
+                gid: Some(12345),
+            }
+        );
+        assert!(!CommandUser::resolve(None, None).unwrap().is_set());
+
+        match CommandUser::resolve(Some("no-such-user"), None) {
+            Err(ProcessingError::UnknownUser { name }) => assert_eq!(name, "no-such-user"),
+            e => panic!("unknown user was not rejected: {e:?}"),
+        }
+        // Without an entry, there is no primary group to fall back to
+        match CommandUser::resolve(Some("4000000000"), None) {
+            Err(ProcessingError::UnknownUser { .. }) => (),
+            e => panic!("user without a group was not rejected: {e:?}"),
+        }
+        match CommandUser::resolve(Some("root"), Some("no-such-group")) {
+            Err(ProcessingError::UnknownGroup { name }) => assert_eq!(name, "no-such-group"),
+            e => panic!("unknown group was not rejected: {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_run_command_as_user() {
+        // Only root is able to change the user of a command
+        if !can_change_user() {
+            return;
+        }
+        let dir = TempDir::new("webhook-runner-test").unwrap();
+        let user = CommandUser {
+            uid: Some(65534),
+            gid: Some(65534),
+        };
+        user.give_directory(dir.path()).unwrap();
+        let output = run_command(
+            "id -u; id -g; touch written",
+            &CommandMode::default(),
+            dir.path(),
+            &WebhookEnv::default(),
+            None,
+            Duration::from_secs(10),
+            &ProcessOptions {
+                user,
+                ..ProcessOptions::default()
+            },
+        )
+        .await
+        .expect("command failed");
+        assert_eq!(output.captured.stdout, b"65534\n65534\n");
+        assert!(dir.path().join("written").exists());
+    }
+}
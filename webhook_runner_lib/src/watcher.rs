@@ -0,0 +1,200 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::{debug, error, info, warn};
+
+use crate::cert_builder::KeyringFile;
+use crate::error::Result;
+
+/// A keyring that may be replaced while the runner is running.
+pub type SharedKeyringFile = Arc<RwLock<KeyringFile>>;
+
+/// Handle for a running keyring watcher; the watcher stops once this is dropped.
+#[derive(Debug)]
+pub struct KeyringWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+/// Watch the given keyring files or directories for changes, reloading the shared keyring once
+/// no further changes have been seen for `debounce`. Waiting for changes to settle avoids loading
+/// a keyring file that is only partially written. Keyring files are watched through the directory
+/// that holds them, so that a file that is replaced by renaming a new file over it, as editors and
+/// configuration management tools do, is still followed.
+///
+/// A reload that fails, or that produces no valid certificates, is logged and the previously
+/// loaded keyring is kept. Otherwise, `on_reload` is called with the new keyring before it
+/// replaces the previous keyring.
+///
+/// # Errors
+///
+/// This function returns an error if the filesystem watcher could not be created or could not
+/// watch any of the given paths.
+pub fn watch_keyring<F>(
+    paths: Vec<PathBuf>,
+    keyring: SharedKeyringFile,
+    debounce: Duration,
+    on_reload: F,
+) -> Result<KeyringWatcher>
+where
+    F: Fn(&KeyringFile) + Send + 'static,
+{
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for path in &paths {
+        let watched = if path.is_dir() {
+            path.as_path()
+        } else {
+            match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            }
+        };
+        watcher.watch(watched, RecursiveMode::NonRecursive)?;
+    }
+
+    std::thread::spawn(move || {
+        // Block until the first event of a batch that changes the keyring, then keep draining
+        // events until the paths have been quiet for the debounce period.
+        while let Ok(event) = rx.recv() {
+            if !is_keyring_change(&event, &paths) {
+                continue;
+            }
+            loop {
+                match rx.recv_timeout(debounce) {
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+
+            debug!(?paths, "reloading keyring after change");
+            match KeyringFile::from_paths(&paths) {
+                Ok(reloaded) if reloaded.is_empty() => {
+                    warn!(
+                        ?paths,
+                        "reloaded keyring has no valid certs, keeping previous keyring"
+                    );
+                }
+                Ok(reloaded) => {
+                    info!(?paths, "reloaded keyring");
+                    on_reload(&reloaded);
+                    // The keyring is replaced whole, so a writer that panicked can't have left
+                    // it half updated
+                    *keyring.write().unwrap_or_else(|e| {
+                        warn!("keyring lock was poisoned, replacing keyring anyway");
+                        e.into_inner()
+                    }) = reloaded;
+                }
+                Err(e) => error!(?paths, %e, "unable to reload keyring"),
+            }
+        }
+    });
+
+    Ok(KeyringWatcher { _watcher: watcher })
+}
+
+/// Whether a watcher event may have changed the keyring at `paths`: any change within a watched
+/// directory, or a change to a file with the name of a watched file, rather than to one of the
+/// other files in its directory. Errors are treated as changes, since they may hide one.
+fn is_keyring_change(event: &notify::Result<Event>, paths: &[PathBuf]) -> bool {
+    let Ok(event) = event else {
+        return true;
+    };
+    event.paths.iter().any(|changed| {
+        paths.iter().any(|path| {
+            if path.is_dir() {
+                changed.starts_with(path)
+            } else {
+                changed.file_name() == path.file_name()
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Instant, SystemTime};
+
+    use tempdir::TempDir;
+
+    use crate::repository::{verify_commit, VerifyOptions};
+    use crate::test_util::{commit, days, generate_cert, init_repository, write_keyring};
+
+    #[test]
+    fn can_verify_commit_after_adding_cert_to_watched_directory() {
+        let now = SystemTime::now();
+        let existing = generate_cert(now - days(1), None);
+        let added = generate_cert(now - days(1), None);
+
+        let keyring_dir = TempDir::new("webhook-runner-test").unwrap();
+        write_keyring(keyring_dir.path(), "existing.pgp", &[&existing]);
+        let paths = vec![keyring_dir.path().to_path_buf()];
+        let keyring = Arc::new(RwLock::new(KeyringFile::from_paths(&paths).unwrap()));
+        let _watcher =
+            watch_keyring(paths, keyring.clone(), Duration::from_millis(100), |_| {}).unwrap();
+
+        let repo_dir = TempDir::new("webhook-runner-test").unwrap();
+        let repo = init_repository(repo_dir.path());
+        let oid = commit(&repo, Some(&added), now);
+        let verify = || {
+            let keyring = keyring.read().unwrap();
+            verify_commit(
+                repo.find_commit(oid).unwrap(),
+                &keyring,
+                &VerifyOptions::default(),
+            )
+        };
+        assert!(verify().is_err(), "commit verified before cert was added");
+
+        write_keyring(keyring_dir.path(), "added.pgp", &[&added]);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while verify().is_err() {
+            assert!(Instant::now() < deadline, "keyring was not reloaded");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn can_reload_keyring_file_replaced_by_rename() {
+        let now = SystemTime::now();
+        let existing = generate_cert(now - days(1), None);
+        let added = generate_cert(now - days(1), None);
+
+        let keyring_dir = TempDir::new("webhook-runner-test").unwrap();
+        let path = write_keyring(keyring_dir.path(), "keyring.pgp", &[&existing]);
+        let keyring = Arc::new(RwLock::new(KeyringFile::from_path(&path).unwrap()));
+        let _watcher = watch_keyring(
+            vec![path.clone()],
+            keyring.clone(),
+            Duration::from_millis(100),
+            |_| {},
+        )
+        .unwrap();
+
+        let repo_dir = TempDir::new("webhook-runner-test").unwrap();
+        let repo = init_repository(repo_dir.path());
+        let oid = commit(&repo, Some(&added), now);
+        let verify = || {
+            let keyring = keyring.read().unwrap();
+            verify_commit(
+                repo.find_commit(oid).unwrap(),
+                &keyring,
+                &VerifyOptions::default(),
+            )
+        };
+
+        // The new keyring is written next to the watched file, then renamed over it
+        let replacement = write_keyring(keyring_dir.path(), "keyring.pgp.new", &[&added]);
+        std::fs::rename(replacement, &path).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while verify().is_err() {
+            assert!(Instant::now() < deadline, "keyring was not reloaded");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}